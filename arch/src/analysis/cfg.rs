@@ -0,0 +1,395 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fmt::Write;
+
+use {Address};
+use analysis::{successors, Decoded, Image};
+use instructions::Instruction;
+
+/// Jump tables longer than this are more likely JMPs that happen to follow each other
+const MAX_TABLE_ENTRIES: usize = 128;
+
+/// How control gets from one block to another
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EdgeKind {
+    /// the following instruction, including where a call returns to
+    Next,
+    Jump,
+
+    /// past the instruction a skip jumps over
+    Skip,
+    Call,
+
+    /// one entry of a JMI jump table
+    Table,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Edge {
+    pub kind: EdgeKind,
+    pub target: Address,
+}
+
+/// BasicBlock
+///
+/// Instructions always executed one after the other, only the last one can branch
+#[derive(Clone, Debug, PartialEq)]
+pub struct BasicBlock {
+    pub start: Address,
+
+    /// one past the last byte of the block
+    pub end: Address,
+    pub instructions: Vec<(Address, Decoded)>,
+    pub edges: Vec<Edge>,
+}
+
+/// A routine, made of the blocks reachable from its entry without following calls
+#[derive(Clone, Debug, PartialEq)]
+pub struct Function {
+    pub entry: Address,
+    pub blocks: BTreeSet<Address>,
+
+    /// entries of the functions this one calls
+    pub calls: BTreeSet<Address>,
+
+    /// whether any path reaches an RTS
+    pub returns: bool,
+}
+
+/// Cfg
+///
+/// The control-flow graph of a ROM, recovered by following every branch from the program start
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cfg {
+    pub entry: Address,
+    pub blocks: BTreeMap<Address, BasicBlock>,
+
+    /// by entry, the program start is a function too
+    pub functions: BTreeMap<Address, Function>,
+
+    /// JMI instructions whose targets couldn't be found
+    pub unresolved: Vec<Address>,
+
+    /// ranges of the ROM no path executes, end is exclusive
+    pub unreachable: Vec<(Address, Address)>,
+}
+
+/// A BNNN usually jumps into a table of JMPs at NNN, indexed by V0
+fn jump_table(image: &Image, address: Address) -> Vec<Address> {
+    (0..MAX_TABLE_ENTRIES)
+        .map(|entry| address.wrapping_add(2 * entry as Address))
+        .take_while(|entry| matches!(image.decode_at(*entry), Some(Decoded::Instruction(Instruction::JMP { .. }))))
+        .collect()
+}
+
+fn edges_of(image: &Image, decoded: &Decoded, address: Address) -> Vec<Edge> {
+    let next = address.wrapping_add(decoded.length() as Address);
+    let kind_of = |target: Address| if target == next { EdgeKind::Next } else { EdgeKind::Skip };
+
+    match *decoded {
+        Decoded::Instruction(Instruction::JMP { address }) => vec![Edge { kind: EdgeKind::Jump, target: address }],
+        Decoded::Instruction(Instruction::JSR { address }) => vec![
+            Edge { kind: EdgeKind::Call, target: address },
+            Edge { kind: EdgeKind::Next, target: next },
+        ],
+        Decoded::Instruction(Instruction::JMI { address }) => jump_table(image, address).into_iter()
+            .map(|target| Edge { kind: EdgeKind::Table, target })
+            .collect(),
+        _ => successors(decoded, address).into_iter()
+            .map(|target| Edge { kind: kind_of(target), target })
+            .collect(),
+    }
+}
+
+/// Whether the instruction does anything but carry on with the next one
+fn ends_block(edges: &[Edge]) -> bool {
+    match edges {
+        [edge] => edge.kind != EdgeKind::Next,
+        _ => true,
+    }
+}
+
+impl Cfg {
+    pub fn build(image: &Image) -> Cfg {
+        let mut decoded = BTreeMap::new();
+        let mut edges = BTreeMap::new();
+        let mut leaders = BTreeSet::new();
+        let mut unresolved = Vec::new();
+
+        leaders.insert(image.base);
+        let mut pending = vec![image.base];
+        while let Some(address) = pending.pop() {
+            if decoded.contains_key(&address) {
+                continue;
+            }
+            // invalid words are kept, they end their block without going anywhere
+            let instruction = match image.decode_at(address) {
+                Some(instruction) => instruction,
+                None => continue,
+            };
+
+            let instruction_edges = edges_of(image, &instruction, address);
+            if let Decoded::Instruction(Instruction::JMI { .. }) = instruction {
+                if instruction_edges.is_empty() {
+                    unresolved.push(address);
+                }
+            }
+            if ends_block(&instruction_edges) {
+                leaders.extend(instruction_edges.iter().map(|edge| edge.target));
+            }
+
+            pending.extend(instruction_edges.iter().map(|edge| edge.target));
+            decoded.insert(address, instruction);
+            edges.insert(address, instruction_edges);
+        }
+
+        let mut blocks = BTreeMap::new();
+        for &start in leaders.iter().filter(|leader| decoded.contains_key(leader)) {
+            let mut block = BasicBlock {
+                start,
+                end: start,
+                instructions: Vec::new(),
+                edges: Vec::new(),
+            };
+
+            let mut address = start;
+            loop {
+                let instruction = decoded[&address];
+                let next = address.wrapping_add(instruction.length() as Address);
+                block.instructions.push((address, instruction));
+                block.end = next;
+
+                let instruction_edges = &edges[&address];
+                if ends_block(instruction_edges) || leaders.contains(&next) || !decoded.contains_key(&next) {
+                    block.edges = instruction_edges.clone();
+                    break;
+                }
+                address = next;
+            }
+
+            blocks.insert(start, block);
+        }
+
+        let entries: BTreeSet<Address> = Some(image.base).into_iter()
+            .chain(blocks.values().flat_map(|block| block.edges.iter())
+                .filter(|edge| edge.kind == EdgeKind::Call)
+                .map(|edge| edge.target))
+            .filter(|entry| blocks.contains_key(entry))
+            .collect();
+        let functions = entries.into_iter()
+            .map(|entry| (entry, function_at(&blocks, entry)))
+            .collect();
+
+        let mut unreachable: Vec<(Address, Address)> = Vec::new();
+        let covered: BTreeSet<Address> = decoded.iter()
+            .flat_map(|(&address, instruction)| (0..instruction.length()).map(move |offset| address.wrapping_add(offset as Address)))
+            .collect();
+        for address in image.base as usize..image.end() {
+            let address = address as Address;
+            if covered.contains(&address) {
+                continue;
+            }
+            match unreachable.last_mut() {
+                Some(&mut (_, ref mut end)) if *end == address => *end = address + 1,
+                _ => unreachable.push((address, address + 1)),
+            }
+        }
+
+        Cfg {
+            entry: image.base,
+            blocks,
+            functions,
+            unresolved,
+            unreachable,
+        }
+    }
+
+    /// The block an instruction at address is part of
+    pub fn block_containing(&self, address: Address) -> Option<&BasicBlock> {
+        self.blocks.range(..=address)
+            .next_back()
+            .map(|(_, block)| block)
+            .filter(|block| address < block.end)
+    }
+
+    /// `main` for the program start, `sub_XXX` for anything called
+    pub fn function_name(&self, entry: Address) -> String {
+        if entry == self.entry {
+            String::from("main")
+        } else {
+            format!("sub_{:03X}", entry)
+        }
+    }
+
+    /// Graphviz DOT source, one box per block, calls dashed and jump tables dotted
+    pub fn to_dot(&self, image: &Image) -> String {
+        let mut dot = String::new();
+        // writing to a String can't fail
+        let _ = self.write_dot(&mut dot, image);
+        dot
+    }
+
+    fn write_dot(&self, dot: &mut String, image: &Image) -> fmt::Result {
+        writeln!(dot, "digraph cfg {{")?;
+        writeln!(dot, "    node [shape=box, fontname=\"monospace\"];")?;
+
+        for block in self.blocks.values() {
+            let mut label = String::new();
+            if self.functions.contains_key(&block.start) {
+                write!(label, "{}:\\l", self.function_name(block.start))?;
+            }
+            for &(address, instruction) in block.instructions.iter() {
+                let code = image.word_at(address).unwrap_or(0);
+                write!(label, "{:03X}  {:04X}  {}\\l", address, code, instruction)?;
+            }
+            writeln!(dot, "    b{:03X} [label=\"{}\"];", block.start, label)?;
+
+            for edge in block.edges.iter() {
+                let style = match edge.kind {
+                    EdgeKind::Next => "",
+                    EdgeKind::Jump => "",
+                    EdgeKind::Skip => " [label=\"skip\"]",
+                    EdgeKind::Call => " [style=dashed]",
+                    EdgeKind::Table => " [style=dotted]",
+                };
+                if self.blocks.contains_key(&edge.target) {
+                    writeln!(dot, "    b{:03X} -> b{:03X}{};", block.start, edge.target, style)?;
+                }
+            }
+        }
+
+        for &address in self.unresolved.iter() {
+            writeln!(dot, "    u{:03X} [shape=plaintext, label=\"?\"];", address)?;
+            let block = self.block_containing(address).map_or(address, |block| block.start);
+            writeln!(dot, "    b{:03X} -> u{:03X} [style=dotted];", block, address)?;
+        }
+
+        for &(start, end) in self.unreachable.iter() {
+            writeln!(dot, "    d{:03X} [shape=note, color=gray, label=\"unreachable {:03X}-{:03X}\"];", start, start, end - 1)?;
+        }
+
+        writeln!(dot, "}}")
+    }
+}
+
+fn function_at(blocks: &BTreeMap<Address, BasicBlock>, entry: Address) -> Function {
+    let mut function = Function {
+        entry,
+        blocks: BTreeSet::new(),
+        calls: BTreeSet::new(),
+        returns: false,
+    };
+
+    let mut pending = vec![entry];
+    while let Some(start) = pending.pop() {
+        let block = match blocks.get(&start) {
+            Some(block) if !function.blocks.contains(&start) => block,
+            _ => continue,
+        };
+        function.blocks.insert(start);
+
+        if let Some(&(_, Decoded::Instruction(Instruction::RTS))) = block.instructions.last() {
+            function.returns = true;
+        }
+        for edge in block.edges.iter() {
+            match edge.kind {
+                EdgeKind::Call => { function.calls.insert(edge.target); },
+                _ => pending.push(edge.target),
+            }
+        }
+    }
+
+    function
+}
+
+impl fmt::Display for Cfg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "blocks:    {}", self.blocks.len())?;
+        writeln!(f, "functions: {}", self.functions.len())?;
+
+        for function in self.functions.values() {
+            write!(f, "\n{} ({} blocks", self.function_name(function.entry), function.blocks.len())?;
+            if !function.returns && function.entry != self.entry {
+                write!(f, ", never returns")?;
+            }
+            writeln!(f, ")")?;
+            for callee in function.calls.iter() {
+                writeln!(f, "    calls {}", self.function_name(*callee))?;
+            }
+        }
+
+        if !self.unresolved.is_empty() {
+            writeln!(f, "\nunresolved computed jumps:")?;
+            for address in self.unresolved.iter() {
+                writeln!(f, "    {:03X}", address)?;
+            }
+        }
+
+        if !self.unreachable.is_empty() {
+            writeln!(f, "\nunreachable:")?;
+            for &(start, end) in self.unreachable.iter() {
+                writeln!(f, "    {:03X}-{:03X} {} bytes", start, end - 1, end - start)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROM: [u8; 14] = [
+        0x22, 0x0A, // 200 JSR 20A
+        0x30, 0x01, // 202 SE V0, 01
+        0x60, 0x02, // 204 MOV V0, 02
+        0xB3, 0x00, // 206 JMI 300
+        0x00, 0x00, // 208
+        0x61, 0x01, // 20A MOV V1, 01
+        0x00, 0xEE, // 20C RTS
+    ];
+
+    fn edges(cfg: &Cfg, start: Address) -> Vec<(EdgeKind, Address)> {
+        let mut edges: Vec<(EdgeKind, Address)> = cfg.blocks[&start].edges.iter().map(|edge| (edge.kind, edge.target)).collect();
+        edges.sort_by_key(|&(_, target)| target);
+        edges
+    }
+
+    #[test]
+    fn skip_falls_through() {
+        let image = Image::new(&ROM);
+        let cfg = Cfg::build(&image);
+        assert_eq!(cfg.blocks.keys().cloned().collect::<Vec<_>>(), vec![0x200, 0x202, 0x204, 0x206, 0x20A]);
+        assert_eq!(edges(&cfg, 0x202), vec![(EdgeKind::Next, 0x204), (EdgeKind::Skip, 0x206)]);
+        // the skipped instruction ends its block, falling into the one the skip lands on
+        assert_eq!(cfg.blocks[&0x204].end, 0x206);
+        assert_eq!(edges(&cfg, 0x204), vec![(EdgeKind::Next, 0x206)]);
+    }
+
+    #[test]
+    fn calls_make_functions() {
+        let image = Image::new(&ROM);
+        let cfg = Cfg::build(&image);
+        assert_eq!(edges(&cfg, 0x200), vec![(EdgeKind::Next, 0x202), (EdgeKind::Call, 0x20A)]);
+
+        let main = &cfg.functions[&0x200];
+        assert_eq!(main.calls, [0x20A].iter().cloned().collect());
+        assert_eq!(main.blocks, [0x200, 0x202, 0x204, 0x206].iter().cloned().collect());
+        assert!(!main.returns);
+
+        let sub = &cfg.functions[&0x20A];
+        assert_eq!(sub.blocks, [0x20A].iter().cloned().collect());
+        assert!(sub.returns);
+        assert_eq!(cfg.function_name(0x20A), "sub_20A");
+    }
+
+    #[test]
+    fn unresolved_jump_table() {
+        let image = Image::new(&ROM);
+        let cfg = Cfg::build(&image);
+        assert_eq!(cfg.unresolved, vec![0x206]);
+        assert!(cfg.blocks[&0x206].edges.is_empty());
+        assert_eq!(cfg.unreachable, vec![(0x208, 0x20A)]);
+    }
+}
@@ -0,0 +1,691 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use {Address, Byte};
+use analysis::Decoded;
+use analysis::cfg::{Cfg, EdgeKind};
+use instructions::Instruction;
+
+const VF: Byte = 0xF;
+
+/// A value compared by a skip instruction
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Operand {
+    Register(Byte),
+    Value(Byte),
+}
+
+/// What a skip instruction tests
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Condition {
+    Equal(Byte, Operand),
+    NotEqual(Byte, Operand),
+    KeyPressed(Byte),
+    KeyNotPressed(Byte),
+}
+
+impl Condition {
+    /// The condition under which a skip instruction skips
+    fn of(instruction: &Instruction) -> Option<Condition> {
+        use instructions::Instruction::*;
+        match *instruction {
+            SE { reg_id, value } => Some(Condition::Equal(reg_id, Operand::Value(value))),
+            SNE { reg_id, value } => Some(Condition::NotEqual(reg_id, Operand::Value(value))),
+            SEXY { x_reg_id, y_reg_id } => Some(Condition::Equal(x_reg_id, Operand::Register(y_reg_id))),
+            SNEXY { x_reg_id, y_reg_id } => Some(Condition::NotEqual(x_reg_id, Operand::Register(y_reg_id))),
+            SKP { reg_id } => Some(Condition::KeyPressed(reg_id)),
+            SKNP { reg_id } => Some(Condition::KeyNotPressed(reg_id)),
+            _ => None,
+        }
+    }
+
+    pub fn negate(self) -> Condition {
+        match self {
+            Condition::Equal(reg_id, operand) => Condition::NotEqual(reg_id, operand),
+            Condition::NotEqual(reg_id, operand) => Condition::Equal(reg_id, operand),
+            Condition::KeyPressed(reg_id) => Condition::KeyNotPressed(reg_id),
+            Condition::KeyNotPressed(reg_id) => Condition::KeyPressed(reg_id),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LoopKind {
+    /// left only through break, return or goto
+    Forever,
+    While(Condition),
+    DoWhile(Condition),
+}
+
+/// A line of pseudo-code, or a structure nesting more of them
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Statement {
+    Instruction(Address, Decoded),
+    Call(Address),
+    Return,
+    Goto(Address),
+    Label(Address),
+    Break,
+    Continue,
+    If { condition: Condition, then: Vec<Statement>, otherwise: Vec<Statement> },
+    Loop { kind: LoopKind, body: Vec<Statement> },
+
+    /// a jump to itself, the program is done
+    Halt,
+
+    /// a loop polling a key until it is pressed, or released
+    WaitKey { reg_id: Byte, pressed: bool },
+
+    /// a loop reading the delay timer until it reaches zero
+    WaitDelay { reg_id: Byte },
+
+    /// BCD followed by loading the three digits into V0-V2
+    Digits { reg_id: Byte },
+
+    /// pointing I at the font glyph of a digit and drawing it
+    DrawDigit { reg_id: Byte, x_reg_id: Byte, y_reg_id: Byte },
+}
+
+/// Routine
+///
+/// The pseudo-code of a function, with its registers named after what they are used for
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Routine {
+    pub entry: Address,
+    pub name: String,
+    pub names: Vec<String>,
+    pub body: Vec<Statement>,
+}
+
+/// Every function of a ROM, decompiled
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Decompiled {
+    pub routines: Vec<Routine>,
+}
+
+pub fn decompile(cfg: &Cfg) -> Decompiled {
+    let routines = cfg.functions.values()
+        .map(|function| {
+            let instructions: BTreeMap<Address, Decoded> = function.blocks.iter()
+                .flat_map(|start| cfg.blocks[start].instructions.iter().cloned())
+                .collect();
+
+            let table_entries: BTreeSet<Address> = function.blocks.iter()
+                .flat_map(|start| cfg.blocks[start].edges.iter())
+                .filter(|edge| edge.kind == EdgeKind::Table)
+                .map(|edge| edge.target)
+                .collect();
+            let targets = instructions.values()
+                .filter_map(|decoded| match *decoded {
+                    Decoded::Instruction(Instruction::JMP { address }) => Some(address),
+                    _ => None,
+                })
+                .chain(table_entries.iter().cloned())
+                .collect();
+
+            let mut structurer = Structurer {
+                instructions: &instructions,
+                targets,
+                loops: Vec::new(),
+            };
+            let end = instructions.iter().next_back()
+                .map_or(function.entry, |(&address, decoded)| address + decoded.length() as Address);
+            let mut body = structurer.structure(function.entry, end);
+            // code placed before the entry is only reached through jumps, so it goes last
+            let start = instructions.keys().next().map_or(function.entry, |&address| address);
+            body.extend(structurer.structure(start, function.entry));
+
+            let mut gotos = table_entries;
+            collect_gotos(&body, &mut gotos);
+            remove_labels(&mut body, &gotos);
+            recognise_idioms(&mut body);
+
+            Routine {
+                entry: function.entry,
+                name: cfg.function_name(function.entry),
+                names: register_names(&instructions),
+                body,
+            }
+        })
+        .collect();
+
+    Decompiled {
+        routines,
+    }
+}
+
+struct Structurer<'a> {
+    instructions: &'a BTreeMap<Address, Decoded>,
+
+    /// addresses something jumps to, these can't be folded into the statement before them
+    targets: BTreeSet<Address>,
+
+    /// header and exit of the loops being structured, innermost last
+    loops: Vec<(Address, Address)>,
+}
+
+impl<'a> Structurer<'a> {
+    /// The last JMP back to header before end, closing a loop
+    fn back_edge(&self, header: Address, end: Address) -> Option<Address> {
+        self.instructions.range(header..end)
+            .filter(|&(&address, decoded)| {
+                *decoded == Decoded::Instruction(Instruction::JMP { address: header }) && address + 2 <= end
+            })
+            .map(|(&address, _)| address)
+            .next_back()
+    }
+
+    /// A jump that continues or breaks out of the innermost loop
+    fn loop_jump(&self, target: Address) -> Option<Statement> {
+        match self.loops.last() {
+            Some(&(header, _)) if header == target => Some(Statement::Continue),
+            Some(&(_, exit)) if exit == target => Some(Statement::Break),
+            _ => None,
+        }
+    }
+
+    fn jump(&self, target: Address) -> Statement {
+        self.loop_jump(target).unwrap_or(Statement::Goto(target))
+    }
+
+    fn single(&self, address: Address, decoded: Decoded) -> Statement {
+        match decoded {
+            Decoded::Instruction(Instruction::JMP { address }) => self.jump(address),
+            Decoded::Instruction(Instruction::JSR { address }) => Statement::Call(address),
+            Decoded::Instruction(Instruction::RTS) => Statement::Return,
+            _ => Statement::Instruction(address, decoded),
+        }
+    }
+
+    fn jump_at(&self, address: Address) -> Option<Address> {
+        match self.instructions.get(&address) {
+            Some(&Decoded::Instruction(Instruction::JMP { address })) => Some(address),
+            _ => None,
+        }
+    }
+
+    /// Statements for the code from start up to, not including, end
+    fn structure(&mut self, start: Address, end: Address) -> Vec<Statement> {
+        let mut statements = Vec::new();
+        let mut address = start;
+
+        while address < end {
+            let decoded = match self.instructions.get(&address) {
+                Some(decoded) => *decoded,
+                None => match self.instructions.range(address..end).next() {
+                    Some((&next, _)) => {
+                        address = next;
+                        continue;
+                    },
+                    None => break,
+                },
+            };
+
+            let is_header = self.loops.iter().any(|&(header, _)| header == address);
+            if !is_header {
+                statements.push(Statement::Label(address));
+
+                if let Some(back) = self.back_edge(address, end) {
+                    // data right after the loop would hide its exit
+                    let exit = self.instructions.range(back + 2..).next().map_or(back + 2, |(&exit, _)| exit);
+                    self.loops.push((address, exit));
+                    let body = self.structure(address, exit);
+                    self.loops.pop();
+
+                    statements.push(make_loop(body));
+                    address = exit;
+                    continue;
+                }
+            }
+
+            let next = address.wrapping_add(decoded.length() as Address);
+            let condition = match decoded {
+                Decoded::Instruction(ref instruction) => Condition::of(instruction),
+                _ => None,
+            };
+
+            match (condition, self.instructions.get(&next)) {
+                (Some(condition), Some(&skipped)) if self.targets.contains(&next) => {
+                    let after = next.wrapping_add(skipped.length() as Address);
+                    statements.push(Statement::If {
+                        condition,
+                        then: vec![self.jump(after)],
+                        otherwise: Vec::new(),
+                    });
+                    address = next;
+                },
+                (Some(condition), Some(&skipped)) => {
+                    let after = next.wrapping_add(skipped.length() as Address);
+                    address = after;
+
+                    match self.jump_at(next) {
+                        Some(target) if self.loop_jump(target).is_some() => statements.push(Statement::If {
+                            condition: condition.negate(),
+                            then: vec![self.jump(target)],
+                            otherwise: Vec::new(),
+                        }),
+                        // skip over a forward jump, the code up to the target runs when the skip is taken
+                        Some(target) if target > after && target <= end => {
+                            let else_end = self.jump_at(target - 2)
+                                .filter(|&target_end| target - 2 >= after && target_end > target && target_end <= end)
+                                .filter(|&target_end| self.loop_jump(target_end).is_none());
+
+                            let (then, otherwise) = match else_end {
+                                Some(else_end) => {
+                                    address = else_end;
+                                    (self.structure(after, target - 2), self.structure(target, else_end))
+                                },
+                                None => {
+                                    address = target;
+                                    (self.structure(after, target), Vec::new())
+                                },
+                            };
+                            statements.push(Statement::If {
+                                condition,
+                                then,
+                                otherwise,
+                            });
+                        },
+                        _ => statements.push(Statement::If {
+                            condition: condition.negate(),
+                            then: vec![self.single(next, skipped)],
+                            otherwise: Vec::new(),
+                        }),
+                    }
+                },
+                // a jump to the next instruction does nothing
+                _ if self.jump_at(address) == Some(next) => address = next,
+                _ => {
+                    statements.push(self.single(address, decoded));
+                    address = next;
+                },
+            }
+        }
+
+        statements
+    }
+}
+
+fn make_loop(mut body: Vec<Statement>) -> Statement {
+    let mut kind = LoopKind::Forever;
+    match body.last().cloned() {
+        Some(Statement::Continue) => { body.pop(); },
+        Some(Statement::If { condition, ref then, ref otherwise }) if *then == [Statement::Continue] && otherwise.is_empty() => {
+            body.pop();
+            kind = LoopKind::DoWhile(condition);
+        },
+        // falling out of the body leaves the loop
+        _ => {
+            body.push(Statement::Break);
+            return Statement::Loop { kind, body };
+        },
+    }
+
+    if kind == LoopKind::Forever {
+        if let Some(Statement::If { condition, ref then, ref otherwise }) = body.first().cloned() {
+            if *then == [Statement::Break] && otherwise.is_empty() {
+                body.remove(0);
+                kind = LoopKind::While(condition.negate());
+            }
+        }
+    }
+
+    Statement::Loop { kind, body }
+}
+
+fn collect_gotos(statements: &[Statement], gotos: &mut BTreeSet<Address>) {
+    for statement in statements {
+        match *statement {
+            Statement::Goto(target) => { gotos.insert(target); },
+            Statement::If { ref then, ref otherwise, .. } => {
+                collect_gotos(then, gotos);
+                collect_gotos(otherwise, gotos);
+            },
+            Statement::Loop { ref body, .. } => collect_gotos(body, gotos),
+            _ => {},
+        }
+    }
+}
+
+fn remove_labels(statements: &mut Vec<Statement>, gotos: &BTreeSet<Address>) {
+    statements.retain(|statement| match *statement {
+        Statement::Label(address) => gotos.contains(&address),
+        _ => true,
+    });
+    for statement in statements.iter_mut() {
+        match *statement {
+            Statement::If { ref mut then, ref mut otherwise, .. } => {
+                remove_labels(then, gotos);
+                remove_labels(otherwise, gotos);
+            },
+            Statement::Loop { ref mut body, .. } => remove_labels(body, gotos),
+            _ => {},
+        }
+    }
+}
+
+fn recognise_idioms(statements: &mut Vec<Statement>) {
+    use self::Statement::Instruction as I;
+    use instructions::Instruction::*;
+
+    for statement in statements.iter_mut() {
+        let idiom = match *statement {
+            Statement::If { ref mut then, ref mut otherwise, .. } => {
+                recognise_idioms(then);
+                recognise_idioms(otherwise);
+                None
+            },
+            Statement::Loop { ref kind, ref mut body } => {
+                recognise_idioms(body);
+                match (*kind, body.as_slice()) {
+                    (LoopKind::Forever, []) => Some(Statement::Halt),
+                    (LoopKind::DoWhile(condition), []) | (LoopKind::While(condition), []) => match condition {
+                        Condition::KeyNotPressed(reg_id) => Some(Statement::WaitKey { reg_id, pressed: true }),
+                        Condition::KeyPressed(reg_id) => Some(Statement::WaitKey { reg_id, pressed: false }),
+                        _ => None,
+                    },
+                    (LoopKind::DoWhile(Condition::NotEqual(reg_id, Operand::Value(0))), [I(_, Decoded::Instruction(GDELAY { reg_id: read }))])
+                        if *read == reg_id => Some(Statement::WaitDelay { reg_id }),
+                    _ => None,
+                }
+            },
+            _ => None,
+        };
+        if let Some(idiom) = idiom {
+            *statement = idiom;
+        }
+    }
+
+    let mut index = 0;
+    while index + 1 < statements.len() {
+        let idiom = match (&statements[index], &statements[index + 1]) {
+            (&I(_, Decoded::Instruction(BCD { reg_id })), &I(_, Decoded::Instruction(LDR { reg_id: 2 }))) => {
+                Some(Statement::Digits { reg_id })
+            },
+            (&I(_, Decoded::Instruction(FONT { reg_id })), &I(_, Decoded::Instruction(DRW { x_reg_id, y_reg_id, value: 5 }))) => {
+                Some(Statement::DrawDigit { reg_id, x_reg_id, y_reg_id })
+            },
+            _ => None,
+        };
+        if let Some(idiom) = idiom {
+            statements[index] = idiom;
+            statements.remove(index + 1);
+        }
+        index += 1;
+    }
+}
+
+/// Name each register after its most common use in the function, VF is always the flag
+fn register_names(instructions: &BTreeMap<Address, Decoded>) -> Vec<String> {
+    use instructions::Instruction::*;
+
+    let mut uses: Vec<BTreeMap<&'static str, usize>> = vec![BTreeMap::new(); 16];
+    let mut use_as = |reg_id: Byte, role: &'static str| *uses[reg_id as usize & 0xF].entry(role).or_insert(0) += 1;
+
+    for decoded in instructions.values() {
+        if let Decoded::Instruction(instruction) = *decoded {
+            match instruction {
+                DRW { x_reg_id, y_reg_id, .. } => {
+                    use_as(x_reg_id, "x");
+                    use_as(y_reg_id, "y");
+                },
+                SKP { reg_id } | SKNP { reg_id } | KEY { reg_id } => use_as(reg_id, "key"),
+                GDELAY { reg_id } | SDELAY { reg_id } => use_as(reg_id, "timer"),
+                SSOUND { reg_id } => use_as(reg_id, "sound"),
+                BCD { reg_id } => use_as(reg_id, "number"),
+                FONT { reg_id } => use_as(reg_id, "digit"),
+                RAND { reg_id, .. } => use_as(reg_id, "random"),
+                ADI { reg_id } => use_as(reg_id, "offset"),
+                JMI { .. } => use_as(0, "index"),
+                _ => {},
+            }
+        }
+    }
+
+    let mut taken: BTreeMap<&'static str, usize> = BTreeMap::new();
+    (0..16).map(|reg_id| {
+        if reg_id == VF as usize {
+            return String::from("flag");
+        }
+        let role = uses[reg_id].iter()
+            .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+            .map(|(role, _)| *role);
+        match role {
+            Some(role) => {
+                let count = taken.entry(role).or_insert(0);
+                *count += 1;
+                if *count == 1 { String::from(role) } else { format!("{}{}", role, count) }
+            },
+            None => format!("v{:x}", reg_id),
+        }
+    }).collect()
+}
+
+struct Writer<'a> {
+    names: &'a [String],
+    functions: &'a BTreeMap<Address, String>,
+}
+
+impl<'a> Writer<'a> {
+    fn name(&self, reg_id: Byte) -> &str {
+        &self.names[reg_id as usize & 0xF]
+    }
+
+    fn condition(&self, condition: &Condition) -> String {
+        let operand = |operand: &Operand| match *operand {
+            Operand::Register(reg_id) => self.name(reg_id).to_string(),
+            Operand::Value(value) => format!("0x{:02X}", value),
+        };
+        match *condition {
+            Condition::Equal(reg_id, ref other) => format!("{} == {}", self.name(reg_id), operand(other)),
+            Condition::NotEqual(reg_id, ref other) => format!("{} != {}", self.name(reg_id), operand(other)),
+            Condition::KeyPressed(reg_id) => format!("key_pressed({})", self.name(reg_id)),
+            Condition::KeyNotPressed(reg_id) => format!("!key_pressed({})", self.name(reg_id)),
+        }
+    }
+
+    fn registers(&self, last: Byte) -> String {
+        (0..=last).map(|reg_id| self.name(reg_id)).collect::<Vec<_>>().join(", ")
+    }
+
+    fn instruction(&self, instruction: &Instruction) -> String {
+        use instructions::Instruction::*;
+        let n = |reg_id: Byte| self.name(reg_id);
+        match *instruction {
+            SYS { address } => format!("sys(0x{:03X})", address),
+            CLS | HCLS => String::from("clear_screen()"),
+            RTS => String::from("return"),
+            JMP { address } => format!("goto L{:03X}", address),
+            JSR { address } => format!("sub_{:03X}()", address),
+            MOV { reg_id, value } => format!("{} = 0x{:02X}", n(reg_id), value),
+            ADD { reg_id, value } => format!("{} += 0x{:02X}", n(reg_id), value),
+            MOVXY { x_reg_id, y_reg_id } => format!("{} = {}", n(x_reg_id), n(y_reg_id)),
+            ORXY { x_reg_id, y_reg_id } => format!("{} |= {}", n(x_reg_id), n(y_reg_id)),
+            ANDXY { x_reg_id, y_reg_id } => format!("{} &= {}", n(x_reg_id), n(y_reg_id)),
+            XORXY { x_reg_id, y_reg_id } => format!("{} ^= {}", n(x_reg_id), n(y_reg_id)),
+            ADDXY { x_reg_id, y_reg_id } => format!("{} += {}  // flag = carry", n(x_reg_id), n(y_reg_id)),
+            SUBXY { x_reg_id, y_reg_id } => format!("{} -= {}  // flag = !borrow", n(x_reg_id), n(y_reg_id)),
+            RSUBXY { x_reg_id, y_reg_id } => format!("{} = {} - {}  // flag = !borrow", n(x_reg_id), n(y_reg_id), n(x_reg_id)),
+            SHR { x_reg_id, y_reg_id } => format!("{} = {} >> 1  // flag = lsb", n(x_reg_id), n(y_reg_id)),
+            SHL { x_reg_id, y_reg_id } => format!("{} = {} << 1  // flag = msb", n(x_reg_id), n(y_reg_id)),
+            MOVI { address } => format!("i = 0x{:03X}", address),
+            JMI { address } => format!("goto *(0x{:03X} + {})", address, n(0)),
+            RAND { reg_id, value } => format!("{} = random() & 0x{:02X}", n(reg_id), value),
+            DRW { x_reg_id, y_reg_id, value } => format!("flag = draw(i, {}, {}, {})", n(x_reg_id), n(y_reg_id), value),
+            GDELAY { reg_id } => format!("{} = delay_timer", n(reg_id)),
+            KEY { reg_id } => format!("{} = wait_key()", n(reg_id)),
+            SDELAY { reg_id } => format!("delay_timer = {}", n(reg_id)),
+            SSOUND { reg_id } => format!("sound_timer = {}", n(reg_id)),
+            ADI { reg_id } => format!("i += {}", n(reg_id)),
+            FONT { reg_id } => format!("i = font({})", n(reg_id)),
+            BCD { reg_id } => format!("memory[i..i + 3] = bcd({})", n(reg_id)),
+            STR { reg_id } => format!("memory[i..] = ({})", self.registers(reg_id)),
+            LDR { reg_id } => format!("({}) = memory[i..]", self.registers(reg_id)),
+            SE { .. } | SNE { .. } | SEXY { .. } | SNEXY { .. } | SKP { .. } | SKNP { .. } => {
+                let condition = Condition::of(instruction).map(|condition| self.condition(&condition)).unwrap_or_default();
+                format!("skip_next_if({})", condition)
+            },
+        }
+    }
+
+    fn statements(&self, f: &mut fmt::Formatter, statements: &[Statement], depth: usize) -> fmt::Result {
+        for statement in statements {
+            self.statement(f, statement, depth)?;
+        }
+        Ok(())
+    }
+
+    fn statement(&self, f: &mut fmt::Formatter, statement: &Statement, depth: usize) -> fmt::Result {
+        let indent = "    ".repeat(depth);
+        match *statement {
+            Statement::Instruction(_, Decoded::Instruction(ref instruction)) => writeln!(f, "{}{}", indent, self.instruction(instruction)),
+            Statement::Instruction(_, Decoded::Extension(extension)) => writeln!(f, "{}{}()", indent, extension.mnemonic.to_lowercase()),
+            Statement::Instruction(_, Decoded::Invalid(code)) => writeln!(f, "{}invalid(0x{:04X})", indent, code),
+            Statement::Call(address) => match self.functions.get(&address) {
+                Some(name) => writeln!(f, "{}{}()", indent, name),
+                None => writeln!(f, "{}sub_{:03X}()", indent, address),
+            },
+            Statement::Return => writeln!(f, "{}return", indent),
+            Statement::Goto(address) => writeln!(f, "{}goto L{:03X}", indent, address),
+            Statement::Label(address) => writeln!(f, "L{:03X}:", address),
+            Statement::Break => writeln!(f, "{}break", indent),
+            Statement::Continue => writeln!(f, "{}continue", indent),
+            Statement::If { ref condition, ref then, ref otherwise } => {
+                writeln!(f, "{}if {} {{", indent, self.condition(condition))?;
+                self.statements(f, then, depth + 1)?;
+                if !otherwise.is_empty() {
+                    writeln!(f, "{}}} else {{", indent)?;
+                    self.statements(f, otherwise, depth + 1)?;
+                }
+                writeln!(f, "{}}}", indent)
+            },
+            Statement::Loop { ref kind, ref body } => {
+                match *kind {
+                    LoopKind::Forever => writeln!(f, "{}loop {{", indent)?,
+                    LoopKind::While(ref condition) => writeln!(f, "{}while {} {{", indent, self.condition(condition))?,
+                    LoopKind::DoWhile(_) => writeln!(f, "{}do {{", indent)?,
+                }
+                self.statements(f, body, depth + 1)?;
+                match *kind {
+                    LoopKind::DoWhile(ref condition) => writeln!(f, "{}}} while {}", indent, self.condition(condition)),
+                    _ => writeln!(f, "{}}}", indent),
+                }
+            },
+            Statement::Halt => writeln!(f, "{}halt()", indent),
+            Statement::WaitKey { reg_id, pressed: true } => writeln!(f, "{}wait_until_pressed({})", indent, self.name(reg_id)),
+            Statement::WaitKey { reg_id, pressed: false } => writeln!(f, "{}wait_until_released({})", indent, self.name(reg_id)),
+            Statement::WaitDelay { reg_id } => writeln!(f, "{}{} = wait_delay_timer()", indent, self.name(reg_id)),
+            Statement::Digits { reg_id } => writeln!(f, "{}({}) = decimal_digits({})", indent, self.registers(2), self.name(reg_id)),
+            Statement::DrawDigit { reg_id, x_reg_id, y_reg_id } => writeln!(
+                f, "{}flag = draw_digit({}, {}, {})", indent, self.name(reg_id), self.name(x_reg_id), self.name(y_reg_id)
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Decompiled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let functions: BTreeMap<Address, String> = self.routines.iter()
+            .map(|routine| (routine.entry, routine.name.clone()))
+            .collect();
+
+        for (index, routine) in self.routines.iter().enumerate() {
+            if index > 0 {
+                writeln!(f)?;
+            }
+
+            let named: Vec<String> = routine.names.iter().enumerate()
+                .filter(|&(reg_id, name)| *name != format!("v{:x}", reg_id))
+                .map(|(reg_id, name)| format!("v{:x} {}", reg_id, name))
+                .collect();
+            if !named.is_empty() {
+                writeln!(f, "// {}", named.join(", "))?;
+            }
+
+            writeln!(f, "fn {}() {{", routine.name)?;
+            let writer = Writer {
+                names: &routine.names,
+                functions: &functions,
+            };
+            writer.statements(f, &routine.body, 1)?;
+            writeln!(f, "}}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use analysis::Image;
+
+    /// The pseudo-code of the main routine, without the register names
+    fn main_of(rom: &[Byte]) -> String {
+        let image = Image::new(rom);
+        let text = decompile(&Cfg::build(&image)).to_string();
+        text.lines().filter(|line| !line.starts_with("//")).map(|line| format!("{}\n", line)).collect()
+    }
+
+    #[test]
+    fn if_else() {
+        let rom = [
+            0x30, 0x01, // 200 SE V0, 01
+            0x12, 0x08, // 202 JMP 208
+            0x61, 0x01, // 204 MOV V1, 01
+            0x12, 0x0A, // 206 JMP 20A
+            0x61, 0x02, // 208 MOV V1, 02
+            0x12, 0x0A, // 20A JMP 20A
+        ];
+        assert_eq!(main_of(&rom), concat!(
+            "fn main() {\n",
+            "    if v0 == 0x01 {\n",
+            "        v1 = 0x01\n",
+            "    } else {\n",
+            "        v1 = 0x02\n",
+            "    }\n",
+            "    halt()\n",
+            "}\n",
+        ));
+    }
+
+    #[test]
+    fn do_while_loop() {
+        let rom = [
+            0x60, 0x00, // 200 MOV V0, 00
+            0x70, 0x01, // 202 ADD V0, 01
+            0x30, 0x0A, // 204 SE V0, 0A
+            0x12, 0x02, // 206 JMP 202
+            0x12, 0x08, // 208 JMP 208
+        ];
+        assert_eq!(main_of(&rom), concat!(
+            "fn main() {\n",
+            "    v0 = 0x00\n",
+            "    do {\n",
+            "        v0 += 0x01\n",
+            "    } while v0 != 0x0A\n",
+            "    halt()\n",
+            "}\n",
+        ));
+    }
+
+    #[test]
+    fn while_loop() {
+        let rom = [
+            0x60, 0x00, // 200 MOV V0, 00
+            0x40, 0x0A, // 202 SNE V0, 0A
+            0x12, 0x0A, // 204 JMP 20A
+            0x70, 0x01, // 206 ADD V0, 01
+            0x12, 0x02, // 208 JMP 202
+            0x12, 0x0A, // 20A JMP 20A
+        ];
+        assert_eq!(main_of(&rom), concat!(
+            "fn main() {\n",
+            "    v0 = 0x00\n",
+            "    while v0 != 0x0A {\n",
+            "        v0 += 0x01\n",
+            "    }\n",
+            "    halt()\n",
+            "}\n",
+        ));
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use {Address, Byte};
+use analysis::{Decoded, Image};
+use config::{Config, Platform, Quirks};
+use cpu::{Cpu, Fault};
+use instructions::{Extension, Instruction};
+
+/// How many instructions the trial run executes at most
+const TRIAL_INSTRUCTIONS: usize = 20_000;
+
+/// Hi-res programs start with the preamble's jump to 260
+const HIRES_PREAMBLE_JUMP: [Byte; 2] = [0x12, 0x60];
+
+/// Below this confidence frontends should let the user pick the platform
+pub const CONFIDENT: f32 = 0.75;
+
+/// Something in the ROM hinting at the platform or quirks it was written for
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Evidence {
+    /// the ROM starts with the jump of the hi-res preamble, and the program after it clears
+    /// the 64x64 display with 0230
+    HiresPreamble { clear: Address },
+
+    /// an SCHIP or XO-CHIP opcode in reachable code
+    Extension { address: Address, extension: Extension },
+
+    /// SHR or SHL with a Vy other than Vx and V0, meaning Vy is shifted into Vx
+    ShiftFromVy { address: Address },
+
+    /// SHR or SHL with V0 as Vy, written for Vx being shifted in place
+    ShiftInPlace { address: Address },
+
+    /// I was used after STR or LDR without being set again, relying on it being incremented
+    StoreReliesOnIncrement { address: Address },
+
+    /// I was set again after STR or LDR before being used
+    StoreReloadsI { address: Address },
+
+    /// the trial run stopped on a fault
+    TrialFault { fault: Fault },
+}
+
+impl fmt::Display for Evidence {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Evidence::HiresPreamble { clear } => write!(f, "200 hi-res preamble, {:03X} clears its display", clear),
+            Evidence::Extension { address, extension } => write!(f, "{:03X} {} opcode {}", address, extension.platform, extension.mnemonic),
+            Evidence::ShiftFromVy { address } => write!(f, "{:03X} shift reads Vy", address),
+            Evidence::ShiftInPlace { address } => write!(f, "{:03X} shift in place", address),
+            Evidence::StoreReliesOnIncrement { address } => write!(f, "{:03X} store relies on I being incremented", address),
+            Evidence::StoreReloadsI { address } => write!(f, "{:03X} store followed by setting I", address),
+            Evidence::TrialFault { fault } => write!(f, "trial run stopped: {}", fault),
+        }
+    }
+}
+
+/// Detection
+///
+/// The platform and quirks a ROM most likely expects, and how sure we are about it
+#[derive(Clone, Debug, PartialEq)]
+pub struct Detection {
+    pub platform: Platform,
+    pub quirks: Quirks,
+
+    /// from 0 to 1
+    pub confidence: f32,
+
+    pub evidence: Vec<Evidence>,
+
+    /// how many instructions the trial run got to execute
+    pub trial_instructions: usize,
+}
+
+impl Detection {
+    pub fn is_confident(&self) -> bool {
+        self.confidence >= CONFIDENT
+    }
+
+    pub fn apply(&self, config: &mut Config) {
+        config.set_platform(self.platform);
+        config.quirks = self.quirks;
+    }
+}
+
+impl fmt::Display for Detection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "detected: {} ({:.0}% confident)", self.platform, self.confidence * 100.0)?;
+        writeln!(f, "quirks:   {:?}", self.quirks)?;
+        writeln!(f, "trial:    {} instructions", self.trial_instructions)?;
+        for evidence in self.evidence.iter() {
+            writeln!(f, "    {}", evidence)?;
+        }
+        Ok(())
+    }
+}
+
+/// Guess the platform and quirks of a ROM from its code, followed by a short trial run
+pub fn detect(rom: &[Byte]) -> Detection {
+    let image = Image::new(rom);
+    let mut evidence = static_evidence(&image);
+    if let Some(clear) = hires_clear(&image) {
+        evidence.insert(0, Evidence::HiresPreamble { clear });
+    }
+
+    let is_extension = |platform: Platform, strong: bool| evidence.iter().any(|evidence| match *evidence {
+        Evidence::Extension { extension, .. } => extension.platform == platform && (!strong || extension.mnemonic != "DRW16"),
+        _ => false,
+    });
+
+    let is_hires = evidence.iter().any(|evidence| matches!(*evidence, Evidence::HiresPreamble { .. }));
+
+    let (platform, mut confidence) = if is_extension(Platform::XoChip, true) {
+        (Platform::XoChip, 0.95)
+    } else if is_extension(Platform::SuperChip, true) {
+        (Platform::SuperChip, 0.9)
+    } else if is_hires {
+        (Platform::HiresChip8, 0.9)
+    } else if is_extension(Platform::SuperChip, false) {
+        // DXY0 draws nothing on a CHIP-8, so it is most likely a 16x16 sprite
+        (Platform::SuperChip, 0.55)
+    } else {
+        // nothing sets a plain CHIP-8 program apart, not enough to trade the default quirks for the VIP's
+        (Platform::Chip8, 0.6)
+    };
+
+    let mut quirks = Quirks::for_platform(platform);
+
+    let count = |evidence: &Vec<Evidence>, predicate: fn(&Evidence) -> bool| evidence.iter().filter(|e| predicate(e)).count();
+
+    let from_vy = count(&evidence, |e| matches!(*e, Evidence::ShiftFromVy { .. }));
+    let in_place = count(&evidence, |e| matches!(*e, Evidence::ShiftInPlace { .. }));
+    if from_vy != in_place {
+        let shift_ignores_vy = in_place > from_vy;
+        if shift_ignores_vy != quirks.shift_ignores_vy {
+            quirks.shift_ignores_vy = shift_ignores_vy;
+            confidence *= 0.85;
+        }
+    }
+
+    let trial_instructions = trial_run(rom, platform, quirks, &mut evidence);
+
+    let relies = count(&evidence, |e| matches!(*e, Evidence::StoreReliesOnIncrement { .. }));
+    if relies > 0 && !quirks.load_store_increments_i {
+        quirks.load_store_increments_i = true;
+        confidence *= 0.85;
+    }
+
+    for e in evidence.iter() {
+        if let Evidence::TrialFault { fault } = *e {
+            confidence *= match fault {
+                // expected when the ROM is for a platform the interpreter doesn't run
+                Fault::InvalidInstruction { .. } if !matches!(platform, Platform::Chip8 | Platform::HiresChip8) => 1.0,
+                Fault::InvalidInstruction { .. } => 0.6,
+                _ => 0.8,
+            };
+        }
+    }
+
+    Detection {
+        platform,
+        quirks,
+        confidence,
+        evidence,
+        trial_instructions,
+    }
+}
+
+/// Where the program of a hi-res ROM clears the display, None unless it looks like one: jumping over
+/// the preamble, and calling the preamble's clear routine from code reachable from the entry point
+fn hires_clear(image: &Image) -> Option<Address> {
+    let entry = Platform::HiresChip8.entry_point();
+    if !image.data.starts_with(&HIRES_PREAMBLE_JUMP) || !image.contains(entry) {
+        return None;
+    }
+    image.reachable_from(entry)
+        .into_iter()
+        .find(|&address| image.decode_at(address) == Some(Decoded::Instruction(Instruction::HCLS)))
+}
+
+fn static_evidence(image: &Image) -> Vec<Evidence> {
+    let mut evidence = Vec::new();
+
+    for address in image.reachable() {
+        let code = image.word_at(address).unwrap_or(0);
+        match image.decode_at(address) {
+            Some(Decoded::Extension(extension)) => evidence.push(Evidence::Extension { address, extension }),
+            Some(Decoded::Instruction(instruction)) => match instruction {
+                Instruction::DRW { value: 0, .. } => {
+                    evidence.extend(Extension::parse_code(code).map(|extension| Evidence::Extension { address, extension }));
+                },
+                Instruction::SHR { x_reg_id, y_reg_id } | Instruction::SHL { x_reg_id, y_reg_id } => {
+                    if y_reg_id == 0 && x_reg_id != 0 {
+                        evidence.push(Evidence::ShiftInPlace { address });
+                    } else if y_reg_id != x_reg_id {
+                        evidence.push(Evidence::ShiftFromVy { address });
+                    }
+                },
+                _ => {},
+            },
+            _ => {},
+        }
+    }
+
+    evidence
+}
+
+/// Run the ROM for a while, watching how it uses I after stores and loads.
+/// Returns the number of instructions executed.
+fn trial_run(rom: &[Byte], platform: Platform, quirks: Quirks, evidence: &mut Vec<Evidence>) -> usize {
+    let mut config = Config::for_platform(platform);
+    config.quirks = quirks;
+
+    let mut cpu = match Cpu::with_config(rom, config) {
+        Ok(cpu) => cpu,
+        Err(_) => return 0,
+    };
+
+    let mut seen = BTreeSet::new();
+    let mut pending_store: Option<Address> = None;
+
+    for executed in 0..TRIAL_INSTRUCTIONS {
+        let address = cpu.get_registers().program_counter;
+        let instruction = cpu.next_instruction();
+
+        if let (Some(store), Some(instruction)) = (pending_store, instruction) {
+            match instruction {
+                Instruction::MOVI { .. } | Instruction::FONT { .. } => {
+                    if seen.insert(store) {
+                        evidence.push(Evidence::StoreReloadsI { address: store });
+                    }
+                    pending_store = None;
+                },
+                Instruction::DRW { .. } | Instruction::STR { .. } | Instruction::LDR { .. } |
+                Instruction::BCD { .. } | Instruction::ADI { .. } => {
+                    if seen.insert(store) {
+                        evidence.push(Evidence::StoreReliesOnIncrement { address: store });
+                    }
+                    pending_store = None;
+                },
+                _ => {},
+            }
+        }
+
+        if let Err(fault) = cpu.try_tick() {
+            evidence.push(Evidence::TrialFault { fault });
+            return executed;
+        }
+        // the trial keeps no time, a DRW waiting for the vertical blank gets it right away
+        cpu.vblank();
+
+        match instruction {
+            Some(Instruction::STR { .. }) | Some(Instruction::LDR { .. }) => pending_store = Some(address),
+            _ => {},
+        }
+
+        // waiting for a key or spinning on a jump to itself, nothing more to learn
+        if cpu.get_registers().program_counter == address {
+            return executed + 1;
+        }
+    }
+
+    TRIAL_INSTRUCTIONS
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A hi-res ROM: the preamble's jump, the preamble left blank but for a jump to 2C0 at 260,
+    /// and a program at 2C0
+    fn hires_rom(program: &[Byte]) -> Vec<Byte> {
+        let mut rom = HIRES_PREAMBLE_JUMP.to_vec();
+        rom.resize(Platform::HiresChip8.entry_point() as usize - 0x200, 0);
+        rom[0x60..0x62].copy_from_slice(&[0x12, 0xC0]);
+        rom.extend_from_slice(program);
+        rom
+    }
+
+    #[test]
+    fn hires_program() {
+        let detection = detect(&hires_rom(&[0x02, 0x30, 0x12, 0xC2]));
+        assert_eq!(detection.platform, Platform::HiresChip8);
+        assert_eq!(detection.evidence[0], Evidence::HiresPreamble { clear: 0x2C0 });
+    }
+
+    #[test]
+    fn jump_to_260_alone_is_not_hires() {
+        let detection = detect(&hires_rom(&[0x00, 0xE0, 0x12, 0xC2]));
+        assert_eq!(detection.platform, Platform::Chip8);
+    }
+
+    #[test]
+    fn plain_program_is_not_confident() {
+        let detection = detect(&[0x60, 0x05, 0xA2, 0x0A, 0xD0, 0x05, 0x70, 0x01, 0x12, 0x04]);
+        assert_eq!(detection.platform, Platform::Chip8);
+        assert!(!detection.is_confident());
+    }
+
+    #[test]
+    fn extensions_outweigh_the_preamble() {
+        let detection = detect(&hires_rom(&[0x02, 0x30, 0x00, 0xFF, 0x12, 0xC4]));
+        assert_eq!(detection.platform, Platform::SuperChip);
+    }
+}
@@ -0,0 +1,311 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+use {Address, Byte};
+use analysis::{Decoded, Image};
+use analysis::cfg::{Cfg, EdgeKind};
+use config::Quirk;
+use effects::LARGE_SPRITE_SIZE;
+use instructions::Instruction;
+use memory::{MEMORY_SIZE, PROGRAM_OFFSET};
+use stack::VIP_STACK_DEPTH;
+
+const VF: Byte = 0xF;
+
+/// How many calls can be nested on every platform, the VIP having the smallest stack
+const MAX_CALL_DEPTH: usize = VIP_STACK_DEPTH;
+
+/// The checks the linter runs
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Check {
+    /// VF is the destination or an operand of an operation that overwrites it with a flag
+    FlagClobbersResult,
+
+    /// a jump or call below the program offset, into the interpreter on the original hardware
+    InterpreterJump,
+
+    /// a jump or call to an odd address, which can't be an instruction of an aligned program
+    OddJump,
+
+    /// a function calling itself, directly or through others
+    Recursion,
+
+    /// calls nested deeper than the stack
+    CallDepth,
+
+    /// a sprite drawn from I reaches past the end of memory
+    DrawPastEnd,
+
+    /// the instruction behaves differently depending on a quirk
+    QuirkDependent(Quirk),
+}
+
+impl Check {
+    pub fn is_warning(&self) -> bool {
+        !matches!(*self, Check::QuirkDependent(_))
+    }
+}
+
+/// A problem the linter found at an address
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Finding {
+    pub address: Address,
+    pub check: Check,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let severity = if self.check.is_warning() { "warning" } else { "note" };
+        write!(f, "{:03X} {}: {}", self.address, severity, self.message)
+    }
+}
+
+/// Run every check over the reachable code of a ROM, findings are ordered by address
+pub fn lint(image: &Image, cfg: &Cfg) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    for block in cfg.blocks.values() {
+        // I is followed through the block, as long as it is only ever set from a constant
+        let mut i: Option<usize> = None;
+
+        for &(address, decoded) in block.instructions.iter() {
+            let instruction = match decoded {
+                Decoded::Instruction(instruction) => instruction,
+                Decoded::Extension(_) | Decoded::Invalid(_) => {
+                    i = None;
+                    continue;
+                },
+            };
+
+            check_instruction(address, &instruction, i, &mut findings);
+
+            i = match instruction {
+                Instruction::MOVI { address } => Some(address as usize),
+                Instruction::ADI { .. } | Instruction::FONT { .. } | Instruction::STR { .. } | Instruction::LDR { .. } => None,
+                _ => i,
+            };
+        }
+    }
+
+    check_calls(image, cfg, &mut findings);
+
+    findings.sort_by_key(|finding| finding.address);
+    findings
+}
+
+fn check_instruction(address: Address, instruction: &Instruction, i: Option<usize>, findings: &mut Vec<Finding>) {
+    use instructions::Instruction::*;
+
+    let mut found = |check: Check, message: String| findings.push(Finding {
+        address,
+        check,
+        message,
+    });
+
+    match *instruction {
+        ADDXY { x_reg_id: VF, .. } | SUBXY { x_reg_id: VF, .. } | RSUBXY { x_reg_id: VF, .. } |
+        SHR { x_reg_id: VF, .. } | SHL { x_reg_id: VF, .. } => found(
+            Check::FlagClobbersResult,
+            format!("{} writes its result to VF, which is then overwritten by the flag", instruction),
+        ),
+        // the flag of an earlier operation is used up, e.g adding a carry, and replaced by a new one
+        ADDXY { y_reg_id: VF, .. } | SUBXY { y_reg_id: VF, .. } | RSUBXY { y_reg_id: VF, .. } |
+        SHR { y_reg_id: VF, .. } | SHL { y_reg_id: VF, .. } => found(
+            Check::FlagClobbersResult,
+            format!("{} reads VF, which is then overwritten by the flag", instruction),
+        ),
+        _ => {},
+    }
+
+    match *instruction {
+        JMP { address: target } | JSR { address: target } | JMI { address: target } | SYS { address: target } => {
+            if (target as usize) < PROGRAM_OFFSET {
+                found(Check::InterpreterJump, format!("{} goes below {:03X}, into the interpreter", instruction, PROGRAM_OFFSET));
+            }
+            if target % 2 == 1 {
+                found(Check::OddJump, format!("{} goes to an odd address", instruction));
+            }
+        },
+        _ => {},
+    }
+
+    if let (DRW { value, .. }, Some(i)) = (*instruction, i) {
+        // a SCHIP program drawing DXY0 expects a 16x16 sprite
+        let size = if value == 0 { LARGE_SPRITE_SIZE } else { value as usize };
+        if i + size > MEMORY_SIZE {
+            found(Check::DrawPastEnd, format!("{} reads {} bytes from I = {:03X}, past the end of memory", instruction, size, i));
+        }
+    }
+
+    let quirk = match *instruction {
+        SHR { x_reg_id, y_reg_id } | SHL { x_reg_id, y_reg_id } if x_reg_id != y_reg_id => Some(Quirk::ShiftIgnoresVy),
+        ORXY { .. } | ANDXY { .. } | XORXY { .. } => Some(Quirk::VfReset),
+        STR { .. } | LDR { .. } => Some(Quirk::LoadStoreIncrementsI),
+        JMI { .. } => Some(Quirk::JumpUsesVx),
+        _ => None,
+    };
+    if let Some(quirk) = quirk {
+        found(Check::QuirkDependent(quirk), format!("{} depends on the {} quirk", instruction, quirk.name()));
+    }
+}
+
+fn check_calls(image: &Image, cfg: &Cfg, findings: &mut Vec<Finding>) {
+    // the call sites of each function, and what they call
+    let calls: BTreeMap<Address, Vec<(Address, Address)>> = cfg.functions.values()
+        .map(|function| {
+            let sites = function.blocks.iter()
+                .flat_map(|start| {
+                    let block = &cfg.blocks[start];
+                    let site = block.instructions.last().map_or(block.start, |&(address, _)| address);
+                    block.edges.iter()
+                        .filter(|edge| edge.kind == EdgeKind::Call)
+                        .map(move |edge| (site, edge.target))
+                })
+                .collect();
+            (function.entry, sites)
+        })
+        .collect();
+
+    let mut depths = BTreeMap::new();
+    let mut path = Vec::new();
+    let depth = call_depth(image.base, &calls, &mut depths, &mut path, findings);
+
+    if depth > MAX_CALL_DEPTH {
+        let mut chain = vec![cfg.function_name(image.base)];
+        let mut entry = image.base;
+        while chain.len() <= depth {
+            let deepest = calls.get(&entry)
+                .and_then(|sites| sites.iter().max_by_key(|&&(_, callee)| depths.get(&callee).cloned().unwrap_or(0)));
+            match deepest {
+                Some(&(_, callee)) => {
+                    chain.push(cfg.function_name(callee));
+                    entry = callee;
+                },
+                None => break,
+            }
+        }
+        findings.push(Finding {
+            address: image.base,
+            check: Check::CallDepth,
+            message: format!(
+                "calls nest {} deep ({}), the VIP stack only holds {}", depth, chain.join(" -> "), MAX_CALL_DEPTH
+            ),
+        });
+    }
+}
+
+/// The deepest nesting of calls starting from entry, reporting recursion found on the way
+fn call_depth(
+    entry: Address,
+    calls: &BTreeMap<Address, Vec<(Address, Address)>>,
+    depths: &mut BTreeMap<Address, usize>,
+    path: &mut Vec<Address>,
+    findings: &mut Vec<Finding>,
+) -> usize {
+    if let Some(&depth) = depths.get(&entry) {
+        return depth;
+    }
+    path.push(entry);
+
+    let mut depth = 0;
+    for &(site, callee) in calls.get(&entry).map_or(&[][..], Vec::as_slice) {
+        if path.contains(&callee) {
+            findings.push(Finding {
+                address: site,
+                check: Check::Recursion,
+                message: format!("call to {:03X} recurses, nothing bounds its depth", callee),
+            });
+            continue;
+        }
+        depth = depth.max(1 + call_depth(callee, calls, depths, path, findings));
+    }
+
+    path.pop();
+    depths.insert(entry, depth);
+    depth
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The checks that found something, by address
+    fn checks(rom: &[Byte]) -> Vec<(Address, Check)> {
+        let image = Image::new(rom);
+        lint(&image, &Cfg::build(&image)).into_iter().map(|finding| (finding.address, finding.check)).collect()
+    }
+
+    #[test]
+    fn flag_clobbers_result() {
+        let rom = [
+            0x8F, 0x14, // 200 ADD VF, V1
+            0x81, 0xF5, // 202 SUB V1, VF
+            0x81, 0x24, // 204 ADD V1, V2
+            0x12, 0x06, // 206 JMP 206
+        ];
+        assert_eq!(checks(&rom), vec![(0x200, Check::FlagClobbersResult), (0x202, Check::FlagClobbersResult)]);
+    }
+
+    #[test]
+    fn interpreter_jump() {
+        assert_eq!(checks(&[0x11, 0x00]), vec![(0x200, Check::InterpreterJump)]);
+    }
+
+    #[test]
+    fn odd_jump() {
+        assert_eq!(checks(&[0x12, 0x03, 0x00, 0x00]), vec![(0x200, Check::OddJump)]);
+    }
+
+    #[test]
+    fn recursion() {
+        let rom = [
+            0x22, 0x04, // 200 JSR 204
+            0x12, 0x02, // 202 JMP 202
+            0x22, 0x04, // 204 JSR 204
+            0x00, 0xEE, // 206 RTS
+        ];
+        assert_eq!(checks(&rom), vec![(0x204, Check::Recursion)]);
+    }
+
+    #[test]
+    fn call_depth() {
+        // each function calls the next, one more than the stack holds
+        let mut rom = Vec::new();
+        for depth in 0..=MAX_CALL_DEPTH {
+            let callee = PROGRAM_OFFSET + 4 * (depth + 1);
+            rom.extend_from_slice(&[0x20 | (callee >> 8) as Byte, callee as Byte, 0x00, 0xEE]);
+        }
+        rom.extend_from_slice(&[0x00, 0xEE]);
+        assert_eq!(checks(&rom), vec![(0x200, Check::CallDepth)]);
+
+        rom.truncate(rom.len() - 6);
+        rom.extend_from_slice(&[0x00, 0xEE]);
+        assert_eq!(checks(&rom), vec![]);
+    }
+
+    #[test]
+    fn draw_past_end() {
+        let rom = [
+            0xAF, 0xFC, // 200 MOVI FFC
+            0xD0, 0x14, // 202 DRW V0, V1, 4
+            0xD0, 0x15, // 204 DRW V0, V1, 5
+            0x12, 0x06, // 206 JMP 206
+        ];
+        assert_eq!(checks(&rom), vec![(0x204, Check::DrawPastEnd)]);
+    }
+
+    #[test]
+    fn quirk_dependent() {
+        let rom = [
+            0x80, 0x16, // 200 SHR V0, V1
+            0x80, 0x06, // 202 SHR V0, V0
+            0xF1, 0x55, // 204 STR V1
+            0x12, 0x06, // 206 JMP 206
+        ];
+        assert_eq!(checks(&rom), vec![
+            (0x200, Check::QuirkDependent(Quirk::ShiftIgnoresVy)),
+            (0x204, Check::QuirkDependent(Quirk::LoadStoreIncrementsI)),
+        ]);
+    }
+}
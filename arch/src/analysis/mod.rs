@@ -0,0 +1,144 @@
+use std::collections::BTreeSet;
+use std::fmt;
+
+use {Address, Byte};
+use instructions::{Instruction, Extension};
+use memory::{MEMORY_SIZE, PROGRAM_OFFSET};
+
+pub mod cfg;
+pub mod decompile;
+pub mod detect;
+pub mod lint;
+pub mod recompile;
+pub mod stats;
+
+/// What a word of a ROM decodes to
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decoded {
+    Instruction(Instruction),
+
+    /// an opcode of SCHIP or XO-CHIP, which the interpreter doesn't run
+    Extension(Extension),
+
+    Invalid(u16),
+}
+
+impl Decoded {
+    /// In bytes
+    pub fn length(&self) -> usize {
+        match *self {
+            Decoded::Extension(extension) => extension.length,
+            _ => 2,
+        }
+    }
+}
+
+impl fmt::Display for Decoded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Decoded::Instruction(instruction) => write!(f, "{}", instruction),
+            Decoded::Extension(extension) => write!(f, "{}", extension.mnemonic),
+            Decoded::Invalid(code) => write!(f, "DW {:04X}", code),
+        }
+    }
+}
+
+/// A ROM as it sits in memory, starting at the program offset
+#[derive(Clone, Copy, Debug)]
+pub struct Image<'a> {
+    pub data: &'a [Byte],
+    pub base: Address,
+}
+
+impl<'a> Image<'a> {
+    pub fn new(data: &'a [Byte]) -> Image<'a> {
+        Image {
+            data,
+            base: PROGRAM_OFFSET as Address,
+        }
+    }
+
+    /// One past the last address of the ROM
+    pub fn end(&self) -> usize {
+        (self.base as usize + self.data.len()).min(MEMORY_SIZE)
+    }
+
+    pub fn contains(&self, address: Address) -> bool {
+        address >= self.base && (address as usize) < self.end()
+    }
+
+    pub fn byte_at(&self, address: Address) -> Option<Byte> {
+        if self.contains(address) {
+            Some(self.data[(address - self.base) as usize])
+        } else {
+            None
+        }
+    }
+
+    pub fn word_at(&self, address: Address) -> Option<u16> {
+        let high = self.byte_at(address)?;
+        let low = self.byte_at(address.wrapping_add(1))?;
+        Some(((high as u16) << 8) | low as u16)
+    }
+
+    /// Decode the instruction at an address, if it is within the ROM
+    pub fn decode_at(&self, address: Address) -> Option<Decoded> {
+        let code = self.word_at(address)?;
+        // DXY0 is a valid, if useless, CHIP-8 draw, so extensions only take what CHIP-8 can't parse
+        Some(match Instruction::parse_code(code) {
+            Some(instruction) => Decoded::Instruction(instruction),
+            None => match Extension::parse_code(code) {
+                Some(extension) => Decoded::Extension(extension),
+                None => Decoded::Invalid(code),
+            },
+        })
+    }
+
+    /// Every address execution can reach by following the program from its start,
+    /// without knowing register values. Computed jumps (JMI) are not followed.
+    pub fn reachable(&self) -> BTreeSet<Address> {
+        self.reachable_from(self.base)
+    }
+
+    /// Every address execution can reach from start, such as an entry point past the start of the image
+    pub fn reachable_from(&self, start: Address) -> BTreeSet<Address> {
+        let mut reached = BTreeSet::new();
+        let mut pending = vec![start];
+
+        while let Some(address) = pending.pop() {
+            if reached.contains(&address) {
+                continue;
+            }
+            let decoded = match self.decode_at(address) {
+                Some(Decoded::Invalid(_)) | None => continue,
+                Some(decoded) => decoded,
+            };
+
+            reached.insert(address);
+            pending.extend(successors(&decoded, address));
+        }
+
+        reached
+    }
+}
+
+/// Where execution can continue after the instruction at address
+pub fn successors(decoded: &Decoded, address: Address) -> Vec<Address> {
+    use instructions::Instruction::*;
+
+    let next = address.wrapping_add(decoded.length() as Address);
+    match *decoded {
+        Decoded::Instruction(instruction) => match instruction {
+            JMP { address } => vec![address],
+            JSR { address } => vec![address, next],
+            RTS | JMI { .. } => vec![],
+            SE { .. } | SNE { .. } | SEXY { .. } | SNEXY { .. } | SKP { .. } | SKNP { .. } => {
+                vec![next, next.wrapping_add(2)]
+            },
+            _ => vec![next],
+        },
+        Decoded::Extension(extension) if extension.mnemonic == "EXIT" => vec![],
+        Decoded::Extension(_) => vec![next],
+        Decoded::Invalid(_) => vec![],
+    }
+}
@@ -0,0 +1,286 @@
+use std::fmt;
+use std::fmt::Write;
+
+use {Address, Byte};
+use analysis::cfg::{BasicBlock, Cfg};
+use analysis::{Decoded, Image};
+use config::{Config, Quirk};
+use instructions::Instruction;
+use instructions::Instruction::*;
+use memory::{FONT_HEIGHT, FONT_OFFSET};
+
+/// How an instruction is recompiled
+enum Translation {
+    /// straight into statements on the registers
+    Inline(String),
+
+    /// a skip or jump, into the program counter to continue at
+    Branch(String),
+
+    /// handed to the cpu, for anything touching memory, the display, the stack or keys
+    Execute,
+
+    /// left to the interpreter, which faults on it just the same
+    Interpret,
+}
+
+/// Rust source of a module running the program of a ROM natively.
+///
+/// Every basic block of the control-flow graph becomes a function updating the registers of an
+/// `arch::cpu::Cpu` directly, which executes what the block can't do by itself. The module's `run`
+/// dispatches on the program counter and falls back to the interpreter wherever no block starts,
+/// for computed jumps, and for blocks whose bytes were overwritten since.
+/// The quirks of config are compiled in, the module's `config` returns it for creating the cpu.
+pub fn recompile(image: &Image, cfg: &Cfg, config: &Config) -> String {
+    let mut source = String::new();
+    // writing to a String can't fail
+    let _ = write_module(&mut source, image, cfg, config);
+    source
+}
+
+fn write_module(out: &mut String, image: &Image, cfg: &Cfg, config: &Config) -> fmt::Result {
+    writeln!(out, "//! Recompiled from a {} byte ROM for {} by `chip8 recompile`", image.data.len(), config.platform)?;
+    writeln!(out, "//!")?;
+    writeln!(out, "//! `let mut cpu = Cpu::with_config(ROM, config())?;` then `run(&mut cpu, n)` wherever `cpu.run(n)` would go.")?;
+    if !cfg.unresolved.is_empty() {
+        let unresolved: Vec<String> = cfg.unresolved.iter().map(|address| format!("{:03X}", address)).collect();
+        writeln!(out, "//! Computed jumps at {} are interpreted wherever they lead.", unresolved.join(", "))?;
+    }
+    writeln!(out)?;
+    writeln!(out, "#![allow(unused_imports, clippy::all)]")?;
+    writeln!(out)?;
+    writeln!(out, "use arch::config::{{Config, Platform, Quirk, Timing}};")?;
+    writeln!(out, "use arch::cpu::{{Cpu, Fault}};")?;
+    writeln!(out, "use arch::instructions::Instruction::*;")?;
+    writeln!(out, "use arch::stack::StackPolicy;")?;
+    writeln!(out)?;
+
+    writeln!(out, "pub const ROM: &[u8] = &[")?;
+    for line in image.data.chunks(16) {
+        let bytes: Vec<String> = line.iter().map(|byte| format!("0x{:02X},", byte)).collect();
+        writeln!(out, "    {}", bytes.join(" "))?;
+    }
+    writeln!(out, "];")?;
+    writeln!(out)?;
+
+    writeln!(out, "/// The config the program was recompiled for, its quirks can't change")?;
+    writeln!(out, "pub fn config() -> Config {{")?;
+    writeln!(out, "    let mut config = Config::for_platform(Platform::{:?});", config.platform)?;
+    for &quirk in Quirk::ALL.iter() {
+        writeln!(out, "    config.quirks.set(Quirk::{:?}, {});", quirk, config.quirks.get(quirk))?;
+    }
+    writeln!(out, "    config.clock_speed = {};", config.clock_speed)?;
+    writeln!(out, "    config.timing = Timing::{:?};", config.timing)?;
+    writeln!(out, "    config.stack_depth = {};", config.stack_depth)?;
+    writeln!(out, "    config.stack_policy = StackPolicy::{:?};", config.stack_policy)?;
+    writeln!(out, "    config")?;
+    writeln!(out, "}}")?;
+    writeln!(out)?;
+
+    writeln!(out, "/// Execute count instructions, stopping at the first fault. A block only runs if it fits in what is left.")?;
+    writeln!(out, "pub fn run(cpu: &mut Cpu, count: usize) -> Result<(), Fault> {{")?;
+    writeln!(out, "    let mut executed = 0;")?;
+    if config.quirks.display_wait {
+        writeln!(out, "    while executed < count && !cpu.is_waiting_for_vblank() {{")?;
+    } else {
+        writeln!(out, "    while executed < count {{")?;
+    }
+    writeln!(out, "        let left = count - executed;")?;
+    writeln!(out, "        executed += match cpu.get_registers().program_counter {{")?;
+    for block in cfg.blocks.values() {
+        writeln!(out, "            0x{:03X} if left >= {} => {}(cpu)?,", block.start, block.instructions.len(), block_name(block))?;
+    }
+    writeln!(out, "            _ => {{")?;
+    writeln!(out, "                cpu.try_tick()?;")?;
+    writeln!(out, "                1")?;
+    writeln!(out, "            }},")?;
+    writeln!(out, "        }};")?;
+    writeln!(out, "    }}")?;
+    writeln!(out, "    Ok(())")?;
+    writeln!(out, "}}")?;
+
+    for block in cfg.blocks.values() {
+        writeln!(out)?;
+        write_block(out, image, cfg, block, config)?;
+    }
+    Ok(())
+}
+
+fn block_name(block: &BasicBlock) -> String {
+    format!("block_{:03x}", block.start)
+}
+
+/// A block as a function returning how many instructions it executed
+fn write_block(out: &mut String, image: &Image, cfg: &Cfg, block: &BasicBlock, config: &Config) -> fmt::Result {
+    let function = cfg.functions.values()
+        .find(|function| function.blocks.contains(&block.start))
+        .map(|function| format!(", in {}", cfg.function_name(function.entry)))
+        .unwrap_or_default();
+    let bytes: Vec<String> = (block.start..block.end)
+        .map(|address| format!("0x{:02X}", image.byte_at(address).unwrap_or(0)))
+        .collect();
+
+    writeln!(out, "/// {:03X}-{:03X}{}", block.start, block.end - 1, function)?;
+    writeln!(out, "fn {}(cpu: &mut Cpu) -> Result<usize, Fault> {{", block_name(block))?;
+    writeln!(out, "    // self-modified since, the interpreter runs what is there now")?;
+    writeln!(out, "    if cpu.get_memory().data()[0x{:03X}..0x{:03X}] != [{}] {{", block.start, block.end, bytes.join(", "))?;
+    writeln!(out, "        cpu.try_tick()?;")?;
+    writeln!(out, "        return Ok(1);")?;
+    writeln!(out, "    }}")?;
+
+    let count = block.instructions.len();
+    let mut inline = false;
+    for (index, &(address, decoded)) in block.instructions.iter().enumerate() {
+        let next = address + decoded.length() as Address;
+        let is_last = index + 1 == count;
+        let translation = match decoded {
+            Decoded::Instruction(instruction) => translate(instruction, address, config),
+            _ => Translation::Interpret,
+        };
+
+        match translation {
+            Translation::Inline(_) | Translation::Branch(_) if !inline => {
+                writeln!(out, "    let r = cpu.get_registers_mut();")?;
+                inline = true;
+            },
+            Translation::Inline(_) | Translation::Branch(_) => {},
+            Translation::Execute | Translation::Interpret => inline = false,
+        }
+        writeln!(out, "    // {:03X}: {}", address, decoded)?;
+
+        match translation {
+            Translation::Inline(statements) => {
+                writeln!(out, "    {}", statements)?;
+                if is_last {
+                    writeln!(out, "    r.program_counter = 0x{:03X};", next)?;
+                }
+            },
+            Translation::Branch(program_counter) => {
+                writeln!(out, "    r.program_counter = {};", program_counter)?;
+            },
+            Translation::Execute | Translation::Interpret => {
+                writeln!(out, "    cpu.get_registers_mut().program_counter = 0x{:03X};", address)?;
+                match (translation, decoded) {
+                    (Translation::Execute, Decoded::Instruction(instruction)) => writeln!(out, "    cpu.execute({:?})?;", instruction)?,
+                    _ => writeln!(out, "    cpu.try_tick()?;")?,
+                }
+                // a key wait or a SYS stays where it is, anything else may not carry on either,
+                // nor can the program after a DRW waiting for the vertical blank
+                let waits = match decoded {
+                    Decoded::Instruction(DRW { .. }) => config.quirks.display_wait,
+                    _ => false,
+                };
+                if !is_last && waits {
+                    writeln!(out, "    if cpu.is_waiting_for_vblank() {{")?;
+                    writeln!(out, "        return Ok({});", index + 1)?;
+                    writeln!(out, "    }}")?;
+                }
+                if !is_last {
+                    writeln!(out, "    if cpu.get_registers().program_counter != 0x{:03X} {{", next)?;
+                    writeln!(out, "        return Ok({});", index + 1)?;
+                    writeln!(out, "    }}")?;
+                }
+            },
+        }
+    }
+    writeln!(out, "    Ok({})", count)?;
+    writeln!(out, "}}")
+}
+
+/// An instruction as Rust, doing exactly what execute_instruction does with the quirks of config
+fn translate(instruction: Instruction, address: Address, config: &Config) -> Translation {
+    let quirks = &config.quirks;
+    let v = |reg_id: Byte| format!("r.vs[0x{:X}]", reg_id);
+    let vf_reset = if quirks.vf_reset { format!(" {} = 0;", v(0x0F)) } else { String::new() };
+    let shift_source = |x_reg_id: Byte, y_reg_id: Byte| v(if quirks.shift_ignores_vy { x_reg_id } else { y_reg_id });
+    let skip = |condition: String| Translation::Branch(format!("if {} {{ 0x{:03X} }} else {{ 0x{:03X} }}", condition, address + 4, address + 2));
+
+    let statements = match instruction {
+        JMP { address } => return Translation::Branch(format!("0x{:03X}", address)),
+        SE { reg_id, value } => return skip(format!("{} == 0x{:02X}", v(reg_id), value)),
+        SNE { reg_id, value } => return skip(format!("{} != 0x{:02X}", v(reg_id), value)),
+        SEXY { x_reg_id, y_reg_id } => return skip(format!("{} == {}", v(x_reg_id), v(y_reg_id))),
+        SNEXY { x_reg_id, y_reg_id } => return skip(format!("{} != {}", v(x_reg_id), v(y_reg_id))),
+        MOV { reg_id, value } => format!("{} = 0x{:02X};", v(reg_id), value),
+        ADD { reg_id, value } => format!("{0} = {0}.wrapping_add(0x{1:02X});", v(reg_id), value),
+        MOVXY { x_reg_id, y_reg_id } => format!("{} = {};", v(x_reg_id), v(y_reg_id)),
+        ORXY { x_reg_id, y_reg_id } => format!("{} |= {};{}", v(x_reg_id), v(y_reg_id), vf_reset),
+        ANDXY { x_reg_id, y_reg_id } => format!("{} &= {};{}", v(x_reg_id), v(y_reg_id), vf_reset),
+        XORXY { x_reg_id, y_reg_id } => format!("{} ^= {};{}", v(x_reg_id), v(y_reg_id), vf_reset),
+        ADDXY { x_reg_id, y_reg_id } => format!(
+            "{{ let (sum, carry) = {0}.overflowing_add({1}); {0} = sum; {2} = carry as u8; }}",
+            v(x_reg_id), v(y_reg_id), v(0x0F)
+        ),
+        SUBXY { x_reg_id, y_reg_id } => format!(
+            "{{ let (x, y) = ({0}, {1}); {0} = x.wrapping_sub(y); {2} = (x >= y) as u8; }}",
+            v(x_reg_id), v(y_reg_id), v(0x0F)
+        ),
+        RSUBXY { x_reg_id, y_reg_id } => format!(
+            "{{ let (x, y) = ({0}, {1}); {0} = y.wrapping_sub(x); {2} = (y >= x) as u8; }}",
+            v(x_reg_id), v(y_reg_id), v(0x0F)
+        ),
+        SHR { x_reg_id, y_reg_id } => format!(
+            "{{ let value = {}; {} = value >> 1; {} = value & 0x01; }}",
+            shift_source(x_reg_id, y_reg_id), v(x_reg_id), v(0x0F)
+        ),
+        SHL { x_reg_id, y_reg_id } => format!(
+            "{{ let value = {}; {} = value << 1; {} = value >> 7; }}",
+            shift_source(x_reg_id, y_reg_id), v(x_reg_id), v(0x0F)
+        ),
+        MOVI { address } => format!("r.i = 0x{:03X};", address),
+        ADI { reg_id } => format!("r.i = r.i.wrapping_add({} as u16);", v(reg_id)),
+        FONT { reg_id } => format!("r.i = 0x{:03X} + ({} as u16 & 0x0F) * {};", FONT_OFFSET, v(reg_id), FONT_HEIGHT),
+        GDELAY { reg_id } => format!("{} = r.delay_timer;", v(reg_id)),
+        SDELAY { reg_id } => format!("r.delay_timer = {};", v(reg_id)),
+        SSOUND { reg_id } => format!("r.sound_timer = {};", v(reg_id)),
+        SYS { .. } | CLS | HCLS | RTS | JSR { .. } | JMI { .. } | RAND { .. } | DRW { .. } | SKP { .. } | SKNP { .. } |
+        KEY { .. } | BCD { .. } | STR { .. } | LDR { .. } => return Translation::Execute,
+    };
+    Translation::Inline(statements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Platform;
+
+    #[test]
+    fn small_program() {
+        let rom = [
+            0x60, 0x05, // 200 MOV V0, 05
+            0x30, 0x05, // 202 SE V0, 05
+            0x12, 0x00, // 204 JMP 200
+            0xA2, 0x0A, // 206 MOVI 20A
+            0x12, 0x08, // 208 JMP 208
+        ];
+        let image = Image::new(&rom);
+        let cfg = Cfg::build(&image);
+        let mut config = Config::for_platform(Platform::SuperChip);
+        config.clock_speed = 1000;
+        let source = recompile(&image, &cfg, &config);
+
+        // a block for each place the program goes to, dispatched on if it fits
+        assert!(source.contains("            0x200 if left >= 2 => block_200(cpu)?,\n"));
+        for &start in [0x204, 0x206, 0x208].iter() {
+            assert!(source.contains(&format!("            0x{:03X} if left >= 1 => block_{:03x}(cpu)?,\n", start, start)));
+        }
+        assert!(source.contains("    r.vs[0x0] = 0x05;\n    // 202: SE V0, 05\n    r.program_counter = if r.vs[0x0] == 0x05 { 0x206 } else { 0x204 };\n    Ok(2)\n"));
+        assert!(source.contains("    r.i = 0x20A;\n    r.program_counter = 0x208;\n    Ok(1)\n"));
+
+        // the config comes along, the quirks of SCHIP included
+        assert!(source.contains("    let mut config = Config::for_platform(Platform::SuperChip);\n"));
+        assert!(source.contains("    config.quirks.set(Quirk::Clipping, true);\n"));
+        assert!(source.contains("    config.quirks.set(Quirk::LoadStoreIncrementsI, false);\n"));
+        assert!(source.contains("    config.clock_speed = 1000;\n"));
+
+        // blocks whose bytes changed are left to the interpreter
+        assert!(source.contains(concat!(
+            "fn block_206(cpu: &mut Cpu) -> Result<usize, Fault> {\n",
+            "    // self-modified since, the interpreter runs what is there now\n",
+            "    if cpu.get_memory().data()[0x206..0x208] != [0xA2, 0x0A] {\n",
+            "        cpu.try_tick()?;\n",
+            "        return Ok(1);\n",
+            "    }\n",
+        )));
+    }
+}
@@ -0,0 +1,194 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use {Address, Byte};
+use analysis::{Decoded, Image};
+use config::{Platform};
+use hashes;
+use instructions::{Extension, Instruction};
+
+/// Whether a range of the ROM looks like it is executed or only read
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Usage {
+    Code,
+    Data,
+}
+
+/// A contiguous range of the ROM with the same usage, end is exclusive
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UsageRegion {
+    pub usage: Usage,
+    pub start: Address,
+    pub end: Address,
+}
+
+/// RomStats
+///
+/// What can be told about a ROM without running it
+pub struct RomStats {
+    pub size: usize,
+    pub sha1: String,
+    pub crc32: u32,
+
+    /// how many times each instruction shows up when decoding every even aligned word
+    pub histogram: BTreeMap<&'static str, usize>,
+
+    /// even aligned words that aren't instructions of any platform
+    pub invalid_words: usize,
+
+    /// SCHIP and XO-CHIP opcodes, by address. These can be data that happens to look like one,
+    /// unless the address is within a code region.
+    pub extensions: Vec<(Address, Extension)>,
+
+    pub platform: Platform,
+    pub regions: Vec<UsageRegion>,
+    pub jump_targets: BTreeSet<Address>,
+    pub call_targets: BTreeSet<Address>,
+}
+
+impl RomStats {
+    pub fn of(rom: &[Byte]) -> RomStats {
+        let image = Image::new(rom);
+
+        let mut histogram = BTreeMap::new();
+        let mut invalid_words = 0;
+        let mut extensions = Vec::new();
+
+        for address in (image.base as usize..image.end()).step_by(2).map(|address| address as Address) {
+            match image.decode_at(address) {
+                Some(Decoded::Instruction(instruction)) => {
+                    *histogram.entry(instruction.mnemonic()).or_insert(0) += 1;
+                    // SCHIP's 16x16 sprites reuse DXY0
+                    if let Instruction::DRW { value: 0, .. } = instruction {
+                        let code = image.word_at(address).unwrap_or(0);
+                        extensions.extend(Extension::parse_code(code).map(|extension| (address, extension)));
+                    }
+                },
+                Some(Decoded::Extension(extension)) => {
+                    *histogram.entry(extension.mnemonic).or_insert(0) += 1;
+                    extensions.push((address, extension));
+                },
+                Some(Decoded::Invalid(_)) => invalid_words += 1,
+                None => {},
+            }
+        }
+
+        let reachable = image.reachable();
+
+        let mut jump_targets = BTreeSet::new();
+        let mut call_targets = BTreeSet::new();
+        for address in reachable.iter() {
+            match image.decode_at(*address) {
+                Some(Decoded::Instruction(Instruction::JMP { address })) => { jump_targets.insert(address); },
+                Some(Decoded::Instruction(Instruction::JMI { address })) => { jump_targets.insert(address); },
+                Some(Decoded::Instruction(Instruction::JSR { address })) => { call_targets.insert(address); },
+                _ => {},
+            }
+        }
+
+        let in_code = |address: Address| reachable.range(..=address)
+            .next_back()
+            .is_some_and(|start| {
+                let length = image.decode_at(*start).map_or(2, |decoded| decoded.length());
+                (address as usize) < *start as usize + length
+            });
+
+        let platform = extensions.iter()
+            .filter(|&&(address, _)| in_code(address))
+            .map(|&(_, extension)| extension.platform)
+            .max_by_key(|platform| match *platform {
+                Platform::Chip8 | Platform::HiresChip8 => 0,
+                Platform::SuperChip => 1,
+                Platform::XoChip => 2,
+            })
+            .unwrap_or(Platform::Chip8);
+
+        let mut regions: Vec<UsageRegion> = Vec::new();
+        for address in image.base as usize..image.end() {
+            let address = address as Address;
+            let usage = if in_code(address) { Usage::Code } else { Usage::Data };
+            match regions.last_mut() {
+                Some(ref mut region) if region.usage == usage => region.end = address + 1,
+                _ => regions.push(UsageRegion {
+                    usage,
+                    start: address,
+                    end: address + 1,
+                }),
+            }
+        }
+
+        RomStats {
+            size: rom.len(),
+            sha1: hashes::sha1(rom),
+            crc32: hashes::crc32(rom),
+            histogram,
+            invalid_words,
+            extensions,
+            platform,
+            regions,
+            jump_targets,
+            call_targets,
+        }
+    }
+
+    /// Bytes of the ROM estimated to be code
+    pub fn code_size(&self) -> usize {
+        self.regions.iter()
+            .filter(|region| region.usage == Usage::Code)
+            .map(|region| (region.end - region.start) as usize)
+            .sum()
+    }
+}
+
+fn write_addresses(f: &mut fmt::Formatter, addresses: &BTreeSet<Address>) -> fmt::Result {
+    for (index, address) in addresses.iter().enumerate() {
+        if index % 8 == 0 {
+            write!(f, "\n   ")?;
+        }
+        write!(f, " {:03X}", address)?;
+    }
+    writeln!(f)
+}
+
+impl fmt::Display for RomStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "size:     {} bytes", self.size)?;
+        writeln!(f, "sha1:     {}", self.sha1)?;
+        writeln!(f, "crc32:    {:08x}", self.crc32)?;
+        writeln!(f, "platform: {}", self.platform)?;
+        writeln!(f, "code:     {} bytes", self.code_size())?;
+        writeln!(f, "data:     {} bytes", self.size - self.code_size())?;
+
+        writeln!(f, "\nregions:")?;
+        for region in self.regions.iter() {
+            let usage = match region.usage {
+                Usage::Code => "code",
+                Usage::Data => "data",
+            };
+            writeln!(f, "    {:03X}-{:03X} {}", region.start, region.end - 1, usage)?;
+        }
+
+        writeln!(f, "\nopcodes:")?;
+        let mut histogram: Vec<_> = self.histogram.iter().collect();
+        histogram.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (mnemonic, count) in histogram {
+            writeln!(f, "    {:<8} {}", mnemonic, count)?;
+        }
+        writeln!(f, "    {:<8} {}", "invalid", self.invalid_words)?;
+
+        if !self.extensions.is_empty() {
+            writeln!(f, "\nextension opcodes:")?;
+            for &(address, extension) in self.extensions.iter() {
+                let usage = if self.regions.iter().any(|region| {
+                    region.usage == Usage::Code && address >= region.start && address < region.end
+                }) { "code" } else { "data" };
+                writeln!(f, "    {:03X} {:<8} {:<6} in {}", address, extension.mnemonic, extension.platform, usage)?;
+            }
+        }
+
+        write!(f, "\njump targets:")?;
+        write_addresses(f, &self.jump_targets)?;
+        write!(f, "call targets:")?;
+        write_addresses(f, &self.call_targets)
+    }
+}
@@ -0,0 +1,289 @@
+use std::fmt;
+
+use {Address, Byte};
+use memory::{Memory, MEMORY_SIZE};
+use registers::{Registers};
+
+/// Filters used to narrow down a RAM search
+///
+/// Each filter compares the current value of a candidate address against
+/// either a given value or the value it had when the previous filter was applied
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SearchFilter {
+    /// keep addresses whose current value equals the given value
+    Equal(Byte),
+
+    /// keep addresses whose value differs from the previous snapshot
+    Changed,
+
+    /// keep addresses whose value is greater than in the previous snapshot
+    Increased,
+
+    /// keep addresses whose value is smaller than in the previous snapshot
+    Decreased,
+}
+
+/// RamSearch
+///
+/// Starts with every address in memory as a candidate and narrows them down
+/// every time a filter is applied, usually once per frame or once per in game event
+pub struct RamSearch {
+    candidates: Vec<Address>,
+    snapshot: [Byte; MEMORY_SIZE],
+}
+
+impl RamSearch {
+    pub fn new(memory: &Memory) -> RamSearch {
+        RamSearch {
+            candidates: (0..MEMORY_SIZE as Address).collect(),
+            snapshot: *memory.data(),
+        }
+    }
+
+    /// Start over, making every address a candidate again
+    pub fn reset(&mut self, memory: &Memory) {
+        *self = RamSearch::new(memory);
+    }
+
+    /// Drop candidates not matching the filter and take a new snapshot of memory
+    pub fn filter(&mut self, memory: &Memory, filter: SearchFilter) {
+        let snapshot = &self.snapshot;
+        self.candidates.retain(|address| {
+            let previous = snapshot[*address as usize];
+            let current = memory.data()[*address as usize];
+            match filter {
+                SearchFilter::Equal(value) => current == value,
+                SearchFilter::Changed => current != previous,
+                SearchFilter::Increased => current > previous,
+                SearchFilter::Decreased => current < previous,
+            }
+        });
+
+        self.snapshot = *memory.data();
+    }
+
+    pub fn candidates(&self) -> &[Address] {
+        &self.candidates
+    }
+}
+
+/// What a cheat pins to its value
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CheatTarget {
+    /// a byte in memory
+    Memory(Address),
+
+    /// one of the V0 - VF registers
+    Register(Byte),
+}
+
+/// A single cheat, pinning its target to a value every frame while enabled
+#[derive(Clone, Debug, PartialEq)]
+pub struct Cheat {
+    pub target: CheatTarget,
+    pub value: Byte,
+    pub enabled: bool,
+    pub description: String,
+}
+
+impl Cheat {
+    /// Freeze a memory address at a given value
+    pub fn freeze(address: Address, value: Byte, description: &str) -> Cheat {
+        Cheat {
+            target: CheatTarget::Memory(address),
+            value,
+            enabled: true,
+            description: description.to_string(),
+        }
+    }
+
+    /// Patch a register to a given value
+    pub fn patch(reg_id: Byte, value: Byte, description: &str) -> Cheat {
+        Cheat {
+            target: CheatTarget::Register(reg_id),
+            value,
+            enabled: true,
+            description: description.to_string(),
+        }
+    }
+
+    /// Parse a single line of a cheat file
+    ///
+    /// The format is `<on|off> <mem ADDR|reg X> VALUE [description]`, numbers in hex,
+    /// for example `on mem 0F2A 03 infinite lives`
+    pub fn parse(line: &str) -> Result<Cheat, CheatError> {
+        let invalid = || CheatError::InvalidLine(line.to_string());
+
+        let line = line.trim();
+        let mut parts = line.split_whitespace();
+
+        let enabled = match parts.next() {
+            Some("on") => true,
+            Some("off") => false,
+            _ => return Err(invalid()),
+        };
+
+        let kind = parts.next().ok_or_else(invalid)?;
+        let location = parts.next().ok_or_else(invalid)?;
+        let target = match kind {
+            "mem" => {
+                let address = Address::from_str_radix(location, 16).map_err(|_| invalid())?;
+                if address as usize >= MEMORY_SIZE {
+                    return Err(invalid());
+                }
+                CheatTarget::Memory(address)
+            },
+            "reg" => {
+                let location = location.trim_start_matches(&['V', 'v'][..]);
+                let reg_id = Byte::from_str_radix(location, 16).map_err(|_| invalid())?;
+                if reg_id > 0x0F {
+                    return Err(invalid());
+                }
+                CheatTarget::Register(reg_id)
+            },
+            _ => return Err(invalid()),
+        };
+
+        let value = parts.next().ok_or_else(invalid)?;
+        let value = Byte::from_str_radix(value, 16).map_err(|_| invalid())?;
+
+        // the description is the rest of the line, spaces within it kept
+        let description = match parts.next() {
+            Some(word) => line[word.as_ptr() as usize - line.as_ptr() as usize..].to_string(),
+            None => String::new(),
+        };
+
+        Ok(Cheat {
+            target,
+            value,
+            enabled,
+            description,
+        })
+    }
+
+    fn apply(&self, memory: &mut Memory, registers: &mut Registers) {
+        match self.target {
+            CheatTarget::Memory(address) => memory.write(address as usize, self.value),
+            CheatTarget::Register(reg_id) => registers.vs[reg_id as usize] = self.value,
+        }
+    }
+}
+
+impl fmt::Display for Cheat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let enabled = if self.enabled { "on" } else { "off" };
+        match self.target {
+            CheatTarget::Memory(address) => write!(f, "{} mem {:04X} {:02X}", enabled, address, self.value)?,
+            CheatTarget::Register(reg_id) => write!(f, "{} reg V{:X} {:02X}", enabled, reg_id, self.value)?,
+        }
+        if !self.description.is_empty() {
+            write!(f, " {}", self.description)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CheatError {
+    InvalidLine(String),
+}
+
+impl fmt::Display for CheatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CheatError::InvalidLine(ref line) => write!(f, "Invalid cheat line: {}", line),
+        }
+    }
+}
+
+impl ::std::error::Error for CheatError {}
+
+/// CheatList
+///
+/// The cheats of a single ROM, stored as a text file with one cheat per line.
+/// Empty lines and lines starting with '#' are ignored
+#[derive(Clone, Debug, Default)]
+pub struct CheatList {
+    pub cheats: Vec<Cheat>,
+}
+
+impl CheatList {
+    pub fn new() -> CheatList {
+        CheatList {
+            cheats: Vec::new(),
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<CheatList, CheatError> {
+        let mut cheats = Vec::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            cheats.push(Cheat::parse(line)?);
+        }
+
+        Ok(CheatList {
+            cheats
+        })
+    }
+
+    pub fn add(&mut self, cheat: Cheat) {
+        self.cheats.push(cheat);
+    }
+
+    /// Flip a cheat on or off, returns the new state if the cheat exists
+    pub fn toggle(&mut self, index: usize) -> Option<bool> {
+        self.cheats.get_mut(index).map(|cheat| {
+            cheat.enabled = !cheat.enabled;
+            cheat.enabled
+        })
+    }
+
+    /// Write every enabled cheat to its target, should be called once per frame
+    pub fn apply(&self, memory: &mut Memory, registers: &mut Registers) {
+        for cheat in self.cheats.iter().filter(|cheat| cheat.enabled) {
+            cheat.apply(memory, registers);
+        }
+    }
+}
+
+impl fmt::Display for CheatList {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for cheat in self.cheats.iter() {
+            writeln!(f, "{}", cheat)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_with_extra_spaces() {
+        let cheat = Cheat::parse("on  mem\t0F2A   03  infinite  lives ").unwrap();
+        assert_eq!(cheat, Cheat::freeze(0x0F2A, 0x03, "infinite  lives"));
+
+        let cheat = Cheat::parse("off reg VA FF").unwrap();
+        assert_eq!(cheat, Cheat { enabled: false, ..Cheat::patch(0x0A, 0xFF, "") });
+
+        assert!(Cheat::parse("on mem 0F2A").is_err());
+    }
+
+    #[test]
+    fn search_narrows_down() {
+        let mut memory = Memory::new();
+        let mut search = RamSearch::new(&memory);
+        memory.write(0x300, 5);
+        memory.write(0x301, 5);
+        search.filter(&memory, SearchFilter::Changed);
+        assert_eq!(search.candidates(), &[0x300, 0x301]);
+
+        memory.write(0x300, 4);
+        search.filter(&memory, SearchFilter::Decreased);
+        assert_eq!(search.candidates(), &[0x300]);
+    }
+}
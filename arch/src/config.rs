@@ -0,0 +1,351 @@
+use std::fmt;
+use std::str::FromStr;
+
+use Address;
+use instructions::Instruction;
+use display::{HIRES_RESOLUTION_HEIGHT, RESOLUTION_HEIGHT, RESOLUTION_WIDTH};
+use memory::{HIRES_ENTRY_POINT, PROGRAM_OFFSET};
+use stack::{StackPolicy, STACK_DEPTH, VIP_STACK_DEPTH};
+
+/// The machines CHIP-8 programs were written for
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Platform {
+    /// The original COSMAC VIP interpreter
+    Chip8,
+
+    /// The VIP interpreter patched for a 64x64 display by a preamble in front of the program
+    HiresChip8,
+
+    /// SUPER-CHIP 1.1 on the HP48
+    SuperChip,
+
+    /// Octo's XO-CHIP extension
+    XoChip,
+}
+
+impl Platform {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Platform::Chip8 => "chip8",
+            Platform::HiresChip8 => "hires",
+            Platform::SuperChip => "schip",
+            Platform::XoChip => "xochip",
+        }
+    }
+
+    /// How many return addresses the interpreter has room for
+    pub fn stack_depth(&self) -> usize {
+        match *self {
+            Platform::Chip8 | Platform::HiresChip8 => VIP_STACK_DEPTH,
+            Platform::SuperChip | Platform::XoChip => STACK_DEPTH,
+        }
+    }
+
+    /// The resolution of the display when a program starts, as width and height
+    pub fn resolution(&self) -> (usize, usize) {
+        match *self {
+            Platform::HiresChip8 => (RESOLUTION_WIDTH, HIRES_RESOLUTION_HEIGHT),
+            _ => (RESOLUTION_WIDTH, RESOLUTION_HEIGHT),
+        }
+    }
+
+    /// Whether the interpreter runs an instruction, 0230 only clears the display with the hi-res preamble in memory
+    pub fn supports(&self, instruction: &Instruction) -> bool {
+        match *instruction {
+            Instruction::HCLS => *self == Platform::HiresChip8,
+            _ => true,
+        }
+    }
+
+    /// Where execution starts, past the preamble for hi-res programs
+    pub fn entry_point(&self) -> Address {
+        match *self {
+            Platform::HiresChip8 => HIRES_ENTRY_POINT as Address,
+            _ => PROGRAM_OFFSET as Address,
+        }
+    }
+}
+
+impl fmt::Display for Platform {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+impl FromStr for Platform {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Platform, String> {
+        match s.trim().to_lowercase().as_str() {
+            "chip8" | "chip-8" => Ok(Platform::Chip8),
+            "hires" | "hires-chip8" | "hires-chip-8" => Ok(Platform::HiresChip8),
+            "schip" | "superchip" | "super-chip" => Ok(Platform::SuperChip),
+            "xochip" | "xo-chip" => Ok(Platform::XoChip),
+            _ => Err(format!("Unknown platform: {}", s)),
+        }
+    }
+}
+
+/// Behaviours that differ between interpreters
+///
+/// Programs are usually written against a specific interpreter and break when these don't match
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Quirk {
+    /// ORXY, ANDXY and XORXY reset VF to 0
+    VfReset,
+
+    /// STR and LDR increment I past the last accessed location
+    LoadStoreIncrementsI,
+
+    /// SHR and SHL shift Vx in place instead of shifting Vy into Vx
+    ShiftIgnoresVy,
+
+    /// JMI jumps to address + Vx, x being the high nibble of the address, instead of address + V0
+    JumpUsesVx,
+
+    /// DRW cuts sprites off at the edges of the display instead of wrapping them around
+    Clipping,
+
+    /// DRW sets VF to the number of sprite rows that collided or were clipped off the bottom, instead of 0 or 1
+    CollisionCountsRows,
+
+    /// DRW waits for the next vertical blank before the program goes on, at most one sprite is drawn a frame
+    DisplayWait,
+}
+
+impl Quirk {
+    pub const ALL: [Quirk; 7] = [
+        Quirk::VfReset,
+        Quirk::LoadStoreIncrementsI,
+        Quirk::ShiftIgnoresVy,
+        Quirk::JumpUsesVx,
+        Quirk::Clipping,
+        Quirk::CollisionCountsRows,
+        Quirk::DisplayWait,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Quirk::VfReset => "vf_reset",
+            Quirk::LoadStoreIncrementsI => "load_store",
+            Quirk::ShiftIgnoresVy => "shift",
+            Quirk::JumpUsesVx => "jump",
+            Quirk::Clipping => "clip",
+            Quirk::CollisionCountsRows => "vf_rows",
+            Quirk::DisplayWait => "vblank",
+        }
+    }
+}
+
+impl fmt::Display for Quirk {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+impl FromStr for Quirk {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Quirk, String> {
+        let s = s.trim();
+        Quirk::ALL.iter()
+            .find(|quirk| quirk.name() == s)
+            .cloned()
+            .ok_or_else(|| format!("Unknown quirk: {}", s))
+    }
+}
+
+/// The quirks an interpreter has enabled
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quirks {
+    pub vf_reset: bool,
+    pub load_store_increments_i: bool,
+    pub shift_ignores_vy: bool,
+    pub jump_uses_vx: bool,
+    pub clipping: bool,
+    pub collision_counts_rows: bool,
+    pub display_wait: bool,
+}
+
+impl Quirks {
+    pub fn for_platform(platform: Platform) -> Quirks {
+        match platform {
+            Platform::Chip8 | Platform::HiresChip8 => Quirks {
+                vf_reset: true,
+                load_store_increments_i: true,
+                shift_ignores_vy: false,
+                jump_uses_vx: false,
+                clipping: true,
+                collision_counts_rows: false,
+                // the VIP waits, but few programs need it and it slows every one down to a sprite a frame,
+                // so the ROM database or cartridge turns it on
+                display_wait: false,
+            },
+            Platform::SuperChip => Quirks {
+                vf_reset: false,
+                load_store_increments_i: false,
+                shift_ignores_vy: true,
+                jump_uses_vx: true,
+                clipping: true,
+                collision_counts_rows: true,
+                display_wait: false,
+            },
+            Platform::XoChip => Quirks {
+                vf_reset: false,
+                load_store_increments_i: true,
+                shift_ignores_vy: false,
+                jump_uses_vx: false,
+                clipping: false,
+                collision_counts_rows: false,
+                display_wait: false,
+            },
+        }
+    }
+
+    pub fn get(&self, quirk: Quirk) -> bool {
+        match quirk {
+            Quirk::VfReset => self.vf_reset,
+            Quirk::LoadStoreIncrementsI => self.load_store_increments_i,
+            Quirk::ShiftIgnoresVy => self.shift_ignores_vy,
+            Quirk::JumpUsesVx => self.jump_uses_vx,
+            Quirk::Clipping => self.clipping,
+            Quirk::CollisionCountsRows => self.collision_counts_rows,
+            Quirk::DisplayWait => self.display_wait,
+        }
+    }
+
+    pub fn set(&mut self, quirk: Quirk, enabled: bool) {
+        match quirk {
+            Quirk::VfReset => self.vf_reset = enabled,
+            Quirk::LoadStoreIncrementsI => self.load_store_increments_i = enabled,
+            Quirk::ShiftIgnoresVy => self.shift_ignores_vy = enabled,
+            Quirk::JumpUsesVx => self.jump_uses_vx = enabled,
+            Quirk::Clipping => self.clipping = enabled,
+            Quirk::CollisionCountsRows => self.collision_counts_rows = enabled,
+            Quirk::DisplayWait => self.display_wait = enabled,
+        }
+    }
+}
+
+/// How the emulator always ran programs, kept for those nothing is known about:
+/// Vx shifted in place, I incremented by STR and LDR, and sprites wrapped around without waiting
+impl Default for Quirks {
+    fn default() -> Quirks {
+        Quirks {
+            vf_reset: false,
+            load_store_increments_i: true,
+            shift_ignores_vy: true,
+            jump_uses_vx: false,
+            clipping: false,
+            collision_counts_rows: false,
+            display_wait: false,
+        }
+    }
+}
+
+/// Colors used to draw the display, as RGB
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Palette {
+    pub background: [u8; 3],
+    pub foreground: [u8; 3],
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette {
+            background: [0x00, 0xFF, 0x00],
+            foreground: [0xFF, 0x00, 0x00],
+        }
+    }
+}
+
+/// How fast programs run
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum Timing {
+    /// clock_speed instructions per second, however long each took on real hardware
+    #[default]
+    Fixed,
+
+    /// the machine cycles per frame of the COSMAC VIP, each instruction costing what it did there
+    Vip,
+}
+
+impl Timing {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Timing::Fixed => "fixed",
+            Timing::Vip => "vip",
+        }
+    }
+}
+
+impl fmt::Display for Timing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+impl FromStr for Timing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Timing, String> {
+        match s.trim().to_lowercase().as_str() {
+            "fixed" => Ok(Timing::Fixed),
+            "vip" => Ok(Timing::Vip),
+            _ => Err(format!("Unknown timing: {}", s)),
+        }
+    }
+}
+
+/// Default number of instructions executed per second
+pub const DEFAULT_CLOCK_SPEED: u32 = 500;
+
+/// Config
+///
+/// Everything about the emulated machine that can change from one program to another
+#[derive(Clone, Debug, PartialEq)]
+pub struct Config {
+    pub platform: Platform,
+    pub quirks: Quirks,
+
+    /// instructions executed per second, with fixed timing
+    pub clock_speed: u32,
+    pub timing: Timing,
+
+    pub palette: Palette,
+
+    /// return addresses the call stack has room for
+    pub stack_depth: usize,
+    pub stack_policy: StackPolicy,
+}
+
+impl Config {
+    pub fn for_platform(platform: Platform) -> Config {
+        Config {
+            platform,
+            quirks: Quirks::for_platform(platform),
+            clock_speed: DEFAULT_CLOCK_SPEED,
+            timing: Timing::default(),
+            palette: Palette::default(),
+            stack_depth: platform.stack_depth(),
+            stack_policy: StackPolicy::default(),
+        }
+    }
+
+    /// Switch to another platform, along with its quirks and stack depth
+    pub fn set_platform(&mut self, platform: Platform) {
+        self.platform = platform;
+        self.quirks = Quirks::for_platform(platform);
+        self.stack_depth = platform.stack_depth();
+    }
+}
+
+/// A CHIP-8 with the default quirks rather than those of the VIP, set_platform switches to them
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            quirks: Quirks::default(),
+            ..Config::for_platform(Platform::Chip8)
+        }
+    }
+}
@@ -0,0 +1,406 @@
+use std::fmt;
+use std::io::{Cursor, Read};
+
+use flate2::read::GzDecoder;
+use gif;
+use serde_json::{self, Value};
+use zip::ZipArchive;
+
+use {Byte};
+use config::{Config, Palette, Platform, Quirk};
+use memory::{MEMORY_SIZE, PROGRAM_OFFSET};
+
+/// Containers can hold other containers, e.g a zipped cartridge, but not endlessly
+const MAX_NESTING: usize = 4;
+
+/// The formats a ROM can be stored in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// plain program bytes, the usual .ch8 file
+    Raw,
+
+    /// an Octo cartridge, a GIF with the program and its options hidden in the pixels
+    OctoCartridge,
+
+    /// Intel HEX records
+    IntelHex,
+
+    Gzip,
+    Zip,
+}
+
+/// Settings a container asks the machine to run with
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RomOptions {
+    pub platform: Option<Platform>,
+    pub quirks: Vec<(Quirk, bool)>,
+    pub clock_speed: Option<u32>,
+    pub palette: Option<Palette>,
+}
+
+impl RomOptions {
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(platform) = self.platform {
+            config.set_platform(platform);
+        }
+
+        for &(quirk, enabled) in self.quirks.iter() {
+            config.quirks.set(quirk, enabled);
+        }
+
+        if let Some(clock_speed) = self.clock_speed {
+            config.clock_speed = clock_speed;
+        }
+
+        if let Some(palette) = self.palette {
+            config.palette = palette;
+        }
+    }
+}
+
+/// A ROM taken out of its container
+#[derive(Clone, Debug, PartialEq)]
+pub struct Rom {
+    /// the outermost format the ROM was stored in
+    pub format: Format,
+
+    /// program bytes, to be loaded at the program offset
+    pub data: Vec<Byte>,
+
+    pub options: RomOptions,
+}
+
+#[derive(Debug)]
+pub enum ContainerError {
+    Gif(gif::DecodingError),
+    Gzip(::std::io::Error),
+    Zip(::zip::result::ZipError),
+    EmptyZip,
+    InvalidHex(usize, String),
+    InvalidCartridge(String),
+
+    /// Octo cartridges usually hold Octo source code, which has to be assembled by Octo
+    UnsupportedOctoSource,
+
+    TooDeeplyNested,
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ContainerError::Gif(ref e) => write!(f, "Invalid GIF: {}", e),
+            ContainerError::Gzip(ref e) => write!(f, "Invalid gzip stream: {}", e),
+            ContainerError::Zip(ref e) => write!(f, "Invalid zip archive: {}", e),
+            ContainerError::EmptyZip => write!(f, "Zip archive contains no files"),
+            ContainerError::InvalidHex(line, ref reason) => write!(f, "Invalid Intel HEX line {}: {}", line, reason),
+            ContainerError::InvalidCartridge(ref reason) => write!(f, "Invalid Octo cartridge: {}", reason),
+            ContainerError::UnsupportedOctoSource => write!(f, "Octo cartridge holds source code, assemble it with Octo first"),
+            ContainerError::TooDeeplyNested => write!(f, "Too many nested containers"),
+        }
+    }
+}
+
+impl ::std::error::Error for ContainerError {}
+
+/// Detect the format of a file from its content
+pub fn detect_format(data: &[Byte]) -> Format {
+    if data.starts_with(b"GIF87a") || data.starts_with(b"GIF89a") {
+        Format::OctoCartridge
+    } else if data.starts_with(&[0x1F, 0x8B]) {
+        Format::Gzip
+    } else if data.starts_with(b"PK\x03\x04") {
+        Format::Zip
+    } else if is_intel_hex(data) {
+        Format::IntelHex
+    } else {
+        Format::Raw
+    }
+}
+
+/// Take a ROM out of whatever container it is stored in
+pub fn decode(data: &[Byte]) -> Result<Rom, ContainerError> {
+    decode_nested(data, 0)
+}
+
+fn decode_nested(data: &[Byte], depth: usize) -> Result<Rom, ContainerError> {
+    if depth > MAX_NESTING {
+        return Err(ContainerError::TooDeeplyNested);
+    }
+
+    let raw = || Ok(Rom {
+        format: Format::Raw,
+        data: data.to_vec(),
+        options: RomOptions::default(),
+    });
+
+    let format = detect_format(data);
+    let inner = match format {
+        Format::Raw => return raw(),
+        Format::OctoCartridge => return decode_cartridge(data),
+        Format::IntelHex => return decode_intel_hex(data),
+        // 1F 8B is also a jump to F8B, a program starting with it is no gzip stream
+        Format::Gzip => match decompress_gzip(data) {
+            Ok(inner) => inner,
+            Err(_) => return raw(),
+        },
+        Format::Zip => decompress_zip(data)?,
+    };
+
+    let rom = decode_nested(&inner, depth + 1)?;
+    Ok(Rom {
+        format,
+        ..rom
+    })
+}
+
+fn decompress_gzip(data: &[Byte]) -> Result<Vec<Byte>, ContainerError> {
+    let mut inner = Vec::new();
+    GzDecoder::new(data).read_to_end(&mut inner).map_err(ContainerError::Gzip)?;
+    Ok(inner)
+}
+
+/// The ROM of a zip archive is its first file
+fn decompress_zip(data: &[Byte]) -> Result<Vec<Byte>, ContainerError> {
+    let mut archive = ZipArchive::new(Cursor::new(data)).map_err(ContainerError::Zip)?;
+    for index in 0..archive.len() {
+        let mut file = archive.by_index(index).map_err(ContainerError::Zip)?;
+        if file.is_dir() {
+            continue;
+        }
+
+        let mut inner = Vec::new();
+        file.read_to_end(&mut inner).map_err(|e| ContainerError::Zip(e.into()))?;
+        return Ok(inner);
+    }
+
+    Err(ContainerError::EmptyZip)
+}
+
+fn is_intel_hex(data: &[Byte]) -> bool {
+    let text = match ::std::str::from_utf8(data) {
+        Ok(text) => text,
+        Err(_) => return false,
+    };
+
+    let mut lines = text.lines().map(str::trim).filter(|line| !line.is_empty()).peekable();
+    lines.peek().is_some() && lines.all(|line| {
+        line.starts_with(':') && line.len() >= 11 && line[1..].chars().all(|c| c.is_ascii_hexdigit())
+    })
+}
+
+/// Intel HEX records are placed by address. Files addressing the program space are
+/// taken as absolute, anything else as relative to the start of the program.
+fn decode_intel_hex(data: &[Byte]) -> Result<Rom, ContainerError> {
+    let text = String::from_utf8_lossy(data);
+    let mut records: Vec<(usize, Vec<Byte>)> = Vec::new();
+    let mut base = 0usize;
+
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        let invalid = |reason: &str| ContainerError::InvalidHex(line_number, reason.to_string());
+
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let bytes = (1..line.len())
+            .step_by(2)
+            .map(|i| line.get(i..i + 2).and_then(|pair| Byte::from_str_radix(pair, 16).ok()))
+            .collect::<Option<Vec<Byte>>>()
+            .ok_or_else(|| invalid("not hex"))?;
+
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(invalid("bad record length"));
+        }
+        if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+            return Err(invalid("bad checksum"));
+        }
+
+        let address = ((bytes[1] as usize) << 8) | bytes[2] as usize;
+        let payload = &bytes[4..bytes.len() - 1];
+        match bytes[3] {
+            0x00 if base + address + payload.len() > MEMORY_SIZE => return Err(invalid("record past the end of memory")),
+            0x00 => records.push((base + address, payload.to_vec())),
+            0x01 => break,
+            0x02 if payload.len() == 2 => base = (((payload[0] as usize) << 8) | payload[1] as usize) << 4,
+            0x04 if payload.len() == 2 => base = (((payload[0] as usize) << 8) | payload[1] as usize) << 16,
+            // start addresses mean nothing to a CHIP-8
+            0x03 | 0x05 => {},
+            _ => return Err(invalid("unsupported record")),
+        }
+    }
+
+    let lowest = records.iter().map(|&(address, _)| address).min().unwrap_or(0);
+    let origin = if lowest >= PROGRAM_OFFSET { PROGRAM_OFFSET } else { 0 };
+
+    let mut rom = Vec::new();
+    for (address, payload) in records {
+        let start = address - origin;
+        if rom.len() < start + payload.len() {
+            rom.resize(start + payload.len(), 0);
+        }
+        rom[start..start + payload.len()].copy_from_slice(&payload);
+    }
+
+    Ok(Rom {
+        format: Format::IntelHex,
+        data: rom,
+        options: RomOptions::default(),
+    })
+}
+
+/// Octo cartridges keep a JSON document in the low nibbles of the GIF's pixels,
+/// two pixels per byte, prefixed by its length as a 32 bit big endian number.
+/// The document holds the program and the options Octo ran it with.
+fn decode_cartridge(data: &[Byte]) -> Result<Rom, ContainerError> {
+    let mut decoder = gif::DecodeOptions::new().read_info(data).map_err(ContainerError::Gif)?;
+
+    let mut payload = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().map_err(ContainerError::Gif)? {
+        for pair in frame.buffer.chunks(2) {
+            let high = pair[0] & 0x0F;
+            let low = pair.get(1).map_or(0, |pixel| pixel & 0x0F);
+            payload.push((high << 4) | low);
+        }
+    }
+
+    if payload.len() < 4 {
+        return Err(ContainerError::InvalidCartridge("missing payload".to_string()));
+    }
+    let length = payload[..4].iter().fold(0usize, |length, byte| (length << 8) | *byte as usize);
+    let document = payload.get(4..4 + length)
+        .ok_or_else(|| ContainerError::InvalidCartridge("truncated payload".to_string()))?;
+
+    let document: Value = serde_json::from_slice(document)
+        .map_err(|e| ContainerError::InvalidCartridge(e.to_string()))?;
+
+    let program = match document.get("program") {
+        Some(Value::Array(bytes)) => bytes.iter()
+            .map(|byte| byte.as_u64().filter(|byte| *byte <= 0xFF).map(|byte| byte as Byte))
+            .collect::<Option<Vec<Byte>>>()
+            .ok_or_else(|| ContainerError::InvalidCartridge("program is not a byte array".to_string()))?,
+        Some(Value::String(source)) => assemble_raw_bytes(source)?,
+        _ => return Err(ContainerError::InvalidCartridge("missing program".to_string())),
+    };
+
+    let options = match document.get("options") {
+        Some(options) => octo_options(options),
+        None => RomOptions::default(),
+    };
+
+    Ok(Rom {
+        format: Format::OctoCartridge,
+        data: program,
+        options,
+    })
+}
+
+/// Octo source made only of numeric byte literals, which is what Octo exports for
+/// binaries, can be turned into a program without a full assembler
+fn assemble_raw_bytes(source: &str) -> Result<Vec<Byte>, ContainerError> {
+    source.lines()
+        .map(|line| line.split('#').next().unwrap_or(""))
+        .flat_map(str::split_whitespace)
+        .map(|token| {
+            let value = if token.starts_with("0x") || token.starts_with("0X") {
+                u16::from_str_radix(&token[2..], 16).ok()
+            } else if token.starts_with("0b") || token.starts_with("0B") {
+                u16::from_str_radix(&token[2..], 2).ok()
+            } else {
+                token.parse::<u16>().ok()
+            };
+            value.filter(|value| *value <= 0xFF).map(|value| value as Byte)
+        })
+        .collect::<Option<Vec<Byte>>>()
+        .ok_or(ContainerError::UnsupportedOctoSource)
+}
+
+fn octo_options(options: &Value) -> RomOptions {
+    let flag = |name: &str| options.get(name).and_then(Value::as_bool);
+
+    let mut quirks = Vec::new();
+    if let Some(enabled) = flag("shiftQuirks") {
+        quirks.push((Quirk::ShiftIgnoresVy, enabled));
+    }
+    // Octo's load/store quirk is leaving I untouched
+    if let Some(enabled) = flag("loadStoreQuirks") {
+        quirks.push((Quirk::LoadStoreIncrementsI, !enabled));
+    }
+    if let Some(enabled) = flag("jumpQuirks") {
+        quirks.push((Quirk::JumpUsesVx, enabled));
+    }
+    if let Some(enabled) = flag("logicQuirks") {
+        quirks.push((Quirk::VfReset, enabled));
+    }
+    if let Some(enabled) = flag("clipQuirks") {
+        quirks.push((Quirk::Clipping, enabled));
+    }
+    if let Some(enabled) = flag("vBlankQuirks") {
+        quirks.push((Quirk::DisplayWait, enabled));
+    }
+
+    // Octo's tickrate is in instructions per frame
+    let clock_speed = options.get("tickrate")
+        .and_then(Value::as_u64)
+        .map(|tickrate| tickrate as u32 * 60);
+
+    let platform = match options.get("maxSize").and_then(Value::as_u64) {
+        Some(3216) => Some(Platform::Chip8),
+        Some(3583) => Some(Platform::SuperChip),
+        Some(65024) => Some(Platform::XoChip),
+        _ => None,
+    };
+
+    let color = |name: &str| options.get(name).and_then(Value::as_str).and_then(parse_color);
+    let palette = match (color("backgroundColor"), color("fillColor")) {
+        (Some(background), Some(foreground)) => Some(Palette {
+            background,
+            foreground,
+        }),
+        _ => None,
+    };
+
+    RomOptions {
+        platform,
+        quirks,
+        clock_speed,
+        palette,
+    }
+}
+
+/// Parse an html style color, #RRGGBB
+fn parse_color(color: &str) -> Option<[u8; 3]> {
+    let color = color.trim_start_matches('#');
+    if color.len() != 6 || !color.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&color[i..i + 2], 16).ok();
+    Some([channel(0)?, channel(2)?, channel(4)?])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn intel_hex_past_memory() {
+        let hex = b":020000040001F9\n:0200000060019D\n:00000001FF\n";
+        assert_eq!(
+            decode(hex).err().map(|e| e.to_string()),
+            Some("Invalid Intel HEX line 2: record past the end of memory".to_string())
+        );
+
+        let hex = b":0202000060019B\n:00000001FF\n";
+        assert_eq!(decode(hex).unwrap().data, vec![0x60, 0x01]);
+    }
+
+    #[test]
+    fn jump_looking_like_gzip() {
+        let program = [0x1F, 0x8B, 0x00, 0xE0];
+        let rom = decode(&program).unwrap();
+        assert_eq!(rom.format, Format::Raw);
+        assert_eq!(rom.data, program.to_vec());
+    }
+}
@@ -0,0 +1,344 @@
+use std::fmt;
+
+use {Address, Byte};
+use analysis::Image;
+use effects::{AccessKind, Effects};
+use memory::MEMORY_SIZE;
+use png;
+
+/// Bytes per row of the heatmap, so all of memory makes a square
+const HEATMAP_WIDTH: usize = 64;
+
+/// Pixels per byte on each side of the heatmap
+const HEATMAP_SCALE: usize = 4;
+
+/// Data bytes with the same usage shown on one line of the disassembly
+const DATA_PER_LINE: usize = 8;
+
+/// How often a single address was used, per kind of use
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Usage {
+    /// fetched as part of an instruction
+    pub executed: u64,
+
+    /// read as sprite data by DRW
+    pub sprite: u64,
+
+    /// read by LDR
+    pub read: u64,
+
+    /// written by STR or BCD
+    pub written: u64,
+}
+
+impl Usage {
+    pub fn is_touched(&self) -> bool {
+        *self != Usage::default()
+    }
+
+    pub fn merge(&mut self, other: &Usage) {
+        self.executed += other.executed;
+        self.sprite += other.sprite;
+        self.read += other.read;
+        self.written += other.written;
+    }
+
+    /// XSRW, with a dash for each kind of use that never happened
+    pub fn flags(&self) -> String {
+        [(self.executed, 'X'), (self.sprite, 'S'), (self.read, 'R'), (self.written, 'W')].iter()
+            .map(|&(count, flag)| if count > 0 { flag } else { '-' })
+            .collect()
+    }
+
+    fn same_kinds(&self, other: &Usage) -> bool {
+        self.flags() == other.flags()
+    }
+}
+
+/// Coverage
+///
+/// What every address of memory was used for, accumulated over one or more runs.
+/// Stored as text, one touched address per line: `ADDR executed sprite read written`,
+/// the address in hex and the counts in decimal, e.g `2A0 12 0 0 0`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Coverage {
+    usage: Vec<Usage>,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Coverage {
+            usage: vec![Usage::default(); MEMORY_SIZE],
+        }
+    }
+
+    /// Record an instruction executed at address, with the effects it had
+    pub fn record(&mut self, address: Address, effects: &Effects) {
+        self.usage[address as usize % MEMORY_SIZE].executed += 1;
+        self.usage[(address as usize + 1) % MEMORY_SIZE].executed += 1;
+
+        if let Some(access) = effects.memory {
+            for location in access.addresses() {
+                let usage = &mut self.usage[location % MEMORY_SIZE];
+                match access.kind {
+                    AccessKind::Sprite => usage.sprite += 1,
+                    AccessKind::Load => usage.read += 1,
+                    AccessKind::Store | AccessKind::Bcd => usage.written += 1,
+                }
+            }
+        }
+    }
+
+    /// Add the counts of another run
+    pub fn merge(&mut self, other: &Coverage) {
+        for (usage, other) in self.usage.iter_mut().zip(other.usage.iter()) {
+            usage.merge(other);
+        }
+    }
+
+    pub fn usage_at(&self, address: Address) -> Usage {
+        self.usage[address as usize % MEMORY_SIZE]
+    }
+
+    pub fn parse(text: &str) -> Result<Coverage, CoverageError> {
+        let mut coverage = Coverage::new();
+        for line in text.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let invalid = || CoverageError::InvalidLine(line.to_string());
+            let parts: Vec<&str> = line.split_whitespace().collect();
+            let (address, counts) = match parts.split_first() {
+                Some((address, counts)) if counts.len() == 4 => (address, counts),
+                _ => return Err(invalid()),
+            };
+            let address = usize::from_str_radix(address, 16).map_err(|_| invalid())?;
+            if address >= MEMORY_SIZE {
+                return Err(invalid());
+            }
+            let mut counts = counts.iter().map(|count| count.parse::<u64>().map_err(|_| invalid()));
+
+            let usage = Usage {
+                executed: counts.next().unwrap_or(Ok(0))?,
+                sprite: counts.next().unwrap_or(Ok(0))?,
+                read: counts.next().unwrap_or(Ok(0))?,
+                written: counts.next().unwrap_or(Ok(0))?,
+            };
+            coverage.usage[address].merge(&usage);
+        }
+        Ok(coverage)
+    }
+
+    /// The ROM disassembled with what each line was used for, including code that never ran.
+    /// Touched addresses outside of the ROM are listed after it.
+    pub fn annotated_disassembly(&self, image: &Image) -> String {
+        let reachable = image.reachable();
+        let mut lines = Vec::new();
+
+        let in_image: Vec<Usage> = (image.base as usize..image.end()).map(|address| self.usage[address % MEMORY_SIZE]).collect();
+        let count = |kind: fn(&Usage) -> u64| in_image.iter().filter(|usage| kind(usage) > 0).count();
+        lines.push(format!(
+            "; {} bytes: {} executed, {} sprite, {} read, {} written, {} untouched",
+            in_image.len(),
+            count(|usage| usage.executed),
+            count(|usage| usage.sprite),
+            count(|usage| usage.read),
+            count(|usage| usage.written),
+            in_image.iter().filter(|usage| !usage.is_touched()).count(),
+        ));
+
+        let mut address = image.base as usize;
+        while address < image.end() {
+            let usage = self.usage[address % MEMORY_SIZE];
+            let is_code = usage.executed > 0 || reachable.contains(&(address as Address));
+
+            match image.decode_at(address as Address).filter(|_| is_code) {
+                Some(decoded) => {
+                    let length = decoded.length().max(2);
+                    let mut combined = usage;
+                    for offset in 1..length {
+                        combined.merge(&Usage { executed: 0, ..self.usage[(address + offset) % MEMORY_SIZE] });
+                    }
+                    let bytes: Vec<String> = (address..address + length)
+                        .filter_map(|location| image.byte_at(location as Address))
+                        .map(|byte| format!("{:02X}", byte))
+                        .collect();
+                    lines.push(format!("{:03X} {} {:>8}  {:<9} {}", address, combined.flags(), usage.executed, bytes.concat(), decoded));
+                    address += length;
+                },
+                None => {
+                    let mut bytes = Vec::new();
+                    while address + bytes.len() < image.end() && bytes.len() < DATA_PER_LINE {
+                        let location = address + bytes.len();
+                        let next = self.usage[location % MEMORY_SIZE];
+                        if !bytes.is_empty() && (!next.same_kinds(&usage) || reachable.contains(&(location as Address))) {
+                            break;
+                        }
+                        bytes.push(image.byte_at(location as Address).unwrap_or(0));
+                    }
+                    let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+                    lines.push(format!("{:03X} {} {:>8}  DB {}", address, usage.flags(), "", hex.join(" ")));
+                    address += bytes.len();
+                },
+            }
+        }
+
+        let outside: Vec<usize> = (0..MEMORY_SIZE)
+            .filter(|&address| !image.contains(address as Address) && self.usage[address].is_touched())
+            .collect();
+        if !outside.is_empty() {
+            lines.push(String::from("\n; outside the ROM"));
+        }
+        let mut index = 0;
+        while index < outside.len() {
+            let start = outside[index];
+            let usage = self.usage[start];
+            let mut end = start;
+            while index + 1 < outside.len() && outside[index + 1] == end + 1 && self.usage[end + 1].same_kinds(&usage) {
+                index += 1;
+                end += 1;
+            }
+            lines.push(format!("{:03X}-{:03X} {}", start, end, usage.flags()));
+            index += 1;
+        }
+
+        let mut text = lines.join("\n");
+        text.push('\n');
+        text
+    }
+
+    /// All of memory as a PNG, one square per byte, 64 bytes per row.
+    /// Executed code is red, sprites green, bytes read blue and written yellow,
+    /// brighter the more often they were used. Untouched bytes are black.
+    pub fn heatmap(&self) -> Vec<Byte> {
+        let rows = MEMORY_SIZE / HEATMAP_WIDTH;
+        let width = HEATMAP_WIDTH * HEATMAP_SCALE;
+        let height = rows * HEATMAP_SCALE;
+
+        let most = self.usage.iter()
+            .map(|usage| usage.executed.max(usage.sprite).max(usage.read).max(usage.written))
+            .max()
+            .unwrap_or(0);
+        // anything used at least once is clearly visible, heavier use gets brighter on a log scale
+        let heat = |count: u64| -> f64 {
+            if count == 0 {
+                0.0
+            } else {
+                0.25 + 0.75 * ((count as f64).ln_1p() / (most as f64).ln_1p())
+            }
+        };
+
+        let mut rgb = vec![0; width * height * 3];
+        for (address, usage) in self.usage.iter().enumerate() {
+            let mut color = [0.0f64; 3];
+            let kinds = [
+                (usage.executed, [1.0, 0.2, 0.2]),
+                (usage.sprite, [0.2, 1.0, 0.2]),
+                (usage.read, [0.2, 0.4, 1.0]),
+                (usage.written, [1.0, 0.9, 0.1]),
+            ];
+            for &(count, tint) in kinds.iter() {
+                for channel in 0..3 {
+                    color[channel] += heat(count) * tint[channel];
+                }
+            }
+            let pixel: Vec<Byte> = color.iter().map(|channel| (channel.min(1.0) * 255.0) as Byte).collect();
+
+            let x = (address % HEATMAP_WIDTH) * HEATMAP_SCALE;
+            let y = (address / HEATMAP_WIDTH) * HEATMAP_SCALE;
+            for row in y..y + HEATMAP_SCALE {
+                for column in x..x + HEATMAP_SCALE {
+                    let offset = (row * width + column) * 3;
+                    rgb[offset..offset + 3].copy_from_slice(&pixel);
+                }
+            }
+        }
+
+        png::encode(width, height, &rgb)
+    }
+}
+
+impl Default for Coverage {
+    fn default() -> Coverage {
+        Coverage::new()
+    }
+}
+
+impl fmt::Display for Coverage {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "# address executed sprite read written")?;
+        for (address, usage) in self.usage.iter().enumerate().filter(|&(_, usage)| usage.is_touched()) {
+            writeln!(f, "{:03X} {} {} {} {}", address, usage.executed, usage.sprite, usage.read, usage.written)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum CoverageError {
+    InvalidLine(String),
+}
+
+impl fmt::Display for CoverageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CoverageError::InvalidLine(ref line) => write!(f, "Invalid coverage line: {}", line),
+        }
+    }
+}
+
+impl ::std::error::Error for CoverageError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::Cpu;
+
+    #[test]
+    fn access_kinds() {
+        let rom = [
+            0xA3, 0x00, // 200 MOVI 300
+            0xD0, 0x13, // 202 DRW V0, V1, 3
+            0xA3, 0x10, // 204 MOVI 310
+            0xF2, 0x55, // 206 STR V2
+            0xA3, 0x20, // 208 MOVI 320
+            0xF1, 0x65, // 20A LDR V1
+            0xA3, 0x30, // 20C MOVI 330
+            0xF0, 0x33, // 20E BCD V0
+            0x12, 0x10, // 210 JMP 210
+        ];
+        let mut cpu = Cpu::new(&rom).unwrap();
+        cpu.enable_coverage();
+        cpu.run(10).unwrap();
+        let coverage = cpu.get_coverage().unwrap();
+        let flags = |address: Address| coverage.usage_at(address).flags();
+
+        assert_eq!(coverage.usage_at(0x210), Usage { executed: 2, ..Usage::default() });
+        assert_eq!(flags(0x211), "X---");
+        assert_eq!((flags(0x300), flags(0x302), flags(0x303)), ("-S--".to_string(), "-S--".to_string(), "----".to_string()));
+        assert_eq!((flags(0x310), flags(0x312), flags(0x313)), ("---W".to_string(), "---W".to_string(), "----".to_string()));
+        assert_eq!((flags(0x320), flags(0x321), flags(0x322)), ("--R-".to_string(), "--R-".to_string(), "----".to_string()));
+        assert_eq!((flags(0x330), flags(0x332), flags(0x333)), ("---W".to_string(), "---W".to_string(), "----".to_string()));
+    }
+
+    #[test]
+    fn merge_files() {
+        let first = Coverage::parse("# address executed sprite read written\n200 1 0 0 0\n300 0 2 0 0\n").unwrap();
+        let mut second = Coverage::parse("200 3 0 0 0\n301 0 0 1 1\n301 0 0 1 0\n").unwrap();
+        second.merge(&first);
+
+        assert_eq!(second.usage_at(0x200).executed, 4);
+        assert_eq!(second.usage_at(0x301), Usage { read: 2, written: 1, ..Usage::default() });
+        assert_eq!(second.to_string(), "# address executed sprite read written\n200 4 0 0 0\n300 0 2 0 0\n301 0 0 2 1\n");
+        assert_eq!(Coverage::parse(&second.to_string()), Ok(second));
+    }
+
+    #[test]
+    fn invalid_lines() {
+        for &line in ["200 1 0 0", "1000 1 0 0 0", "2G0 1 0 0 0", "200 1 0 0 x"].iter() {
+            assert_eq!(Coverage::parse(line), Err(CoverageError::InvalidLine(line.to_string())));
+        }
+    }
+}
@@ -1,57 +1,433 @@
+use std::collections::VecDeque;
+use std::fmt;
+
 use {Address, Byte, Renderer};
-use memory::{Memory, PROGRAM_OFFSET};
+use memory::{Memory, MEMORY_SIZE};
+use loader::{Loader, LoadError, MemoryMap};
 use registers::{Registers};
 use instructions::{Instruction};
 use display::{Display};
 use keyboard::{Keyboard};
+use cheats::{CheatList};
+use config::{Config};
+use sanitizer::{Issue, Sanitizer, SanitizerOptions};
+use profiler::Profiler;
+use coverage::Coverage;
+use effects::Effects;
+use state::SaveState;
+use stack::CallStack;
+use timing;
+use timing::PROGRAM_CYCLES_PER_FRAME;
+use decode_cache::{DecodeCache, Decoded};
+#[cfg(feature = "jit")]
+use jit::Jit;
 
 use executions::execute_instruction;
 
-const STACK_SIZE: usize = 0x0F;
-pub type Stack = [Address; STACK_SIZE];
+/// How many executed instructions the cpu remembers, for crash reports
+pub const HISTORY_SIZE: usize = 256;
+
+/// Errors the program running on the cpu can run into
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fault {
+    /// the word at address isn't an instruction the cpu understands
+    InvalidInstruction { address: Address, code: u16 },
+
+    /// a subroutine call at address with the stack already full
+    StackOverflow { address: Address },
+
+    /// a return at address with no subroutine to return from
+    StackUnderflow { address: Address },
+
+    /// the sanitizer trapped on the instruction at address
+    UndefinedBehavior { address: Address, issue: Issue },
+}
+
+impl Fault {
+    /// Where the faulting instruction is
+    pub fn address(&self) -> Address {
+        match *self {
+            Fault::InvalidInstruction { address, .. } | Fault::StackOverflow { address } |
+            Fault::StackUnderflow { address } | Fault::UndefinedBehavior { address, .. } => address,
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Fault::InvalidInstruction { address, code } => write!(f, "Unable to parse instruction {:04X} at {:03X}", code, address),
+            Fault::StackOverflow { address } => write!(f, "Stack overflow at {:03X}", address),
+            Fault::StackUnderflow { address } => write!(f, "Stack underflow at {:03X}", address),
+            Fault::UndefinedBehavior { address, issue } => write!(f, "Undefined behavior at {:03X}: {}", address, issue),
+        }
+    }
+}
+
+impl ::std::error::Error for Fault {}
 
 pub struct Cpu {
     memory: Memory,
+    memory_map: MemoryMap,
     registers: Registers,
     display: Display,
     keyboard: Keyboard,
-    stack: Stack
+    stack: CallStack,
+    config: Config,
+    sanitizer: Option<Sanitizer>,
+    profiler: Option<Profiler>,
+    coverage: Option<Coverage>,
+    decode_cache: Option<DecodeCache>,
+    #[cfg(feature = "jit")]
+    jit: Option<Jit>,
+
+    /// machine cycles the instructions so far took on the VIP
+    cycles: u64,
+
+    /// the cycle count the current frame runs up to
+    frame_end: u64,
+
+    /// a DRW with the display wait quirk holds the program until the next vertical blank
+    waiting_for_vblank: bool,
+
+    /// the last instructions executed as address and code, oldest first
+    history: VecDeque<(Address, u16)>,
 }
 
 impl Cpu {
-    pub fn new(program_data: &Vec<Byte>) -> Cpu {
+    pub fn new(program_data: &[Byte]) -> Result<Cpu, LoadError> {
+        Cpu::with_config(program_data, Config::default())
+    }
+
+    /// Load a program for the platform of config, at its entry point and display resolution
+    pub fn with_config(program_data: &[Byte], config: Config) -> Result<Cpu, LoadError> {
+        let loader = Loader::new(program_data).entry_at(config.platform.entry_point());
+        Cpu::load(&loader, config)
+    }
+
+    /// Create a cpu from a loader, execution starts at the loader's entry point
+    pub fn load(loader: &Loader, config: Config) -> Result<Cpu, LoadError> {
+        let (memory, memory_map) = loader.load()?;
+
         let mut registers = Registers::new();
-        registers.program_counter = PROGRAM_OFFSET as u16;
+        registers.program_counter = loader.entry();
+        let (width, height) = config.platform.resolution();
 
-        Cpu {
-            memory: Memory::new(program_data),
+        Ok(Cpu {
+            memory,
+            memory_map,
             registers,
-            display: Display::new(),
+            display: Display::with_resolution(width, height),
             keyboard: Keyboard::new(),
-            stack: [0; STACK_SIZE],
-        }
+            stack: CallStack::new(config.stack_depth, config.stack_policy),
+            config,
+            sanitizer: None,
+            profiler: None,
+            coverage: None,
+            decode_cache: Some(DecodeCache::new()),
+            #[cfg(feature = "jit")]
+            jit: None,
+            cycles: 0,
+            frame_end: 0,
+            waiting_for_vblank: false,
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+        })
     }
 
+    /// Execute a single instruction, panics if the program faults.
+    /// Waiting for the vertical blank nothing is executed, interrupt or vblank let the program go on.
     pub fn tick(&mut self) {
-        let high = self.memory.data[self.registers.program_counter as usize];
-        let low = self.memory.data[self.registers.program_counter as usize + 1];
-        let instruction_code = ((high as u16) << 8) + low as u16;
-        self.execute(instruction_code);
+        if let Err(fault) = self.try_tick() {
+            panic!("{}", fault);
+        }
+    }
+
+    /// Execute count instructions, stopping at the first fault.
+    /// Waiting for the vertical blank, whatever is left of count is spent waiting.
+    pub fn run(&mut self, count: usize) -> Result<(), Fault> {
+        let mut executed = 0;
+        while executed < count && !self.waiting_for_vblank {
+            #[cfg(feature = "jit")]
+            {
+                if let Some(block) = self.run_block(count - executed) {
+                    executed += block;
+                    continue;
+                }
+            }
+            self.try_tick()?;
+            executed += 1;
+        }
+        Ok(())
+    }
+
+    /// Run a 60 Hz frame of the VIP: the display interrupt, then instructions until the machine
+    /// cycles left of the frame are spent. An instruction running past the end of a frame is paid for by the next.
+    /// Waiting for the vertical blank spends the rest of the frame.
+    pub fn run_frame(&mut self) -> Result<(), Fault> {
+        self.interrupt();
+        self.frame_end += PROGRAM_CYCLES_PER_FRAME as u64;
+        while self.cycles < self.frame_end {
+            if self.waiting_for_vblank {
+                self.cycles = self.frame_end;
+                break;
+            }
+            self.try_tick()?;
+        }
+        Ok(())
+    }
+
+    /// The 60 Hz interrupt, at the vertical blank, counting the timers down
+    pub fn interrupt(&mut self) {
+        self.vblank();
+        self.registers.delay_timer = self.registers.delay_timer.saturating_sub(1);
+        self.registers.sound_timer = self.registers.sound_timer.saturating_sub(1);
+    }
+
+    /// The display's vertical blank, a DRW waiting for it lets the program go on
+    pub fn vblank(&mut self) {
+        self.waiting_for_vblank = false;
     }
 
-    fn execute(&mut self, instruction_code: u16) {
-        match Instruction::parse_code(instruction_code) {
-            Some(instruction) => execute_instruction(
-                instruction,
-                &mut self.memory,
-                &mut self.registers,
-                &mut self.stack,
-                &mut self.display,
-                &mut self.keyboard,
-            ),
-            None => {
-                panic!("Unable to parse instruction: {:X?}", instruction_code);
-            },
+    /// Whether a DRW holds the program until the next vertical blank
+    pub fn is_waiting_for_vblank(&self) -> bool {
+        self.waiting_for_vblank
+    }
+
+    /// Machine cycles the instructions interpreted so far would have taken on the VIP
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Execute a single instruction, on a fault the cpu is left as it was before the instruction.
+    /// Waiting for the vertical blank, nothing is executed.
+    pub fn try_tick(&mut self) -> Result<(), Fault> {
+        if self.waiting_for_vblank {
+            return Ok(());
+        }
+
+        let address = self.registers.program_counter;
+        let Decoded { code: instruction_code, instruction } = self.decode(address);
+        let instruction = instruction.filter(|instruction| self.config.platform.supports(instruction));
+
+        if let Some(ref mut sanitizer) = self.sanitizer {
+            if let Some(issue) = sanitizer.check(address, instruction.as_ref(), &self.registers, &self.config) {
+                return Err(Fault::UndefinedBehavior { address, issue });
+            }
+        }
+
+        let instruction = instruction.ok_or(Fault::InvalidInstruction {
+            address,
+            code: instruction_code,
+        })?;
+        // what the instruction touches depends on the registers before it runs
+        let effects = self.coverage.as_ref().map(|_| Effects::of(&instruction, &self.registers, &self.config));
+        let before = self.registers;
+        execute_instruction(
+            instruction,
+            &mut self.memory,
+            &mut self.registers,
+            &mut self.stack,
+            &mut self.display,
+            &mut self.keyboard,
+            &self.config.quirks,
+        )?;
+        self.wait_for_vblank(&instruction);
+        self.cycles += timing::cycles(&instruction, &before, self.registers.program_counter) as u64;
+
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.record(address, &instruction);
+        }
+        if let (Some(coverage), Some(effects)) = (self.coverage.as_mut(), effects) {
+            coverage.record(address, &effects);
+        }
+
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back((address, instruction_code));
+        Ok(())
+    }
+
+    fn fetch(&self, address: Address) -> u16 {
+        let high = self.memory.read(address as usize);
+        let low = self.memory.read(address as usize + 1);
+        ((high as u16) << 8) + low as u16
+    }
+
+    /// Drop whatever was decoded or compiled from memory written since the last time
+    fn invalidate_written(&mut self) {
+        if let Some((low, high)) = self.memory.take_written() {
+            if let Some(ref mut cache) = self.decode_cache {
+                cache.invalidate(low, high);
+            }
+            #[cfg(feature = "jit")]
+            {
+                if let Some(ref mut jit) = self.jit {
+                    jit.invalidate(low, high);
+                }
+            }
+        }
+    }
+
+    /// Fetch and parse the instruction at address, from the decode cache when it has it
+    fn decode(&mut self, address: Address) -> Decoded {
+        self.invalidate_written();
+        if let Some(decoded) = self.decode_cache.as_ref().and_then(|cache| cache.get(address)) {
+            return decoded;
+        }
+
+        let code = self.fetch(address);
+        let decoded = Decoded {
+            code,
+            instruction: Instruction::parse_code(code),
+        };
+        if let Some(ref mut cache) = self.decode_cache {
+            cache.insert(address, decoded);
+        }
+        decoded
+    }
+
+    /// Keep decoded instructions around, on by default.
+    /// Without the cache every instruction is parsed as it is executed, to compare the two.
+    pub fn set_decode_cache(&mut self, enabled: bool) {
+        self.decode_cache = if enabled { Some(DecodeCache::new()) } else { None };
+    }
+
+    /// Run instructions natively from now on, wherever the jit can.
+    /// Tools watch single instructions, so while any is enabled everything is interpreted.
+    #[cfg(feature = "jit")]
+    pub fn enable_jit(&mut self) {
+        self.jit = Some(Jit::new(self.config.quirks));
+    }
+
+    /// Run the compiled block at the program counter, returning how many instructions it executed.
+    /// None when the next instruction is up to the interpreter.
+    ///
+    /// The machine cycles are counted as if interpreted. Compiled instructions only cost more
+    /// when a skip is taken, which ends the block, so only the last depends on where it left off.
+    #[cfg(feature = "jit")]
+    fn run_block(&mut self, budget: usize) -> Option<usize> {
+        if self.jit.is_none() || self.sanitizer.is_some() || self.profiler.is_some() || self.coverage.is_some() {
+            return None;
+        }
+        self.invalidate_written();
+
+        let jit = self.jit.as_mut()?;
+        let trace = jit.run(&self.memory, &mut self.registers, budget)?;
+        let end = self.registers.program_counter;
+        for (index, &(address, code)) in trace.iter().enumerate() {
+            if let Some(instruction) = Instruction::parse_code(code) {
+                // the cost of what blocks hold depends on nothing but the program counter
+                let mut before = self.registers;
+                before.program_counter = address;
+                let after = if index + 1 == trace.len() { end } else { address + 2 };
+                self.cycles += timing::cycles(&instruction, &before, after) as u64;
+            }
+
+            if self.history.len() == HISTORY_SIZE {
+                self.history.pop_front();
+            }
+            self.history.push_back((address, code));
+        }
+        Some(trace.len())
+    }
+
+    /// The instruction the next tick will execute, if it is one
+    pub fn next_instruction(&self) -> Option<Instruction> {
+        Instruction::parse_code(self.fetch(self.registers.program_counter))
+    }
+
+    /// Strict mode, reports reads of uninitialized state and undefined behavior from now on.
+    /// Whatever was written before counts as uninitialized, so enable it right after loading,
+    /// a save state loaded afterwards counts as initialized.
+    pub fn enable_sanitizer(&mut self, options: SanitizerOptions) {
+        let sanitizer = Sanitizer::new(options, &self.memory_map);
+        if options.randomize {
+            sanitizer.randomize(&mut self.memory, &mut self.registers);
+        }
+        self.sanitizer = Some(sanitizer);
+    }
+
+    pub fn get_sanitizer(&self) -> Option<&Sanitizer> {
+        self.sanitizer.as_ref()
+    }
+
+    /// Count every instruction executed from now on, starting from the current program counter
+    pub fn enable_profiler(&mut self) {
+        self.profiler = Some(Profiler::new(self.registers.program_counter, self.config.clock_speed));
+    }
+
+    pub fn get_profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn get_profiler_mut(&mut self) -> Option<&mut Profiler> {
+        self.profiler.as_mut()
+    }
+
+    /// Record what memory is used for from now on
+    pub fn enable_coverage(&mut self) {
+        self.coverage = Some(Coverage::new());
+    }
+
+    pub fn get_coverage(&self) -> Option<&Coverage> {
+        self.coverage.as_ref()
+    }
+
+    /// The last instructions executed as address and code, oldest first
+    pub fn history(&self) -> Vec<(Address, u16)> {
+        self.history.iter().cloned().collect()
+    }
+
+    pub fn save_state(&self) -> SaveState {
+        SaveState {
+            config: self.config.clone(),
+            registers: self.registers,
+            stack: self.stack.slots().to_vec(),
+            stack_pointer: self.stack.len(),
+            memory: self.memory.data().to_vec(),
+            display: self.display.clone(),
+            keys: (0..16).filter(|&key| self.keyboard.is_pressed(key)).fold(0, |keys, key| keys | 1 << key),
+            waiting_for_vblank: self.waiting_for_vblank,
+        }
+    }
+
+    /// Resume from a save state, the history of executed instructions starts over.
+    /// A sanitizer starts over too, with all of the state initialized.
+    pub fn load_state(&mut self, state: &SaveState) {
+        self.config = state.config.clone();
+        self.registers = state.registers;
+
+        self.stack = CallStack::new(state.config.stack_depth, state.config.stack_policy);
+        self.stack.restore(&state.stack, state.stack_pointer);
+
+        for (address, &value) in state.memory.iter().enumerate().take(MEMORY_SIZE) {
+            self.memory.write(address, value);
+        }
+
+        self.display = state.display.clone();
+        self.display.invalidate();
+
+        for key in 0..16 {
+            if state.keys & 1 << key != 0 {
+                self.keyboard.set_pressed(key);
+            } else {
+                self.keyboard.set_released(key);
+            }
+        }
+
+        self.waiting_for_vblank = state.waiting_for_vblank;
+        self.history.clear();
+        if let Some(options) = self.sanitizer.as_ref().map(Sanitizer::options) {
+            self.sanitizer = Some(Sanitizer::resumed(options));
+        }
+
+        // blocks were compiled for the quirks before
+        #[cfg(feature = "jit")]
+        {
+            if self.jit.is_some() {
+                self.enable_jit();
+            }
         }
     }
 
@@ -66,4 +442,157 @@ impl Cpu {
     pub fn get_display(&self) -> &Display {
         &self.display
     }
-}
\ No newline at end of file
+
+    /// Send what changed on the display since the last frame to a renderer
+    pub fn render<R: Renderer>(&mut self, renderer: &mut R) {
+        self.display.render(renderer);
+    }
+
+    /// The return addresses of the subroutines being executed, for backtraces
+    pub fn get_stack(&self) -> &CallStack {
+        &self.stack
+    }
+
+    pub fn get_config(&self) -> &Config {
+        &self.config
+    }
+
+    pub fn get_registers(&self) -> &Registers {
+        &self.registers
+    }
+
+    /// For code running the program outside the interpreter, e.g recompiled
+    pub fn get_registers_mut(&mut self) -> &mut Registers {
+        &mut self.registers
+    }
+
+    /// Execute an instruction as if it was fetched from the program counter, without the tools
+    /// watching it. For code running the program outside the interpreter, e.g recompiled.
+    pub fn execute(&mut self, instruction: Instruction) -> Result<(), Fault> {
+        if !self.config.platform.supports(&instruction) {
+            let address = self.registers.program_counter;
+            return Err(Fault::InvalidInstruction { address, code: self.fetch(address) });
+        }
+        execute_instruction(
+            instruction,
+            &mut self.memory,
+            &mut self.registers,
+            &mut self.stack,
+            &mut self.display,
+            &mut self.keyboard,
+            &self.config.quirks,
+        )?;
+        self.wait_for_vblank(&instruction);
+        Ok(())
+    }
+
+    /// Hold the program after a DRW until the next vertical blank, with the display wait quirk
+    fn wait_for_vblank(&mut self, instruction: &Instruction) {
+        if let Instruction::DRW { .. } = *instruction {
+            self.waiting_for_vblank = self.config.quirks.display_wait;
+        }
+    }
+
+    pub fn get_memory(&self) -> &Memory {
+        &self.memory
+    }
+
+    /// Change a byte of memory from outside the program, e.g from a debugger
+    pub fn poke(&mut self, address: Address, value: Byte) {
+        self.memory.write(address as usize, value);
+    }
+
+    pub fn get_memory_map(&self) -> &MemoryMap {
+        &self.memory_map
+    }
+
+    pub fn apply_cheats(&mut self, cheats: &CheatList) {
+        cheats.apply(&mut self.memory, &mut self.registers);
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Platform;
+
+    /// 0230 clears the display, only with the hi-res preamble there to do it
+    #[test]
+    fn hires_clear_needs_hires_platform() {
+        let rom = [
+            0x60, 0x3C, // MOV V0, 3C
+            0xD0, 0x05, // DRW V0, V0, 5
+            0x02, 0x30, // HCLS
+        ];
+
+        // the preamble, left blank, in front of the program
+        let mut hires = vec![0; 0xC0];
+        hires.extend_from_slice(&rom);
+        let mut cpu = Cpu::with_config(&hires, Config::for_platform(Platform::HiresChip8)).unwrap();
+        assert_eq!(cpu.get_registers().program_counter, 0x2C0);
+        cpu.tick();
+        cpu.tick();
+        assert!(cpu.get_display().pixels().any(|(_, y, is_set)| is_set && y == 60));
+        cpu.vblank();
+        cpu.tick();
+        assert!(!cpu.get_display().pixels().any(|(_, _, is_set)| is_set));
+
+        for &platform in [Platform::Chip8, Platform::SuperChip, Platform::XoChip].iter() {
+            let mut cpu = Cpu::with_config(&rom, Config::for_platform(platform)).unwrap();
+            cpu.try_tick().unwrap();
+            cpu.try_tick().unwrap();
+            cpu.vblank();
+            assert_eq!(cpu.try_tick(), Err(Fault::InvalidInstruction { address: 0x204, code: 0x0230 }));
+            assert_eq!(cpu.execute(Instruction::HCLS), Err(Fault::InvalidInstruction { address: 0x204, code: 0x0230 }));
+        }
+    }
+
+    /// With the display wait quirk DRW holds the program until the interrupt, which also counts the timers down
+    #[test]
+    fn display_wait_until_interrupt() {
+        let rom = [
+            0x60, 0x02, // MOV V0, 02
+            0xF0, 0x15, // SDELAY V0
+            0xD0, 0x05, // DRW V0, V0, 5
+            0x70, 0x01, // ADD V0, 01
+        ];
+
+        let mut config = Config::default();
+        config.quirks.display_wait = true;
+        let mut cpu = Cpu::with_config(&rom, config).unwrap();
+        cpu.run(3).unwrap();
+        assert!(cpu.is_waiting_for_vblank());
+        cpu.tick();
+        assert_eq!(cpu.get_registers().program_counter, 0x206);
+
+        cpu.interrupt();
+        cpu.tick();
+        assert_eq!(cpu.get_registers().vs[0], 0x03);
+        assert_eq!(cpu.get_registers().delay_timer, 0x01);
+
+        let mut cpu = Cpu::new(&rom).unwrap();
+        cpu.run(4).unwrap();
+        assert_eq!(cpu.get_registers().vs[0], 0x03);
+    }
+
+    /// Memory the program never loaded is initialized in a save state
+    #[test]
+    fn sanitizer_takes_loaded_state_as_initialized() {
+        let rom = [
+            0xA3, 0x00, // MOVI 300
+            0xF0, 0x65, // LDR V0
+        ];
+        let uninitialized_reads = |cpu: &Cpu| cpu.get_sanitizer().unwrap().reports().len();
+
+        let mut cpu = Cpu::new(&rom).unwrap();
+        let state = cpu.save_state();
+        cpu.enable_sanitizer(SanitizerOptions::default());
+        cpu.run(2).unwrap();
+        assert_eq!(uninitialized_reads(&cpu), 1);
+
+        let mut cpu = Cpu::new(&rom).unwrap();
+        cpu.enable_sanitizer(SanitizerOptions::default());
+        cpu.load_state(&state);
+        cpu.run(2).unwrap();
+        assert_eq!(uninitialized_reads(&cpu), 0);
+    }
+}
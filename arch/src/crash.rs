@@ -0,0 +1,193 @@
+use std::fmt;
+
+use {Address, Byte};
+use analysis::Image;
+use config::Quirk;
+use cpu::{Cpu, Fault};
+use png;
+use state::SaveState;
+
+/// Instructions shown on each side of the program counter
+const DISASSEMBLY_CONTEXT: usize = 8;
+
+/// Pixels per display pixel in the PNG
+const DISPLAY_SCALE: usize = 8;
+
+/// CrashReport
+///
+/// The state of the machine when the program faulted, taken before the faulting instruction ran,
+/// so loading the save state and executing one instruction faults again
+pub struct CrashReport {
+    pub fault: Fault,
+    pub rom_sha1: String,
+
+    /// address and code of the instructions executed before the fault, oldest first
+    pub history: Vec<(Address, u16)>,
+    pub state: SaveState,
+}
+
+impl CrashReport {
+    pub fn new(cpu: &Cpu, fault: Fault, rom_sha1: &str) -> CrashReport {
+        CrashReport {
+            fault,
+            rom_sha1: rom_sha1.to_string(),
+            history: cpu.history(),
+            state: cpu.save_state(),
+        }
+    }
+
+    /// The display as text, '#' for set pixels
+    pub fn display_ascii(&self) -> String {
+        let display = &self.state.display;
+        let mut ascii = String::with_capacity((display.width() + 1) * display.height());
+        for (x, _, is_set) in display.pixels() {
+            ascii.push(if is_set { '#' } else { '.' });
+            if x + 1 == display.width() {
+                ascii.push('\n');
+            }
+        }
+        ascii
+    }
+
+    /// The display as a PNG, in the palette the program ran with
+    pub fn display_png(&self) -> Vec<Byte> {
+        let palette = self.state.config.palette;
+        let display = &self.state.display;
+        let width = display.width() * DISPLAY_SCALE;
+        let height = display.height() * DISPLAY_SCALE;
+
+        let mut rgb = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                let is_set = display.pixel(x / DISPLAY_SCALE, y / DISPLAY_SCALE);
+                rgb.extend_from_slice(if is_set { &palette.foreground } else { &palette.background });
+            }
+        }
+        png::encode(width, height, &rgb)
+    }
+
+    /// The files making up the bundle, by name
+    pub fn files(&self) -> Vec<(&'static str, Vec<Byte>)> {
+        vec![
+            ("report.txt", self.to_string().into_bytes()),
+            ("display.png", self.display_png()),
+            ("state.c8s", self.state.to_bytes()),
+        ]
+    }
+}
+
+/// Disassemble a single instruction, whether or not it is within a ROM
+fn disassemble(address: Address, code: u16) -> String {
+    let bytes = [(code >> 8) as Byte, code as Byte];
+    let image = Image {
+        data: &bytes,
+        base: address,
+    };
+    image.decode_at(address).map_or_else(String::new, |decoded| decoded.to_string())
+}
+
+impl fmt::Display for CrashReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = &self.state;
+        let registers = &state.registers;
+        let config = &state.config;
+
+        writeln!(f, "{}", self.fault)?;
+        writeln!(f, "ROM sha1 {}", self.rom_sha1)?;
+
+        let quirks: Vec<&str> = Quirk::ALL.iter()
+            .filter(|&&quirk| config.quirks.get(quirk))
+            .map(Quirk::name)
+            .collect();
+        writeln!(f, "\nconfig:")?;
+        writeln!(f, "    platform {}", config.platform)?;
+        writeln!(f, "    quirks   {}", if quirks.is_empty() { String::from("none") } else { quirks.join(" ") })?;
+        writeln!(f, "    clock    {} instructions per second", config.clock_speed)?;
+        writeln!(f, "    timing   {}", config.timing)?;
+
+        writeln!(f, "\nregisters:")?;
+        for (index, values) in registers.vs.chunks(8).enumerate() {
+            let values: Vec<String> = values.iter()
+                .enumerate()
+                .map(|(offset, value)| format!("V{:X} {:02X}", index * 8 + offset, value))
+                .collect();
+            writeln!(f, "    {}", values.join("  "))?;
+        }
+        writeln!(
+            f, "    I  {:03X}  PC {:03X}  SP {:X}  DT {:02X}  ST {:02X}",
+            registers.i, registers.program_counter, state.stack_pointer, registers.delay_timer, registers.sound_timer
+        )?;
+        if state.waiting_for_vblank {
+            writeln!(f, "    waiting for vblank")?;
+        }
+
+        writeln!(f, "\nstack of {}, innermost first:", config.stack_depth)?;
+        let depth = state.stack_pointer.min(state.stack.len());
+        if depth == 0 {
+            writeln!(f, "    empty")?;
+        }
+        for slot in (0..depth).rev() {
+            writeln!(f, "    {:X} {:03X}", slot, state.stack[slot])?;
+        }
+
+        writeln!(f, "\ndisassembly:")?;
+        let pc = registers.program_counter as usize;
+        let start = pc.saturating_sub(DISASSEMBLY_CONTEXT * 2);
+        for address in (start..=pc + DISASSEMBLY_CONTEXT * 2).step_by(2) {
+            let (high, low) = match (state.memory.get(address), state.memory.get(address + 1)) {
+                (Some(&high), Some(&low)) => (high, low),
+                _ => break,
+            };
+            let code = (high as u16) << 8 | low as u16;
+            let marker = if address == pc { "=>" } else { "  " };
+            writeln!(f, "  {} {:03X}  {:04X}  {}", marker, address, code, disassemble(address as Address, code))?;
+        }
+
+        writeln!(f, "\ndisplay:")?;
+        for line in self.display_ascii().lines() {
+            writeln!(f, "    {}", line)?;
+        }
+
+        writeln!(f, "\nlast {} instructions, oldest first:", self.history.len())?;
+        for &(address, code) in self.history.iter() {
+            writeln!(f, "    {:03X}  {:04X}  {}", address, code, disassemble(address, code))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Loading the state of a report and executing its instruction faults the same way
+    #[test]
+    fn state_reproduces_fault() {
+        let rom = [
+            0x60, 0x05, // 200 MOV V0, 05
+            0xA2, 0x0A, // 202 MOVI 20A
+            0x22, 0x08, // 204 JSR 208
+            0x12, 0x06, // 206 JMP 206
+            0xD0, 0x05, // 208 DRW V0, V0, 5
+            0xFF, 0xFF, // 20A invalid
+        ];
+        let mut cpu = Cpu::new(&rom).unwrap();
+        let fault = cpu.run(10).unwrap_err();
+        assert_eq!(fault, Fault::InvalidInstruction { address: 0x20A, code: 0xFFFF });
+
+        let report = CrashReport::new(&cpu, fault, "sha1");
+        assert_eq!(report.history.last(), Some(&(0x208, 0xD005)));
+        let text = report.to_string();
+        assert!(text.starts_with("Unable to parse instruction FFFF at 20A\n"));
+        assert!(text.contains("    0 206\n"));
+        assert!(text.contains("  => 20A  FFFF"));
+
+        let (_, bytes) = report.files().into_iter().find(|&(name, _)| name == "state.c8s").unwrap();
+        let state = SaveState::from_bytes(&bytes).unwrap();
+        let mut resumed = Cpu::with_config(&rom, state.config.clone()).unwrap();
+        resumed.load_state(&state);
+        assert_eq!(resumed.get_display(), cpu.get_display());
+        assert_eq!(resumed.try_tick(), Err(fault));
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use {Byte};
+use config::{Config, Platform, Quirk};
+
+const EMBEDDED_DATABASE: &str = include_str!("database.txt");
+
+/// Everything known about a single ROM
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RomInfo {
+    pub title: Option<String>,
+    pub author: Option<String>,
+    pub platform: Option<Platform>,
+
+    /// quirks the ROM needs set to a specific value, on top of the platform defaults
+    pub quirks: Vec<(Quirk, bool)>,
+
+    /// recommended instructions per second
+    pub clock_speed: Option<u32>,
+
+    /// what each key does in the game
+    pub keys: Vec<(Byte, String)>,
+}
+
+impl RomInfo {
+    /// Apply the settings of this ROM on top of a config
+    pub fn apply(&self, config: &mut Config) {
+        if let Some(platform) = self.platform {
+            config.set_platform(platform);
+        }
+
+        for &(quirk, enabled) in self.quirks.iter() {
+            config.quirks.set(quirk, enabled);
+        }
+
+        if let Some(clock_speed) = self.clock_speed {
+            config.clock_speed = clock_speed;
+        }
+    }
+
+    /// Fill the fields of this entry with those set in other
+    fn merge(&mut self, other: RomInfo) {
+        if other.title.is_some() {
+            self.title = other.title;
+        }
+        if other.author.is_some() {
+            self.author = other.author;
+        }
+        if other.platform.is_some() {
+            self.platform = other.platform;
+        }
+        if other.clock_speed.is_some() {
+            self.clock_speed = other.clock_speed;
+        }
+        for (quirk, enabled) in other.quirks {
+            self.quirks.retain(|&(existing, _)| existing != quirk);
+            self.quirks.push((quirk, enabled));
+        }
+        for (key, description) in other.keys {
+            self.keys.retain(|&(existing, _)| existing != key);
+            self.keys.push((key, description));
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum DatabaseError {
+    /// line number and the reason it couldn't be parsed
+    InvalidLine(usize, String),
+}
+
+impl fmt::Display for DatabaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            DatabaseError::InvalidLine(line, ref reason) => write!(f, "Invalid database line {}: {}", line, reason),
+        }
+    }
+}
+
+impl ::std::error::Error for DatabaseError {}
+
+/// Database
+///
+/// ROM metadata keyed by the SHA-1 of the ROM.
+///
+/// The text format has a section per ROM, headed by its hash, followed by `key = value` lines:
+///
+/// ```text
+/// [0123456789abcdef0123456789abcdef01234567]
+/// title = Some Game
+/// author = Someone
+/// platform = schip
+/// quirks = shift, !vf_reset
+/// speed = 1000
+/// key.5 = fire
+/// ```
+///
+/// Lines starting with '#' are comments
+#[derive(Clone, Debug, Default)]
+pub struct Database {
+    entries: HashMap<String, RomInfo>,
+}
+
+impl Database {
+    pub fn new() -> Database {
+        Database {
+            entries: HashMap::new(),
+        }
+    }
+
+    /// The database built into the emulator
+    pub fn embedded() -> Database {
+        Database::parse(EMBEDDED_DATABASE).expect("Embedded ROM database is invalid")
+    }
+
+    pub fn parse(text: &str) -> Result<Database, DatabaseError> {
+        let mut database = Database::new();
+        let mut current: Option<(String, RomInfo)> = None;
+
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let invalid = |reason: String| DatabaseError::InvalidLine(line_number, reason);
+
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            if line.starts_with('[') && line.ends_with(']') {
+                if let Some((hash, info)) = current.take() {
+                    database.insert(&hash, info);
+                }
+                let hash = line[1..line.len() - 1].trim().to_lowercase();
+                if hash.len() != 40 || !hash.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(invalid(format!("not a SHA-1: {}", hash)));
+                }
+                current = Some((hash, RomInfo::default()));
+                continue;
+            }
+
+            let info = match current {
+                Some((_, ref mut info)) => info,
+                None => return Err(invalid("entry outside of a ROM section".to_string())),
+            };
+
+            let mut parts = line.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim();
+            let value = match parts.next() {
+                Some(value) => value.trim(),
+                None => return Err(invalid(format!("expected key = value, got {}", line))),
+            };
+
+            match key {
+                "title" => info.title = Some(value.to_string()),
+                "author" => info.author = Some(value.to_string()),
+                "platform" => info.platform = Some(value.parse().map_err(&invalid)?),
+                "speed" => info.clock_speed = Some(value.parse().map_err(|_| invalid(format!("invalid speed: {}", value)))?),
+                "quirks" => {
+                    for name in value.split(',').map(str::trim).filter(|name| !name.is_empty()) {
+                        let (name, enabled) = match name.strip_prefix('!') {
+                            Some(name) => (name, false),
+                            None => (name, true),
+                        };
+                        info.quirks.push((name.parse().map_err(&invalid)?, enabled));
+                    }
+                },
+                _ if key.starts_with("key.") => {
+                    let key_id = Byte::from_str_radix(&key[4..], 16)
+                        .ok()
+                        .filter(|key_id| *key_id <= 0x0F)
+                        .ok_or_else(|| invalid(format!("invalid key: {}", key)))?;
+                    info.keys.push((key_id, value.to_string()));
+                },
+                _ => return Err(invalid(format!("unknown field: {}", key))),
+            }
+        }
+
+        if let Some((hash, info)) = current.take() {
+            database.insert(&hash, info);
+        }
+
+        Ok(database)
+    }
+
+    /// Add an entry, fields already known for the hash are overridden by those set in info
+    pub fn insert(&mut self, hash: &str, info: RomInfo) {
+        self.entries.entry(hash.to_lowercase())
+            .or_default()
+            .merge(info);
+    }
+
+    /// Override entries of this database with those of another, e.g a user's local database
+    pub fn extend(&mut self, other: Database) {
+        for (hash, info) in other.entries {
+            self.insert(&hash, info);
+        }
+    }
+
+    pub fn lookup(&self, hash: &str) -> Option<&RomInfo> {
+        self.entries.get(&hash.to_lowercase())
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn embedded_parses() {
+        Database::embedded();
+    }
+
+    #[test]
+    fn local_overrides_embedded() {
+        let hash = "0123456789abcdef0123456789abcdef01234567";
+        let mut database = Database::parse(&format!("[{}]\ntitle = Game\nplatform = schip\nspeed = 1000\n", hash)).unwrap();
+        database.extend(Database::parse(&format!("[{}]\nspeed = 2000\nquirks = !clip\n", hash.to_uppercase())).unwrap());
+
+        let info = database.lookup(hash).unwrap();
+        assert_eq!(info.title.as_deref(), Some("Game"));
+        assert_eq!(info.platform, Some(Platform::SuperChip));
+        assert_eq!(info.clock_speed, Some(2000));
+        assert_eq!(info.quirks, vec![(Quirk::Clipping, false)]);
+    }
+
+    #[test]
+    fn invalid_line_number() {
+        let text = "# comment\n[0123456789abcdef0123456789abcdef01234567]\nspeed = fast\n";
+        assert_eq!(Database::parse(text).err(), Some(DatabaseError::InvalidLine(3, "invalid speed: fast".to_string())));
+    }
+}
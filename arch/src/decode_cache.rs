@@ -0,0 +1,189 @@
+use {Address};
+use instructions::Instruction;
+use memory::MEMORY_SIZE;
+
+/// An instruction as fetched and parsed from an address
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Decoded {
+    pub code: u16,
+
+    /// None when the code isn't an instruction, so invalid ones aren't parsed again either
+    pub instruction: Option<Instruction>,
+}
+
+/// DecodeCache
+///
+/// The instructions already fetched and parsed, by address, so a loop only pays for decoding once.
+/// An entry depends on the two bytes at its address, writing either of them drops it.
+pub struct DecodeCache {
+    entries: Vec<Option<Decoded>>,
+}
+
+impl DecodeCache {
+    pub fn new() -> DecodeCache {
+        DecodeCache {
+            entries: vec![None; MEMORY_SIZE],
+        }
+    }
+
+    pub fn get(&self, address: Address) -> Option<Decoded> {
+        self.entries[address as usize % MEMORY_SIZE]
+    }
+
+    pub fn insert(&mut self, address: Address, decoded: Decoded) {
+        self.entries[address as usize % MEMORY_SIZE] = Some(decoded);
+    }
+
+    /// Drop the instructions overlapping the bytes from low to high, inclusive
+    pub fn invalidate(&mut self, low: usize, high: usize) {
+        // the instruction starting the byte before low ends in it
+        let start = low.saturating_sub(1);
+        let end = high.min(MEMORY_SIZE - 1);
+        for entry in self.entries[start..=end].iter_mut() {
+            *entry = None;
+        }
+        // and the one at the end of memory wraps around into the first byte
+        if low == 0 {
+            self.entries[MEMORY_SIZE - 1] = None;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.iter_mut().for_each(|entry| *entry = None);
+    }
+}
+
+impl Default for DecodeCache {
+    fn default() -> DecodeCache {
+        DecodeCache::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use Byte;
+    use cheats::{Cheat, CheatList};
+    use cpu::Cpu;
+
+    /// Run the ROM with and without the cache, calling change on both after the given number of
+    /// instructions, and check they end up the same
+    fn compare<F: Fn(&mut Cpu)>(rom: &[Byte], instructions: usize, change_after: usize, change: F) -> Cpu {
+        let run = |cached: bool| {
+            let mut cpu = Cpu::new(rom).unwrap();
+            cpu.set_decode_cache(cached);
+            for executed in 0..instructions {
+                if executed == change_after {
+                    change(&mut cpu);
+                }
+                cpu.try_tick().unwrap();
+            }
+            cpu
+        };
+        let cached = run(true);
+        let uncached = run(false);
+
+        assert_eq!(cached.get_registers(), uncached.get_registers());
+        assert_eq!(&cached.get_memory().data()[..], &uncached.get_memory().data()[..]);
+        assert_eq!(cached.get_display(), uncached.get_display());
+        assert_eq!(cached.history(), uncached.history());
+        cached
+    }
+
+    #[test]
+    fn store_over_executed_instruction() {
+        let rom = [
+            0x12, 0x0A, // 200: JMP 20A
+            0xA2, 0x0A, // 202: MOVI 20A
+            0x60, 0x71, // 204: MOV V0, 71
+            0x61, 0x01, // 206: MOV V1, 01
+            0xF1, 0x55, // 208: STR V1, 20A becomes ADD V1, 01
+            0x61, 0x05, // 20A: MOV V1, 05
+            0x72, 0x01, // 20C: ADD V2, 01
+            0x32, 0x03, // 20E: SE V2, 03
+            0x12, 0x02, // 210: JMP 202
+            0x12, 0x12, // 212: JMP 212
+        ];
+        let cpu = compare(&rom, 40, 0, |_| {});
+        assert_eq!(cpu.get_registers().vs[1], 0x02);
+    }
+
+    #[test]
+    fn bcd_over_executed_jump() {
+        let rom = [
+            0x63, 0xC8, // 200: MOV V3, C8
+            0x74, 0x01, // 202: ADD V4, 01
+            0x34, 0x03, // 204: SE V4, 03
+            0x12, 0x0A, // 206: JMP 20A
+            0x12, 0x08, // 208: JMP 208
+            0x12, 0x0E, // 20A: JMP 20E, BCD 200 makes it JMP 202
+            0x00, 0x00, // 20C:
+            0xA2, 0x0B, // 20E: MOVI 20B
+            0xF3, 0x33, // 210: BCD V3
+            0x12, 0x0A, // 212: JMP 20A
+        ];
+        let cpu = compare(&rom, 40, 0, |_| {});
+        assert_eq!(cpu.get_registers().vs[4], 0x03);
+    }
+
+    #[test]
+    fn poke() {
+        let rom = [
+            0x71, 0x01, // 200: ADD V1, 01
+            0x12, 0x00, // 202: JMP 200
+        ];
+        let cpu = compare(&rom, 20, 10, |cpu| cpu.poke(0x200, 0x72));
+        assert_eq!(cpu.get_registers().vs[1], 5);
+        assert_eq!(cpu.get_registers().vs[2], 5);
+    }
+
+    #[test]
+    fn cheat_write() {
+        let rom = [
+            0x71, 0x01, // 200: ADD V1, 01
+            0x12, 0x00, // 202: JMP 200
+        ];
+        let mut cheats = CheatList::new();
+        cheats.add(Cheat::freeze(0x201, 0x10, "faster"));
+        let cpu = compare(&rom, 20, 10, |cpu| cpu.apply_cheats(&cheats));
+        assert_eq!(cpu.get_registers().vs[1], 5 + 5 * 0x10);
+    }
+
+    #[test]
+    fn load_state() {
+        let rom = [
+            0x71, 0x01, // 200: ADD V1, 01
+            0x12, 0x00, // 202: JMP 200
+        ];
+        let mut other = Cpu::new(&[0x72, 0x01, 0x12, 0x00]).unwrap();
+        other.run(4).unwrap();
+        let state = other.save_state();
+
+        let cpu = compare(&rom, 20, 10, |cpu| cpu.load_state(&state));
+        assert_eq!(cpu.get_registers().vs[1], 0);
+        assert_eq!(cpu.get_registers().vs[2], 2 + 5);
+    }
+
+    #[test]
+    fn writes_wrapping_around_memory() {
+        let rom = [
+            0x60, 0x12, // 200: MOV V0, 12
+            0x61, 0x0A, // 202: MOV V1, 0A
+            0xAF, 0xFF, // 204: MOVI FFF
+            0xF1, 0x55, // 206: STR V1, FFF and 000 make JMP 20A
+            0x1F, 0xFF, // 208: JMP FFF
+            0x72, 0x01, // 20A: ADD V2, 01
+            0x32, 0x02, // 20C: SE V2, 02
+            0x12, 0x12, // 20E: JMP 212
+            0x12, 0x10, // 210: JMP 210
+            0xAF, 0xFF, // 212: MOVI FFF
+            0x63, 0x01, // 214: MOV V3, 01
+            0xF3, 0x1E, // 216: ADI V3, I is past the end of memory
+            0x60, 0x10, // 218: MOV V0, 10
+            0xF0, 0x55, // 21A: STR V0, only 000 changes, making JMP 210
+            0x1F, 0xFF, // 21C: JMP FFF
+        ];
+        let cpu = compare(&rom, 40, 0, |_| {});
+        assert_eq!(cpu.get_registers().vs[2], 1);
+        assert_eq!(cpu.get_registers().program_counter, 0x210);
+    }
+}
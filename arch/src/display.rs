@@ -1,90 +1,393 @@
-use {Byte};
+use std::hash::{Hash, Hasher};
 
+use {Byte, Renderer};
+
+/// The resolution of the original CHIP-8, what a display starts with
 pub const RESOLUTION_WIDTH: usize = 64;
 pub const RESOLUTION_HEIGHT: usize = 32;
-pub const PIXEL_COUNT: usize = RESOLUTION_HEIGHT * RESOLUTION_WIDTH;
 
-type Sprite = Vec<Byte>;
+/// The hi-res CHIP-8 variant's display is twice as tall
+pub const HIRES_RESOLUTION_HEIGHT: usize = 64;
+
+/// The widest and tallest resolution a display can have, SUPER-CHIP's hi-res mode
+pub const MAX_WIDTH: usize = 128;
+pub const MAX_HEIGHT: usize = 64;
+
 type Pixel = bool;
 
+/// A row of pixels, packed
+pub type Row = u128;
+
+/// How a sprite drawn onto the display collided
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Collision {
+    /// rows that turned at least one set pixel off
+    pub rows: usize,
+
+    /// rows cut off by the bottom edge
+    pub clipped_rows: usize,
+}
+
+impl Collision {
+    pub fn any(&self) -> bool {
+        self.rows > 0
+    }
+}
+
+/// A region of the display, in pixels
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Rect {
+    /// The smallest rectangle covering both
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        Rect {
+            x,
+            y,
+            width: (self.x + self.width).max(other.x + other.width) - x,
+            height: (self.y + self.height).max(other.y + other.height) - y,
+        }
+    }
+}
+
+/// Display
+///
+/// Stored as one word per row, pixel x of a row being bit width - 1 - x,
+/// so the leftmost pixel is the most significant bit in use, as in sprites.
+/// A u128 holds the widest, hi-res rows.
+///
+/// Keeps track of what changed since the last frame, so renderers only redraw that
+#[derive(Clone, Debug)]
 pub struct Display {
-    pixels: [Pixel; PIXEL_COUNT],
+    width: usize,
+    height: usize,
+    rows: Vec<Row>,
+
+    /// region changed since the last frame
+    dirty: Option<Rect>,
+
+    /// the resolution changed since the last frame
+    resized: bool,
 }
 
 impl Display {
     pub fn new() -> Display {
+        Display::with_resolution(RESOLUTION_WIDTH, RESOLUTION_HEIGHT)
+    }
+
+    /// A blank display, panics unless the width is between 8 and MAX_WIDTH and the height at most MAX_HEIGHT
+    pub fn with_resolution(width: usize, height: usize) -> Display {
+        assert!((8..=MAX_WIDTH).contains(&width) && (1..=MAX_HEIGHT).contains(&height), "Unsupported resolution {}x{}", width, height);
         Display {
-            pixels: [false; PIXEL_COUNT],
+            width,
+            height,
+            rows: vec![0; height],
+            dirty: None,
+            resized: true,
         }
     }
 
-    pub fn clear(&mut self) {
-        for pixel in self.pixels.iter_mut() {
-            *pixel = false;
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Switch to another resolution, clearing the display
+    pub fn resize(&mut self, width: usize, height: usize) {
+        *self = Display::with_resolution(width, height);
+    }
+
+    fn whole(&self) -> Rect {
+        Rect {
+            x: 0,
+            y: 0,
+            width: self.width,
+            height: self.height,
         }
     }
 
-    pub fn set_sprite(&mut self, dx: usize, dy: usize, sprite: &Vec<Byte>) -> bool {
-        let mut did_flip = false;
+    fn mark_dirty(&mut self, rect: Rect) {
+        self.dirty = Some(match self.dirty {
+            Some(dirty) => dirty.union(&rect),
+            None => rect,
+        });
+    }
+
+    /// Have the whole display redrawn on the next frame
+    pub fn invalidate(&mut self) {
+        let whole = self.whole();
+        self.mark_dirty(whole);
+    }
+
+    pub fn clear(&mut self) {
+        if self.rows.iter().any(|&row| row != 0) {
+            self.rows.iter_mut().for_each(|row| *row = 0);
+            self.invalidate();
+        }
+    }
 
-        let pixel_matrix = pixels_matrix_from_sprite(sprite);
+    /// Draw a sprite, one byte per row, by XORing it onto the display.
+    /// The starting coordinate wraps around the display, the edges of the sprite are either
+    /// clipped or wrapped as well.
+    pub fn draw_sprite(&mut self, x: usize, y: usize, sprite: &[Byte], clip: bool) -> Collision {
+        let x = x % self.width;
+        let y = y % self.height;
+        let mut collision = Collision::default();
 
-        for rowdex in 0..sprite.len() {
-            let byte = sprite[rowdex];
-            let pixels = pixels_from_byte(&byte);
+        // sprites wrapping around the right edge can change any column
+        let columns = if x + 8 <= self.width || clip { (x, (self.width - x).min(8)) } else { (0, self.width) };
 
-            for coldex in 0..pixels.len() {
-                let offseted_x = dx + coldex;
-                let wrapped_row = dy + rowdex + (offseted_x / RESOLUTION_WIDTH);
-                let pixel_index = wrapped_row * RESOLUTION_WIDTH + (dx + coldex) % RESOLUTION_WIDTH;
+        for (offset, &byte) in sprite.iter().enumerate() {
+            let mut py = y + offset;
+            if py >= self.height {
+                if clip {
+                    collision.clipped_rows += 1;
+                    continue;
+                }
+                py %= self.height;
+            }
 
-                let sprite_pixel = pixels[coldex];
-                did_flip |= (sprite_pixel && self.pixels[pixel_index]);
-                self.pixels[pixel_index] ^= sprite_pixel;
+            let bits = self.sprite_row(byte, x, clip);
+            if bits == 0 {
+                continue;
+            }
+            let row = &mut self.rows[py];
+            if *row & bits != 0 {
+                collision.rows += 1;
             }
+            *row ^= bits;
+            self.mark_dirty(Rect {
+                x: columns.0,
+                y: py,
+                width: columns.1,
+                height: 1,
+            });
         }
 
-        did_flip
+        collision
     }
 
-    pub fn temp(&self) -> Vec<(usize, usize, bool)> {
-        let mut vec = Vec::new();
-        for rowdex in 0..RESOLUTION_HEIGHT {
-            let offset = rowdex * RESOLUTION_WIDTH;
-            for coldex in 0..RESOLUTION_WIDTH {
-                let pixel = self.pixels[offset + coldex];
-                vec.push((coldex, rowdex, pixel));
-            }
-        }    
+    /// The bits a sprite byte covers when drawn at column x, which is within the display.
+    /// Whatever sticks out on the right is cut off or wrapped around to the left.
+    fn sprite_row(&self, byte: Byte, x: usize, clip: bool) -> Row {
+        if x + 8 <= self.width {
+            return (byte as Row) << (self.width - 8 - x);
+        }
+
+        let overflow = x + 8 - self.width;
+        let visible = (byte >> overflow) as Row;
+        if clip {
+            visible
+        } else {
+            let wrapped = (byte & ((1 << overflow) - 1)) as Row;
+            visible | wrapped << (self.width - overflow)
+        }
+    }
+
+    fn pixel_bit(&self, x: usize) -> Row {
+        1 << (self.width - 1 - x)
+    }
+
+    /// The packed rows, top to bottom
+    pub fn rows(&self) -> &[Row] {
+        &self.rows
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> bool {
+        self.rows[y] & self.pixel_bit(x) != 0
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, is_set: bool) {
+        if self.pixel(x, y) != is_set {
+            self.rows[y] ^= self.pixel_bit(x);
+            self.mark_dirty(Rect {
+                x,
+                y,
+                width: 1,
+                height: 1,
+            });
+        }
+    }
+
+    /// Every pixel, row by row
+    pub fn pixels(&self) -> Pixels<'_> {
+        let whole = self.whole();
+        self.pixels_in(whole)
+    }
+
+    /// The pixels of a region, row by row
+    pub fn pixels_in(&self, rect: Rect) -> Pixels<'_> {
+        Pixels {
+            display: self,
+            rect,
+            x: rect.x,
+            y: rect.y,
+        }
+    }
 
-        vec
+    /// Whether anything changed since the last frame
+    pub fn is_changed(&self) -> bool {
+        self.dirty.is_some() || self.resized
+    }
+
+    /// The region changed since the last frame, starting a new one
+    pub fn take_dirty(&mut self) -> Option<Rect> {
+        if self.resized {
+            self.resized = false;
+            self.dirty = None;
+            return Some(self.whole());
+        }
+        self.dirty.take()
+    }
+
+    /// End the frame, sending what changed to the renderer
+    pub fn render<R: Renderer>(&mut self, renderer: &mut R) {
+        if self.resized {
+            renderer.resize(self.width, self.height);
+        }
+        if let Some(rect) = self.take_dirty() {
+            for (x, y, is_set) in self.pixels_in(rect) {
+                renderer.pixel(x, y, is_set);
+            }
+            renderer.flush();
+        }
     }
 
     pub fn traverse<F>(&self, f: F)
         where F: Fn(usize, usize, Pixel) {
-            for rowdex in 0..RESOLUTION_HEIGHT {
-                let offset = rowdex * RESOLUTION_WIDTH;
-                for coldex in 0..RESOLUTION_WIDTH {
-                    let pixel = self.pixels[offset + coldex];
-                    f(coldex, rowdex, pixel);
-                }
+            for (x, y, pixel) in self.pixels() {
+                f(x, y, pixel);
             }
         }
 }
 
-fn pixels_from_byte(byte: &Byte) -> [Pixel; 8] {
-    [
-        byte & 128 == 128,
-        byte & 64 == 64,
-        byte & 32 == 32,
-        byte & 16 == 16,
-        byte & 8 == 8,
-        byte & 4 == 4,
-        byte & 2 == 2,
-        byte & 1 == 1,
-    ]
+impl Default for Display {
+    fn default() -> Display {
+        Display::new()
+    }
+}
+
+/// Displays are equal when they show the same pixels, whatever was redrawn
+impl PartialEq for Display {
+    fn eq(&self, other: &Display) -> bool {
+        self.width == other.width && self.height == other.height && self.rows == other.rows
+    }
+}
+
+impl Eq for Display {}
+
+impl Hash for Display {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.width.hash(state);
+        self.height.hash(state);
+        self.rows.hash(state);
+    }
 }
 
-fn pixels_matrix_from_sprite(sprite: &Sprite) -> Vec<[Pixel; 8]> {
-    sprite.iter().map(pixels_from_byte).collect()
-}
\ No newline at end of file
+/// Pixels
+///
+/// The pixels of a region of a display as x, y and whether it is set, read straight from the rows
+pub struct Pixels<'a> {
+    display: &'a Display,
+    rect: Rect,
+    x: usize,
+    y: usize,
+}
+
+impl<'a> Iterator for Pixels<'a> {
+    type Item = (usize, usize, bool);
+
+    fn next(&mut self) -> Option<(usize, usize, bool)> {
+        if self.rect.width == 0 || self.y >= self.rect.y + self.rect.height {
+            return None;
+        }
+
+        let pixel = (self.x, self.y, self.display.pixel(self.x, self.y));
+        self.x += 1;
+        if self.x >= self.rect.x + self.rect.width {
+            self.x = self.rect.x;
+            self.y += 1;
+        }
+        Some(pixel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::Config;
+    use cpu::Cpu;
+
+    const BLOCK: [Byte; 4] = [0xFF; 4];
+
+    /// The set pixels, row by row
+    fn set_pixels(display: &Display) -> Vec<(usize, usize)> {
+        display.pixels().filter(|&(_, _, is_set)| is_set).map(|(x, y, _)| (x, y)).collect()
+    }
+
+    #[test]
+    fn clip_at_corner() {
+        let mut display = Display::new();
+        let collision = display.draw_sprite(60, 30, &BLOCK, true);
+        assert_eq!(collision, Collision { rows: 0, clipped_rows: 2 });
+
+        let expected: Vec<(usize, usize)> = (30..32).flat_map(|y| (60..64).map(move |x| (x, y))).collect();
+        assert_eq!(set_pixels(&display), expected);
+
+        let collision = display.draw_sprite(60, 30, &BLOCK, true);
+        assert_eq!(collision, Collision { rows: 2, clipped_rows: 2 });
+        assert!(set_pixels(&display).is_empty());
+    }
+
+    #[test]
+    fn wrap_at_corner() {
+        let mut display = Display::new();
+        let collision = display.draw_sprite(60, 30, &BLOCK, false);
+        assert_eq!(collision, Collision::default());
+
+        let columns = [0, 1, 2, 3, 60, 61, 62, 63];
+        let expected: Vec<(usize, usize)> = [0, 1, 30, 31].iter()
+            .flat_map(|&y| columns.iter().map(move |&x| (x, y)))
+            .collect();
+        assert_eq!(set_pixels(&display), expected);
+
+        let collision = display.draw_sprite(60, 30, &BLOCK, false);
+        assert_eq!(collision, Collision { rows: 4, clipped_rows: 0 });
+        assert!(set_pixels(&display).is_empty());
+    }
+
+    /// VF after drawing the 5 row glyph of 0 at (60,30) twice, clipped
+    fn vf_of_draws(collision_counts_rows: bool) -> (Byte, Byte) {
+        let rom = [
+            0x60, 0x3C, // MOV V0, 3C
+            0x61, 0x1E, // MOV V1, 1E
+            0xF2, 0x29, // FONT V2
+            0xD0, 0x15, // DRW V0, V1, 5
+            0x83, 0xF0, // MOVXY V3, VF
+            0xD0, 0x15, // DRW V0, V1, 5
+        ];
+        let mut config = Config::default();
+        config.quirks.clipping = true;
+        config.quirks.collision_counts_rows = collision_counts_rows;
+        let mut cpu = Cpu::with_config(&rom, config).unwrap();
+        cpu.run(6).unwrap();
+        (cpu.get_registers().vs[3], cpu.get_registers().vs[0xF])
+    }
+
+    #[test]
+    fn vf_by_collision_mode() {
+        assert_eq!(vf_of_draws(false), (0, 1));
+        // 3 rows clipped off the bottom, then 2 rows colliding as well
+        assert_eq!(vf_of_draws(true), (3, 5));
+    }
+}
@@ -0,0 +1,181 @@
+use {Byte};
+use config::Config;
+use instructions::Instruction;
+use registers::Registers;
+
+/// What SCHIP and XO-CHIP programs read for a 16x16 sprite drawn by DXY0, two bytes per row.
+/// The interpreter doesn't draw those and reads nothing.
+pub const LARGE_SPRITE_SIZE: usize = 32;
+
+/// Why an instruction touches memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum AccessKind {
+    /// sprite rows read by DRW
+    Sprite,
+
+    /// registers loaded by LDR
+    Load,
+
+    /// registers stored by STR
+    Store,
+
+    /// digits written by BCD
+    Bcd,
+}
+
+/// A range of memory an instruction reads or writes, starting at I.
+/// The range isn't wrapped, start + length can go past the end of memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryAccess {
+    pub kind: AccessKind,
+    pub start: usize,
+    pub length: usize,
+}
+
+impl MemoryAccess {
+    pub fn is_write(&self) -> bool {
+        match self.kind {
+            AccessKind::Store | AccessKind::Bcd => true,
+            AccessKind::Sprite | AccessKind::Load => false,
+        }
+    }
+
+    pub fn addresses(&self) -> ::std::ops::Range<usize> {
+        self.start..self.start + self.length
+    }
+}
+
+/// Effects
+///
+/// What an instruction is about to read and write, given the registers before it runs.
+/// Lets tools watch execution without the interpreter knowing about them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Effects {
+    /// bit x set for each Vx read
+    pub reads: u16,
+
+    /// bit x set for each Vx written
+    pub writes: u16,
+
+    pub reads_i: bool,
+    pub writes_i: bool,
+    pub memory: Option<MemoryAccess>,
+}
+
+impl Effects {
+    pub fn of(instruction: &Instruction, registers: &Registers, config: &Config) -> Effects {
+        use instructions::Instruction::*;
+
+        let quirks = &config.quirks;
+        let bit = |reg_id: Byte| 1u16 << (reg_id & 0xF);
+        let up_to = |reg_id: Byte| (0..=reg_id & 0xF).fold(0u16, |bits, reg_id| bits | bit(reg_id));
+        let vf = bit(0xF);
+        let i = registers.i as usize;
+
+        let mut effects = Effects::default();
+        match *instruction {
+            SYS { .. } | CLS | HCLS | RTS | JMP { .. } | JSR { .. } => {},
+            SE { reg_id, .. } | SNE { reg_id, .. } | SKP { reg_id } | SKNP { reg_id } |
+            SDELAY { reg_id } | SSOUND { reg_id } => effects.reads = bit(reg_id),
+            SEXY { x_reg_id, y_reg_id } | SNEXY { x_reg_id, y_reg_id } => effects.reads = bit(x_reg_id) | bit(y_reg_id),
+            MOV { reg_id, .. } | RAND { reg_id, .. } | GDELAY { reg_id } | KEY { reg_id } => effects.writes = bit(reg_id),
+            ADD { reg_id, .. } => {
+                effects.reads = bit(reg_id);
+                effects.writes = bit(reg_id);
+            },
+            MOVXY { x_reg_id, y_reg_id } => {
+                effects.reads = bit(y_reg_id);
+                effects.writes = bit(x_reg_id);
+            },
+            ORXY { x_reg_id, y_reg_id } | ANDXY { x_reg_id, y_reg_id } | XORXY { x_reg_id, y_reg_id } => {
+                effects.reads = bit(x_reg_id) | bit(y_reg_id);
+                effects.writes = bit(x_reg_id) | if quirks.vf_reset { vf } else { 0 };
+            },
+            ADDXY { x_reg_id, y_reg_id } | SUBXY { x_reg_id, y_reg_id } | RSUBXY { x_reg_id, y_reg_id } => {
+                effects.reads = bit(x_reg_id) | bit(y_reg_id);
+                effects.writes = bit(x_reg_id) | vf;
+            },
+            SHR { x_reg_id, y_reg_id } | SHL { x_reg_id, y_reg_id } => {
+                effects.reads = bit(if quirks.shift_ignores_vy { x_reg_id } else { y_reg_id });
+                effects.writes = bit(x_reg_id) | vf;
+            },
+            MOVI { .. } => effects.writes_i = true,
+            JMI { address } => effects.reads = bit(if quirks.jump_uses_vx { (address >> 8) as Byte } else { 0 }),
+            DRW { x_reg_id, y_reg_id, value } => {
+                effects.reads = bit(x_reg_id) | bit(y_reg_id);
+                effects.writes = vf;
+                effects.reads_i = true;
+                effects.memory = Some(MemoryAccess {
+                    kind: AccessKind::Sprite,
+                    start: i,
+                    length: value as usize,
+                });
+            },
+            ADI { reg_id } => {
+                effects.reads = bit(reg_id);
+                effects.reads_i = true;
+                effects.writes_i = true;
+            },
+            FONT { reg_id } => {
+                effects.reads = bit(reg_id);
+                effects.writes_i = true;
+            },
+            BCD { reg_id } => {
+                effects.reads = bit(reg_id);
+                effects.reads_i = true;
+                effects.memory = Some(MemoryAccess {
+                    kind: AccessKind::Bcd,
+                    start: i,
+                    length: 3,
+                });
+            },
+            STR { reg_id } => {
+                effects.reads = up_to(reg_id);
+                effects.reads_i = true;
+                effects.writes_i = quirks.load_store_increments_i;
+                effects.memory = Some(MemoryAccess {
+                    kind: AccessKind::Store,
+                    start: i,
+                    length: reg_id as usize + 1,
+                });
+            },
+            LDR { reg_id } => {
+                effects.writes = up_to(reg_id);
+                effects.reads_i = true;
+                effects.writes_i = quirks.load_store_increments_i;
+                effects.memory = Some(MemoryAccess {
+                    kind: AccessKind::Load,
+                    start: i,
+                    length: reg_id as usize + 1,
+                });
+            },
+        }
+        effects
+    }
+
+    /// The registers read, as ids
+    pub fn registers_read(&self) -> impl Iterator<Item = Byte> {
+        let reads = self.reads;
+        (0..16).filter(move |reg_id| reads & (1 << reg_id) != 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use config::Platform;
+
+    #[test]
+    fn sprite_read_matches_height() {
+        let length = |value, platform| {
+            let drw = Instruction::DRW { x_reg_id: 0, y_reg_id: 1, value };
+            Effects::of(&drw, &Registers::new(), &Config::for_platform(platform)).memory.map(|access| access.length)
+        };
+        for &platform in [Platform::Chip8, Platform::HiresChip8, Platform::SuperChip, Platform::XoChip].iter() {
+            assert_eq!(length(5, platform), Some(5));
+            // DXY0 draws nothing, whatever the platform
+            assert_eq!(length(0, platform), Some(0));
+        }
+    }
+}
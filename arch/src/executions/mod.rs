@@ -1,42 +1,51 @@
 use rand;
 
 use {Address, Byte};
-use memory::{Memory, FONT_OFFSET};
+use memory::{Memory, FONT_OFFSET, FONT_HEIGHT};
 use registers::{Registers};
 use instructions::{Instruction};
 use display::{Display};
 use keyboard::{Keyboard};
-use cpu::{Stack};
+use cpu::{Fault};
+use stack::{CallStack};
+use config::{Quirks};
 
 use instructions::Instruction::*;
 
+/// DRW takes the height of a sprite from a nibble
+const MAX_SPRITE_HEIGHT: usize = 0x0F;
+
 pub fn execute_instruction(
     instruction: Instruction,
     memory: &mut Memory,
     registers: &mut Registers,
-    stack: &mut Stack,
+    stack: &mut CallStack,
     display: &mut Display,
     keyboard: &mut Keyboard,
-) {
+    quirks: &Quirks,
+) -> Result<(), Fault> {
     match instruction {
         SYS { address } => {
             // Doc says modern interperters ignore, so ignore
         },
-        CLS => {
+        CLS | HCLS => {
             display.clear();
             registers.program_counter += 2;
         },
         RTS => {
-            registers.program_counter =
-                stack[registers.stack_pointer as usize];
-            registers.stack_pointer -= 1;
+            match stack.pop() {
+                Ok(Some(return_address)) => registers.program_counter = return_address,
+                Ok(None) => registers.program_counter += 2,
+                Err(_) => return Err(Fault::StackUnderflow { address: registers.program_counter }),
+            }
         },
         JMP { address } => {
             registers.program_counter = address;
         },
         JSR { address } => {
-            registers.stack_pointer += 1;
-            stack[registers.stack_pointer as usize] = registers.program_counter;
+            if stack.push(registers.program_counter + 2).is_err() {
+                return Err(Fault::StackOverflow { address: registers.program_counter });
+            }
             registers.program_counter = address;
         },
         SE { reg_id, value } => {
@@ -77,54 +86,60 @@ pub fn execute_instruction(
         },
         ORXY { x_reg_id, y_reg_id } => {
             registers.vs[x_reg_id as usize] |= registers.vs[y_reg_id as usize];
+            if quirks.vf_reset {
+                registers.vs[0x0F] = 0;
+            }
             registers.program_counter += 2;
         },
         ANDXY { x_reg_id, y_reg_id } => {
             registers.vs[x_reg_id as usize] &= registers.vs[y_reg_id as usize];
+            if quirks.vf_reset {
+                registers.vs[0x0F] = 0;
+            }
             registers.program_counter += 2;
         },
         XORXY { x_reg_id, y_reg_id } => {
             registers.vs[x_reg_id as usize] ^= registers.vs[y_reg_id as usize];
+            if quirks.vf_reset {
+                registers.vs[0x0F] = 0;
+            }
             registers.program_counter += 2;
         },
         ADDXY { x_reg_id, y_reg_id } => {
             let x = registers.vs[x_reg_id as usize];
             let y = registers.vs[y_reg_id as usize];
-            let sum = (x as u16) + (y as u16);
-            if sum > 255 {
-                registers.vs[0x0F] = 1;
-            }
-            registers.vs[x_reg_id as usize] = sum as Byte;
+            let (sum, carry) = x.overflowing_add(y);
+            registers.vs[x_reg_id as usize] = sum;
+            registers.vs[0x0F] = carry as Byte;
             registers.program_counter += 2;
         },
         SUBXY { x_reg_id, y_reg_id } => {
             let x = registers.vs[x_reg_id as usize];
             let y = registers.vs[y_reg_id as usize];
-            if x < y {
-                registers.vs[0x0F] = 1;
-            }
-            registers.vs[x_reg_id as usize] = x - y;
+            registers.vs[x_reg_id as usize] = x.wrapping_sub(y);
+            // VF is set when nothing was borrowed
+            registers.vs[0x0F] = (x >= y) as Byte;
             registers.program_counter += 2;
         },
-        SHR { reg_id } => {
-            let v = registers.vs[reg_id as usize];
+        SHR { x_reg_id, y_reg_id } => {
+            let source = if quirks.shift_ignores_vy { x_reg_id } else { y_reg_id };
+            let v = registers.vs[source as usize];
+            registers.vs[x_reg_id as usize] = v >> 1;
             registers.vs[0x0F] = v & 0x01;
-            registers.vs[reg_id as usize] >>= 1;
             registers.program_counter += 2;
         },
         RSUBXY { x_reg_id, y_reg_id } => {
             let x = registers.vs[x_reg_id as usize];
             let y = registers.vs[y_reg_id as usize];
-            if y < x {
-                registers.vs[0x0F] = 1;
-            }
-            registers.vs[x_reg_id as usize] = y - x;
+            registers.vs[x_reg_id as usize] = y.wrapping_sub(x);
+            registers.vs[0x0F] = (y >= x) as Byte;
             registers.program_counter += 2;
         },
-        SHL { reg_id } => {
-            let v = registers.vs[reg_id as usize];
+        SHL { x_reg_id, y_reg_id } => {
+            let source = if quirks.shift_ignores_vy { x_reg_id } else { y_reg_id };
+            let v = registers.vs[source as usize];
+            registers.vs[x_reg_id as usize] = v << 1;
             registers.vs[0x0F] = (v & 0x80) >> 7;
-            registers.vs[reg_id as usize] <<= 1;
             registers.program_counter += 2;
         },
         SNEXY { x_reg_id, y_reg_id } => {
@@ -141,23 +156,28 @@ pub fn execute_instruction(
             registers.program_counter += 2;
         },
         JMI { address } => {
-            registers.program_counter = (registers.vs[0] as u16) + address;
+            let reg_id = if quirks.jump_uses_vx { (address >> 8) as usize } else { 0 };
+            registers.program_counter = (registers.vs[reg_id] as u16) + address;
         },
         RAND { reg_id, value } => {
             let random_byte = rand::prelude::random::<Byte>();
-            registers.vs[reg_id as usize] = random_byte + value;
+            registers.vs[reg_id as usize] = random_byte & value;
             registers.program_counter += 2;
         },
         DRW { x_reg_id, y_reg_id, value } => {
-            let mut sprite = Vec::with_capacity(value as usize);
-            for i in 0..value as u16 {
-                let location = (registers.i + i) as usize;
-                sprite.push(memory.data[location]);
+            let mut rows = [0; MAX_SPRITE_HEIGHT];
+            let sprite = &mut rows[..value as usize];
+            for (offset, row) in sprite.iter_mut().enumerate() {
+                *row = memory.read(registers.i as usize + offset);
             }
             let x = registers.vs[x_reg_id as usize] as usize;
             let y = registers.vs[y_reg_id as usize] as usize;
-            let did_flip = display.set_sprite(x, y, &sprite);
-            registers.vs[0x0F] = if did_flip { 1 } else { 0 };
+            let collision = display.draw_sprite(x, y, sprite, quirks.clipping);
+            registers.vs[0x0F] = if quirks.collision_counts_rows {
+                (collision.rows + collision.clipped_rows) as Byte
+            } else {
+                collision.any() as Byte
+            };
             registers.program_counter += 2;
         },
         SKP { reg_id } => {
@@ -192,12 +212,13 @@ pub fn execute_instruction(
             registers.program_counter += 2;
         },
         ADI { reg_id } => {
-            registers.i += registers.vs[reg_id as usize] as u16;
+            registers.i = registers.i.wrapping_add(registers.vs[reg_id as usize] as u16);
             registers.program_counter += 2;
         },
         FONT { reg_id } => {
             let v = registers.vs[reg_id as usize];
-            registers.i = FONT_OFFSET as u16 + v as u16;
+            registers.i = FONT_OFFSET as u16 + (v as u16 & 0x0F) * FONT_HEIGHT as u16;
+            registers.program_counter += 2;
         },
         BCD { reg_id } => {
             let value = registers.vs[reg_id as usize];
@@ -206,25 +227,154 @@ pub fn execute_instruction(
             let tens = (value / 10) % 10;
             let hundreds = (value / 100) % 10;
 
-            memory.data[registers.i as usize] = hundreds;
-            memory.data[registers.i as usize + 1] = tens;
-            memory.data[registers.i as usize + 2] = units;
+            memory.write(registers.i as usize, hundreds);
+            memory.write(registers.i as usize + 1, tens);
+            memory.write(registers.i as usize + 2, units);
+            registers.program_counter += 2;
         },
         STR { reg_id } => {
             let initial_location = registers.i as usize;
-            for curr_reg_id in 0..reg_id as usize {
+            for curr_reg_id in 0..=reg_id as usize {
                 let value = registers.vs[curr_reg_id];
-                memory.data[initial_location + curr_reg_id] = value;
+                memory.write(initial_location + curr_reg_id, value);
             }
-            registers.i += reg_id as u16 + 1;
+            if quirks.load_store_increments_i {
+                registers.i = registers.i.wrapping_add(reg_id as u16 + 1);
+            }
+            registers.program_counter += 2;
         },
         LDR { reg_id } => {
             let initial_location = registers.i as usize;
-            for curr_reg_id in 0..reg_id as usize {
-                let data = memory.data[initial_location + curr_reg_id];
+            for curr_reg_id in 0..=reg_id as usize {
+                let data = memory.read(initial_location + curr_reg_id);
                 registers.vs[curr_reg_id] = data;
             }
-            registers.i += reg_id as u16 + 1;
+            if quirks.load_store_increments_i {
+                registers.i = registers.i.wrapping_add(reg_id as u16 + 1);
+            }
+            registers.program_counter += 2;
         },
     };
-}
\ No newline at end of file
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use cpu::Cpu;
+    use memory::{FONT_HEIGHT, FONT_OFFSET, MEMORY_SIZE};
+    use registers::Registers;
+    use Byte;
+
+    /// The registers after running a program for count instructions
+    fn run(rom: &[Byte], count: usize) -> Registers {
+        let mut cpu = Cpu::new(rom).unwrap();
+        cpu.run(count).unwrap();
+        *cpu.get_registers()
+    }
+
+    /// V0 and VF after VF = 7, V0 = x, V1 = y and the 8XY op given by its low nibble
+    fn arithmetic(op: Byte, x: Byte, y: Byte) -> (Byte, Byte) {
+        let registers = run(&[0x6F, 0x07, 0x60, x, 0x61, y, 0x80, 0x10 | op], 4);
+        (registers.vs[0], registers.vs[0xF])
+    }
+
+    #[test]
+    fn add_sets_carry() {
+        assert_eq!(arithmetic(0x4, 0xFF, 0x02), (0x01, 1));
+        assert_eq!(arithmetic(0x4, 0x01, 0x02), (0x03, 0));
+    }
+
+    #[test]
+    fn sub_sets_no_borrow() {
+        assert_eq!(arithmetic(0x5, 5, 1), (4, 1));
+        assert_eq!(arithmetic(0x5, 1, 5), (0xFC, 0));
+        assert_eq!(arithmetic(0x5, 5, 5), (0, 1));
+        assert_eq!(arithmetic(0x7, 1, 5), (4, 1));
+        assert_eq!(arithmetic(0x7, 5, 1), (0xFC, 0));
+    }
+
+    /// With VF as Vx the flag is written last and wins over the result
+    #[test]
+    fn flag_overwrites_vf_result() {
+        // VF = 5, V1 = 1, SUB VF, V1
+        assert_eq!(run(&[0x6F, 0x05, 0x61, 0x01, 0x8F, 0x15], 3).vs[0xF], 1);
+        // VF = 1, V1 = 5, SUB VF, V1
+        assert_eq!(run(&[0x6F, 0x01, 0x61, 0x05, 0x8F, 0x15], 3).vs[0xF], 0);
+        // VF = FF, V1 = 1, ADD VF, V1
+        assert_eq!(run(&[0x6F, 0xFF, 0x61, 0x01, 0x8F, 0x14], 3).vs[0xF], 1);
+        // VF = 1, V1 = 1, ADD VF, V1
+        assert_eq!(run(&[0x6F, 0x01, 0x61, 0x01, 0x8F, 0x14], 3).vs[0xF], 0);
+    }
+
+    #[test]
+    fn font_points_at_glyph() {
+        // V0 = 7, FONT V0, V1 = 1A, FONT V1
+        let rom = [0x60, 0x07, 0xF0, 0x29, 0x61, 0x1A, 0xF1, 0x29];
+        let registers = run(&rom[..4], 2);
+        assert_eq!((registers.i, registers.program_counter), ((FONT_OFFSET + 7 * FONT_HEIGHT) as u16, 0x204));
+        // only the low digit of Vx picks the glyph
+        assert_eq!(run(&rom, 4).i, (FONT_OFFSET + 0xA * FONT_HEIGHT) as u16);
+    }
+
+    #[test]
+    fn rand_masks_with_value() {
+        // RAND V0, 0F; RAND V1, 00
+        for _ in 0..32 {
+            let registers = run(&[0xC0, 0x0F, 0xC1, 0x00], 2);
+            assert_eq!(registers.vs[0] & 0xF0, 0);
+            assert_eq!(registers.vs[1], 0);
+            assert_eq!(registers.program_counter, 0x204);
+        }
+    }
+
+    #[test]
+    fn store_and_load_include_vx() {
+        let rom = [
+            0x60, 0x01, // 200 MOV V0, 01
+            0x61, 0x02, // 202 MOV V1, 02
+            0x62, 0x03, // 204 MOV V2, 03
+            0x63, 0x04, // 206 MOV V3, 04
+            0xA3, 0x00, // 208 MOVI 300
+            0xF2, 0x55, // 20A STR V2
+            0xA3, 0x00, // 20C MOVI 300
+            0xF3, 0x65, // 20E LDR V3
+        ];
+        let mut cpu = Cpu::new(&rom).unwrap();
+        cpu.run(6).unwrap();
+        assert_eq!(&cpu.get_memory().data()[0x300..0x304], &[1, 2, 3, 0]);
+        assert_eq!((cpu.get_registers().i, cpu.get_registers().program_counter), (0x303, 0x20C));
+
+        cpu.run(2).unwrap();
+        assert_eq!(&cpu.get_registers().vs[..4], &[1, 2, 3, 0]);
+        assert_eq!((cpu.get_registers().i, cpu.get_registers().program_counter), (0x304, 0x210));
+    }
+
+    #[test]
+    fn memory_wraps_around() {
+        let rom = [
+            0x60, 0x01, // 200 MOV V0, 01
+            0x61, 0x02, // 202 MOV V1, 02
+            0x62, 0x03, // 204 MOV V2, 03
+            0xAF, 0xFE, // 206 MOVI FFE
+            0xF2, 0x55, // 208 STR V2
+            0x63, 0xFF, // 20A MOV V3, FF
+            0xAF, 0xFF, // 20C MOVI FFF
+            0xF3, 0x33, // 20E BCD V3
+            0xF3, 0x1E, // 210 ADI V3
+        ];
+        let mut cpu = Cpu::new(&rom).unwrap();
+        cpu.run(5).unwrap();
+        let end = MEMORY_SIZE - 1;
+        assert_eq!((cpu.get_memory().data()[end - 1], cpu.get_memory().data()[end], cpu.get_memory().data()[0]), (1, 2, 3));
+
+        cpu.run(3).unwrap();
+        assert_eq!((cpu.get_memory().data()[end], cpu.get_memory().data()[0], cpu.get_memory().data()[1]), (2, 5, 5));
+        assert_eq!(cpu.get_registers().program_counter, 0x210);
+
+        // I wraps around its 16 bits
+        cpu.get_registers_mut().i = 0xFFFF;
+        cpu.run(1).unwrap();
+        assert_eq!(cpu.get_registers().i, 0xFE);
+    }
+}
@@ -0,0 +1,14 @@
+use crc32fast;
+use sha1_smol::Sha1;
+
+use {Byte};
+
+/// SHA-1 of a ROM as a lowercase hex string, the key used by the ROM database
+pub fn sha1(data: &[Byte]) -> String {
+    Sha1::from(data).digest().to_string()
+}
+
+/// CRC32 of a ROM, as used by patch formats and most ROM lists
+pub fn crc32(data: &[Byte]) -> u32 {
+    crc32fast::hash(data)
+}
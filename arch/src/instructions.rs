@@ -1,5 +1,9 @@
+use std::fmt;
+
 use {Address, Byte};
+use config::{Platform};
 
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Instruction {
 
     /// Jump to a machine code routin at address
@@ -9,6 +13,10 @@ pub enum Instruction {
     /// Clear the display
     CLS,
 
+    /// Clear the 64x64 display of the hi-res variant
+    ///  - 0230, a call to the routine the hi-res preamble has at 230, no other platform runs it
+    HCLS,
+
     /// Return from a subroutine
     ///  - set program counter to the top of the stack
     ///  - decrement the stack pointer
@@ -60,18 +68,20 @@ pub enum Instruction {
     ///  - i.e set VF = 1 if initialy Vx_reg_id < Vy_reg_id
     SUBXY { x_reg_id: Byte, y_reg_id: Byte },
 
-    /// set Vreg_id = Vreg_id >> 1
+    /// set Vx_reg_id = Vy_reg_id >> 1
     /// set VF = initial lsb
-    SHR { reg_id: Byte },
+    ///  - with the shift quirk, Vx_reg_id is shifted in place and Vy_reg_id is ignored
+    SHR { x_reg_id: Byte, y_reg_id: Byte },
 
     /// set Vx_reg_id = Vy_reg_id - Vx_reg_id
     /// set VF = burrow
     ///  - i.e set VF = 1 if initialy Vy_reg_id < Vx_reg_id
     RSUBXY { x_reg_id: Byte, y_reg_id: Byte },
 
-    /// set Vreg_id = Vreg_id << 1
+    /// set Vx_reg_id = Vy_reg_id << 1
     /// set VF = initial msb
-    SHL { reg_id: Byte },
+    ///  - with the shift quirk, Vx_reg_id is shifted in place and Vy_reg_id is ignored
+    SHL { x_reg_id: Byte, y_reg_id: Byte },
 
     /// skip next instruction if Vx_reg_id != Vy_reg_id
     SNEXY { x_reg_id: Byte, y_reg_id: Byte },
@@ -80,6 +90,7 @@ pub enum Instruction {
     MOVI { address: Address },
 
     /// jump to address + V0
+    ///  - with the jump quirk, jump to address + Vx where x is the high nibble of address
     JMI { address: Address },
 
     /// set Vreg_id = {random_byte} AND value
@@ -127,6 +138,48 @@ pub enum Instruction {
 }
 
 impl Instruction {
+    pub fn mnemonic(&self) -> &'static str {
+        use self::Instruction::*;
+        match *self {
+            SYS { .. } => "SYS",
+            CLS => "CLS",
+            HCLS => "HCLS",
+            RTS => "RTS",
+            JMP { .. } => "JMP",
+            JSR { .. } => "JSR",
+            SE { .. } => "SE",
+            SNE { .. } => "SNE",
+            SEXY { .. } => "SEXY",
+            MOV { .. } => "MOV",
+            ADD { .. } => "ADD",
+            MOVXY { .. } => "MOVXY",
+            ORXY { .. } => "ORXY",
+            ANDXY { .. } => "ANDXY",
+            XORXY { .. } => "XORXY",
+            ADDXY { .. } => "ADDXY",
+            SUBXY { .. } => "SUBXY",
+            SHR { .. } => "SHR",
+            RSUBXY { .. } => "RSUBXY",
+            SHL { .. } => "SHL",
+            SNEXY { .. } => "SNEXY",
+            MOVI { .. } => "MOVI",
+            JMI { .. } => "JMI",
+            RAND { .. } => "RAND",
+            DRW { .. } => "DRW",
+            SKP { .. } => "SKP",
+            SKNP { .. } => "SKNP",
+            GDELAY { .. } => "GDELAY",
+            KEY { .. } => "KEY",
+            SDELAY { .. } => "SDELAY",
+            SSOUND { .. } => "SSOUND",
+            ADI { .. } => "ADI",
+            FONT { .. } => "FONT",
+            BCD { .. } => "BCD",
+            STR { .. } => "STR",
+            LDR { .. } => "LDR",
+        }
+    }
+
     pub fn parse_code(code: u16) -> Option<Instruction> {
         
         let nibbles: [u8; 4] = [
@@ -140,10 +193,83 @@ impl Instruction {
     }
 }
 
+/// Disassembly, e.g `MOV V1, 05` or `DRW V0, V1, 5`
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::Instruction::*;
+        let mnemonic = self.mnemonic();
+        match *self {
+            CLS | HCLS | RTS => write!(f, "{}", mnemonic),
+            SYS { address } | JMP { address } | JSR { address } | MOVI { address } | JMI { address } => {
+                write!(f, "{} {:03X}", mnemonic, address)
+            },
+            SE { reg_id, value } | SNE { reg_id, value } | MOV { reg_id, value } |
+            ADD { reg_id, value } | RAND { reg_id, value } => write!(f, "{} V{:X}, {:02X}", mnemonic, reg_id, value),
+            SEXY { x_reg_id, y_reg_id } | MOVXY { x_reg_id, y_reg_id } | ORXY { x_reg_id, y_reg_id } |
+            ANDXY { x_reg_id, y_reg_id } | XORXY { x_reg_id, y_reg_id } | ADDXY { x_reg_id, y_reg_id } |
+            SUBXY { x_reg_id, y_reg_id } | SHR { x_reg_id, y_reg_id } | RSUBXY { x_reg_id, y_reg_id } |
+            SHL { x_reg_id, y_reg_id } | SNEXY { x_reg_id, y_reg_id } => {
+                write!(f, "{} V{:X}, V{:X}", mnemonic, x_reg_id, y_reg_id)
+            },
+            DRW { x_reg_id, y_reg_id, value } => write!(f, "{} V{:X}, V{:X}, {:X}", mnemonic, x_reg_id, y_reg_id, value),
+            SKP { reg_id } | SKNP { reg_id } | GDELAY { reg_id } | KEY { reg_id } | SDELAY { reg_id } |
+            SSOUND { reg_id } | ADI { reg_id } | FONT { reg_id } | BCD { reg_id } | STR { reg_id } |
+            LDR { reg_id } => write!(f, "{} V{:X}", mnemonic, reg_id),
+        }
+    }
+}
+
+/// An opcode only understood by the interpreters extending CHIP-8
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Extension {
+    /// the first platform to understand the opcode
+    pub platform: Platform,
+    pub mnemonic: &'static str,
+
+    /// in bytes, XO-CHIP's long I load is followed by a 16 bit address
+    pub length: usize,
+}
+
+impl Extension {
+    pub fn parse_code(code: u16) -> Option<Extension> {
+        let extension = |platform, mnemonic| Some(Extension {
+            platform,
+            mnemonic,
+            length: 2,
+        });
+
+        match ((code & 0xF000) >> 12, (code & 0x0F00) >> 8, (code & 0x00F0) >> 4, code & 0x000F) {
+            (0x0, 0x0, 0xC, _) => extension(Platform::SuperChip, "SCD"),
+            (0x0, 0x0, 0xF, 0xB) => extension(Platform::SuperChip, "SCR"),
+            (0x0, 0x0, 0xF, 0xC) => extension(Platform::SuperChip, "SCL"),
+            (0x0, 0x0, 0xF, 0xD) => extension(Platform::SuperChip, "EXIT"),
+            (0x0, 0x0, 0xF, 0xE) => extension(Platform::SuperChip, "LOW"),
+            (0x0, 0x0, 0xF, 0xF) => extension(Platform::SuperChip, "HIGH"),
+            (0xD, _, _, 0x0) => extension(Platform::SuperChip, "DRW16"),
+            (0xF, _, 0x3, 0x0) => extension(Platform::SuperChip, "HFONT"),
+            (0xF, _, 0x7, 0x5) => extension(Platform::SuperChip, "SFLAGS"),
+            (0xF, _, 0x8, 0x5) => extension(Platform::SuperChip, "LFLAGS"),
+            (0x0, 0x0, 0xD, _) => extension(Platform::XoChip, "SCU"),
+            (0x5, _, _, 0x2) => extension(Platform::XoChip, "STRXY"),
+            (0x5, _, _, 0x3) => extension(Platform::XoChip, "LDRXY"),
+            (0xF, 0x0, 0x0, 0x0) => Some(Extension {
+                platform: Platform::XoChip,
+                mnemonic: "MOVIL",
+                length: 4,
+            }),
+            (0xF, _, 0x0, 0x1) => extension(Platform::XoChip, "PLANE"),
+            (0xF, 0x0, 0x0, 0x2) => extension(Platform::XoChip, "AUDIO"),
+            (0xF, _, 0x3, 0xA) => extension(Platform::XoChip, "PITCH"),
+            _ => None,
+        }
+    }
+}
+
 fn match_nibbles(nibbles: &[u8; 4]) -> Option<Instruction> {
     let rev = [nibbles[3], nibbles[2], nibbles[1], nibbles[0]];
     match rev {
         [ 0x00, 0x00, 0x0E, 0x00 ] => Some(Instruction::CLS),
+        [ 0x00, 0x02, 0x03, 0x00 ] => Some(Instruction::HCLS),
         [ 0x00, 0x00, 0x0E, 0x0E ] => Some(Instruction::RTS),
         [ 0x01, high, middle, low ] => Some(Instruction::JMP {
             address: three_nibbles(high, middle, low)
@@ -184,14 +310,14 @@ fn match_nibbles(nibbles: &[u8; 4]) -> Option<Instruction> {
         [ 0x08, x, y, 5 ] => Some(Instruction::SUBXY {
             x_reg_id: x, y_reg_id: y,
         }),
-        [ 0x08, x, 0x00, 0x06 ] => Some(Instruction::SHR {
-            reg_id: x
+        [ 0x08, x, y, 0x06 ] => Some(Instruction::SHR {
+            x_reg_id: x, y_reg_id: y
         }),
         [ 0x08, x, y, 0x07 ] => Some(Instruction::RSUBXY {
             x_reg_id: x, y_reg_id: y
         }),
-        [ 0x08, x, 0, 0x0E ] => Some(Instruction::SHL {
-            reg_id: x
+        [ 0x08, x, y, 0x0E ] => Some(Instruction::SHL {
+            x_reg_id: x, y_reg_id: y
         }),
         [ 0x09, x, y, 0x00 ] => Some(Instruction::SNEXY {
             x_reg_id: x, y_reg_id: y
@@ -0,0 +1,53 @@
+use std::os::raw::{c_int, c_void};
+use std::ptr;
+
+const PROT_READ: c_int = 0x1;
+const PROT_WRITE: c_int = 0x2;
+const PROT_EXEC: c_int = 0x4;
+const MAP_PRIVATE: c_int = 0x02;
+const MAP_ANONYMOUS: c_int = 0x20;
+
+extern "C" {
+    fn mmap(addr: *mut c_void, length: usize, prot: c_int, flags: c_int, fd: c_int, offset: i64) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, length: usize, prot: c_int) -> c_int;
+    fn munmap(addr: *mut c_void, length: usize) -> c_int;
+}
+
+/// ExecutableBuffer
+///
+/// Machine code copied into pages of its own, which are never writable and executable at once
+pub struct ExecutableBuffer {
+    pointer: *mut c_void,
+    length: usize,
+}
+
+impl ExecutableBuffer {
+    /// None when the pages can't be mapped
+    pub fn new(code: &[u8]) -> Option<ExecutableBuffer> {
+        let length = code.len().max(1);
+        unsafe {
+            let pointer = mmap(ptr::null_mut(), length, PROT_READ | PROT_WRITE, MAP_PRIVATE | MAP_ANONYMOUS, -1, 0);
+            if pointer as isize == -1 {
+                return None;
+            }
+            ptr::copy_nonoverlapping(code.as_ptr(), pointer as *mut u8, code.len());
+            if mprotect(pointer, length, PROT_READ | PROT_EXEC) != 0 {
+                munmap(pointer, length);
+                return None;
+            }
+            Some(ExecutableBuffer { pointer, length })
+        }
+    }
+
+    pub fn as_ptr(&self) -> *const u8 {
+        self.pointer as *const u8
+    }
+}
+
+impl Drop for ExecutableBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.pointer, self.length);
+        }
+    }
+}
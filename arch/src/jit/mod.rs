@@ -0,0 +1,353 @@
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+compile_error!("The jit feature needs x86-64 Linux");
+
+use std::mem;
+
+use {Address, Byte};
+use config::Quirks;
+use instructions::Instruction;
+use instructions::Instruction::*;
+use memory::{Memory, FONT_OFFSET, MEMORY_SIZE};
+use registers::Registers;
+
+mod buffer;
+mod x64;
+
+use self::buffer::ExecutableBuffer;
+use self::x64::Assembler;
+
+/// The most instructions compiled into one block
+pub const MAX_BLOCK_LENGTH: usize = 32;
+
+/// The registers a block works on, laid out for the generated code
+#[repr(C)]
+struct State {
+    vs: [Byte; 16],
+    i: u16,
+    program_counter: u16,
+}
+
+type BlockFn = unsafe extern "sysv64" fn(*mut State) -> u32;
+
+/// Block
+///
+/// A run of instructions compiled to native code. It only touches registers, so it runs
+/// from start to the first jump, skip taken or instruction it can't handle, all of which end it.
+struct Block {
+    code: ExecutableBuffer,
+
+    /// address and code of each instruction, in order, for the cpu's history
+    trace: Vec<(Address, u16)>,
+}
+
+impl Block {
+    /// The address past the last instruction
+    fn end(&self, start: Address) -> usize {
+        start as usize + self.trace.len() * 2
+    }
+}
+
+/// Jit
+///
+/// Compiles blocks the first time execution reaches them. Anything else, memory and the display,
+/// the stack, keys and timers, is left to the interpreter, so blocks end right before it.
+/// A write to memory drops the blocks made from it.
+pub struct Jit {
+    quirks: Quirks,
+
+    /// by start address, None where no instruction could be compiled
+    blocks: Vec<Option<Option<Block>>>,
+}
+
+impl Jit {
+    /// Compiles for the quirks given, a new Jit is needed when they change
+    pub fn new(quirks: Quirks) -> Jit {
+        Jit {
+            quirks,
+            blocks: (0..MEMORY_SIZE).map(|_| None).collect(),
+        }
+    }
+
+    /// Drop the blocks overlapping the bytes from low to high, inclusive
+    pub fn invalidate(&mut self, low: usize, high: usize) {
+        let first = low.saturating_sub(MAX_BLOCK_LENGTH * 2 - 1);
+        for start in first..=high.min(MEMORY_SIZE - 1) {
+            let overlaps = match self.blocks[start] {
+                Some(Some(ref block)) => block.end(start as Address) > low,
+                Some(None) => start + 2 > low,
+                None => false,
+            };
+            if overlaps {
+                self.blocks[start] = None;
+            }
+        }
+    }
+
+    /// Run the block at the program counter, if it is no longer than budget.
+    /// Returns the instructions it executed as address and code, None to have the interpreter
+    /// execute the next one instead.
+    pub fn run(&mut self, memory: &Memory, registers: &mut Registers, budget: usize) -> Option<&[(Address, u16)]> {
+        let start = registers.program_counter as usize;
+        if start >= MEMORY_SIZE {
+            return None;
+        }
+        if self.blocks[start].is_none() {
+            self.blocks[start] = Some(compile(memory, start as Address, &self.quirks));
+        }
+        let block = match self.blocks[start] {
+            Some(Some(ref block)) if block.trace.len() <= budget => block,
+            _ => return None,
+        };
+
+        let mut state = State {
+            vs: registers.vs,
+            i: registers.i,
+            program_counter: registers.program_counter,
+        };
+        let executed = unsafe {
+            let entry: BlockFn = mem::transmute(block.code.as_ptr());
+            entry(&mut state)
+        };
+        registers.vs = state.vs;
+        registers.i = state.i;
+        registers.program_counter = state.program_counter;
+
+        Some(&block.trace[..executed as usize])
+    }
+}
+
+/// Compile the instructions from start on, None if the first can't be
+fn compile(memory: &Memory, start: Address, quirks: &Quirks) -> Option<Block> {
+    let mut assembler = Assembler::new();
+    let mut trace = Vec::new();
+    let mut address = start;
+
+    while trace.len() < MAX_BLOCK_LENGTH && address as usize + 1 < MEMORY_SIZE {
+        let code = (memory.read(address as usize) as u16) << 8 | memory.read(address as usize + 1) as u16;
+        let instruction = match Instruction::parse_code(code) {
+            Some(instruction) if emit(&mut assembler, instruction, address, trace.len() as u32, quirks) => instruction,
+            _ => break,
+        };
+        trace.push((address, code));
+        address += 2;
+
+        if let JMP { .. } = instruction {
+            return finish(assembler, trace);
+        }
+    }
+
+    assembler.exit(address, trace.len() as u32);
+    finish(assembler, trace)
+}
+
+fn finish(assembler: Assembler, trace: Vec<(Address, u16)>) -> Option<Block> {
+    if trace.is_empty() {
+        return None;
+    }
+    let code = ExecutableBuffer::new(&assembler.finish())?;
+    Some(Block { code, trace })
+}
+
+/// Emit an instruction exactly as execute_instruction runs it, false if it can't be compiled.
+/// executed is how many instructions of the block come before it.
+fn emit(assembler: &mut Assembler, instruction: Instruction, address: Address, executed: u32, quirks: &Quirks) -> bool {
+    let vf_reset = |assembler: &mut Assembler| if quirks.vf_reset {
+        assembler.set(0x0F, 0);
+    };
+    let shift_source = |x_reg_id: Byte, y_reg_id: Byte| if quirks.shift_ignores_vy { x_reg_id } else { y_reg_id };
+
+    match instruction {
+        JMP { address } => assembler.exit(address, executed + 1),
+        SE { reg_id, value } => {
+            assembler.compare(reg_id, value);
+            assembler.exit_if(true, address + 4, executed + 1);
+        },
+        SNE { reg_id, value } => {
+            assembler.compare(reg_id, value);
+            assembler.exit_if(false, address + 4, executed + 1);
+        },
+        SEXY { x_reg_id, y_reg_id } => {
+            assembler.compare_registers(x_reg_id, y_reg_id);
+            assembler.exit_if(true, address + 4, executed + 1);
+        },
+        SNEXY { x_reg_id, y_reg_id } => {
+            assembler.compare_registers(x_reg_id, y_reg_id);
+            assembler.exit_if(false, address + 4, executed + 1);
+        },
+        MOV { reg_id, value } => assembler.set(reg_id, value),
+        ADD { reg_id, value } => assembler.add(reg_id, value),
+        MOVXY { x_reg_id, y_reg_id } => {
+            assembler.load_al(y_reg_id);
+            assembler.store_al(x_reg_id);
+        },
+        ORXY { x_reg_id, y_reg_id } => {
+            assembler.load_al(y_reg_id);
+            assembler.or_al(x_reg_id);
+            vf_reset(assembler);
+        },
+        ANDXY { x_reg_id, y_reg_id } => {
+            assembler.load_al(y_reg_id);
+            assembler.and_al(x_reg_id);
+            vf_reset(assembler);
+        },
+        XORXY { x_reg_id, y_reg_id } => {
+            assembler.load_al(y_reg_id);
+            assembler.xor_al(x_reg_id);
+            vf_reset(assembler);
+        },
+        ADDXY { x_reg_id, y_reg_id } => {
+            assembler.load_al(x_reg_id);
+            assembler.add_al(y_reg_id);
+            assembler.store_al(x_reg_id);
+            assembler.store_carry_to_vf();
+        },
+        SUBXY { x_reg_id, y_reg_id } => {
+            assembler.load_al(x_reg_id);
+            assembler.sub_al(y_reg_id);
+            assembler.store_al(x_reg_id);
+            assembler.store_no_borrow_to_vf();
+        },
+        RSUBXY { x_reg_id, y_reg_id } => {
+            assembler.load_al(y_reg_id);
+            assembler.sub_al(x_reg_id);
+            assembler.store_al(x_reg_id);
+            assembler.store_no_borrow_to_vf();
+        },
+        SHR { x_reg_id, y_reg_id } => {
+            assembler.load_al(shift_source(x_reg_id, y_reg_id));
+            assembler.shift_right_al();
+            assembler.store_al(x_reg_id);
+            assembler.store_cl(0x0F);
+        },
+        SHL { x_reg_id, y_reg_id } => {
+            assembler.load_al(shift_source(x_reg_id, y_reg_id));
+            assembler.shift_left_al();
+            assembler.store_al(x_reg_id);
+            assembler.store_cl(0x0F);
+        },
+        MOVI { address } => assembler.set_i(address),
+        ADI { reg_id } => assembler.add_i(reg_id),
+        FONT { reg_id } => assembler.set_i_to_glyph(reg_id, FONT_OFFSET as u16),
+        _ => return false,
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use Byte;
+    use config::Config;
+    use cpu::Cpu;
+
+    /// Run count instructions with and without the jit, which must leave the same trace behind
+    fn compare(rom: &[Byte], config: Config, count: usize) -> Cpu {
+        let mut interpreted = Cpu::with_config(rom, config.clone()).unwrap();
+        interpreted.run(count).unwrap();
+
+        let mut compiled = Cpu::with_config(rom, config).unwrap();
+        compiled.enable_jit();
+        compiled.run(count).unwrap();
+
+        assert_eq!(compiled.history(), interpreted.history());
+        assert_eq!(compiled.get_registers(), interpreted.get_registers());
+        assert_eq!(&compiled.get_memory().data()[..], &interpreted.get_memory().data()[..]);
+        assert_eq!(compiled.cycles(), interpreted.cycles());
+        compiled
+    }
+
+    #[test]
+    fn arithmetic_into_vf() {
+        let rom = [
+            0x6F, 0xF0, // MOV VF, F0
+            0x61, 0x20, // MOV V1, 20
+            0x8F, 0x14, // ADDXY VF, V1
+            0x6F, 0x10, // MOV VF, 10
+            0x8F, 0x15, // SUBXY VF, V1
+            0x6F, 0x10, // MOV VF, 10
+            0x8F, 0x17, // RSUBXY VF, V1
+            0x8F, 0x11, // ORXY VF, V1
+            0x8F, 0x12, // ANDXY VF, V1
+            0x8F, 0x13, // XORXY VF, V1
+            0x8F, 0x10, // MOVXY VF, V1
+            0x82, 0x14, // ADDXY V2, V1
+            0x83, 0x25, // SUBXY V3, V2
+            0x84, 0x37, // RSUBXY V4, V3
+            0x71, 0x37, // ADD V1, 37
+            0x12, 0x00, // JMP 200
+        ];
+        compare(&rom, Config::default(), 500);
+    }
+
+    #[test]
+    fn shifts_with_both_quirks() {
+        let rom = [
+            0x61, 0x81, // MOV V1, 81
+            0x62, 0x03, // MOV V2, 03
+            0x81, 0x26, // SHR V1, V2
+            0x83, 0x1E, // SHL V3, V1
+            0x8F, 0x16, // SHR VF, V1
+            0x8F, 0x1E, // SHL VF, V1
+            0x84, 0xF6, // SHR V4, VF
+            0x72, 0x45, // ADD V2, 45
+            0x12, 0x04, // JMP 204
+        ];
+        for &shift_ignores_vy in [false, true].iter() {
+            let mut config = Config::default();
+            config.quirks.shift_ignores_vy = shift_ignores_vy;
+            compare(&rom, config, 500);
+        }
+    }
+
+    #[test]
+    fn index_register() {
+        let rom = [
+            0xAF, 0xF0, // MOVI FF0
+            0x60, 0x20, // MOV V0, 20
+            0xF0, 0x1E, // ADI V0, past the end of memory
+            0xF0, 0x1E, // ADI V0
+            0x61, 0x1B, // MOV V1, 1B
+            0xF1, 0x29, // FONT V1, only the low nibble counts
+            0x70, 0x70, // ADD V0, 70
+            0x12, 0x04, // JMP 204
+        ];
+        let cpu = compare(&rom, Config::default(), 7);
+        assert_eq!(cpu.get_registers().i, 0x0B * 5);
+        compare(&rom, Config::default(), 500);
+    }
+
+    #[test]
+    fn skips_leave_blocks() {
+        let rom = [
+            0x70, 0x01, // 200: ADD V0, 01
+            0x30, 0x05, // 202: SE V0, 05
+            0x12, 0x00, // 204: JMP 200
+            0x40, 0x06, // 206: SNE V0, 06
+            0x00, 0x00, // 208:
+            0x50, 0x20, // 20A: SEXY V0, V2
+            0x90, 0x20, // 20C: SNEXY V0, V2
+            0x00, 0x00, // 20E:
+            0x60, 0x00, // 210: MOV V0, 00
+            0x71, 0x05, // 212: ADD V1, 05
+            0x50, 0x10, // 214: SEXY V0, V1
+            0x12, 0x00, // 216: JMP 200
+            0x12, 0x1A, // 218: JMP 218, once V1 wraps to 0
+            0x12, 0x1A, // 21A: JMP 21A
+        ];
+        compare(&rom, Config::default(), 2000);
+    }
+
+    #[test]
+    fn write_into_compiled_block() {
+        let rom = [
+            0x71, 0x01, // 200: ADD V1, 01, becomes ADD V3, 01
+            0x72, 0x01, // 202: ADD V2, 01
+            0xA2, 0x00, // 204: MOVI 200
+            0x60, 0x73, // 206: MOV V0, 73
+            0xF0, 0x55, // 208: STR V0, interpreted
+            0x12, 0x00, // 20A: JMP 200
+        ];
+        let cpu = compare(&rom, Config::default(), 60);
+        assert_eq!(cpu.get_registers().vs[1], 1);
+        assert_eq!(cpu.get_registers().vs[3], 9);
+    }
+}
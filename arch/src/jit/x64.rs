@@ -0,0 +1,153 @@
+use {Address, Byte};
+
+/// Offsets into the State a block runs on, which it gets in rdi
+const VF: Byte = 0x0F;
+const I: Byte = 16;
+const PROGRAM_COUNTER: Byte = 18;
+
+/// The length of exit, for jumping over it
+const EXIT_LENGTH: Byte = 12;
+
+/// Assembler
+///
+/// Emits the few x86-64 instructions blocks are made of. Everything works on memory relative to rdi,
+/// Vx at offset x, with al and cl as scratch registers.
+pub struct Assembler {
+    code: Vec<u8>,
+}
+
+impl Assembler {
+    pub fn new() -> Assembler {
+        Assembler { code: Vec::new() }
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.code
+    }
+
+    fn emit(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+
+    /// mov byte [rdi+reg], value
+    pub fn set(&mut self, reg_id: Byte, value: Byte) {
+        self.emit(&[0xC6, 0x47, reg_id, value]);
+    }
+
+    /// add byte [rdi+reg], value
+    pub fn add(&mut self, reg_id: Byte, value: Byte) {
+        self.emit(&[0x80, 0x47, reg_id, value]);
+    }
+
+    /// mov al, [rdi+reg]
+    pub fn load_al(&mut self, reg_id: Byte) {
+        self.emit(&[0x8A, 0x47, reg_id]);
+    }
+
+    /// mov [rdi+reg], al
+    pub fn store_al(&mut self, reg_id: Byte) {
+        self.emit(&[0x88, 0x47, reg_id]);
+    }
+
+    /// mov [rdi+reg], cl
+    pub fn store_cl(&mut self, reg_id: Byte) {
+        self.emit(&[0x88, 0x4F, reg_id]);
+    }
+
+    /// or, and or xor [rdi+reg], al
+    pub fn or_al(&mut self, reg_id: Byte) {
+        self.emit(&[0x08, 0x47, reg_id]);
+    }
+
+    pub fn and_al(&mut self, reg_id: Byte) {
+        self.emit(&[0x20, 0x47, reg_id]);
+    }
+
+    pub fn xor_al(&mut self, reg_id: Byte) {
+        self.emit(&[0x30, 0x47, reg_id]);
+    }
+
+    /// add al, [rdi+reg]
+    pub fn add_al(&mut self, reg_id: Byte) {
+        self.emit(&[0x02, 0x47, reg_id]);
+    }
+
+    /// sub al, [rdi+reg]
+    pub fn sub_al(&mut self, reg_id: Byte) {
+        self.emit(&[0x2A, 0x47, reg_id]);
+    }
+
+    /// VF = 1 when the last add carried, 0 otherwise. Stores in between leave the flags alone.
+    pub fn store_carry_to_vf(&mut self) {
+        // setc byte [rdi+VF]
+        self.emit(&[0x0F, 0x92, 0x47, VF]);
+    }
+
+    /// VF = 1 when the last sub didn't borrow, 0 otherwise
+    pub fn store_no_borrow_to_vf(&mut self) {
+        // setnc byte [rdi+VF]
+        self.emit(&[0x0F, 0x93, 0x47, VF]);
+    }
+
+    /// al >>= 1, cl = the bit shifted out
+    pub fn shift_right_al(&mut self) {
+        // mov cl, al; shr al, 1; and cl, 1
+        self.emit(&[0x88, 0xC1, 0xD0, 0xE8, 0x80, 0xE1, 0x01]);
+    }
+
+    /// al <<= 1, cl = the bit shifted out
+    pub fn shift_left_al(&mut self) {
+        // mov cl, al; shl al, 1; shr cl, 7
+        self.emit(&[0x88, 0xC1, 0xD0, 0xE0, 0xC0, 0xE9, 0x07]);
+    }
+
+    /// mov word [rdi+I], address
+    pub fn set_i(&mut self, address: Address) {
+        self.emit(&[0x66, 0xC7, 0x47, I]);
+        self.emit(&address.to_le_bytes());
+    }
+
+    /// add word [rdi+I], Vx
+    pub fn add_i(&mut self, reg_id: Byte) {
+        // movzx eax, byte [rdi+reg]; add [rdi+I], ax
+        self.emit(&[0x0F, 0xB6, 0x47, reg_id, 0x66, 0x01, 0x47, I]);
+    }
+
+    /// I = offset + (Vx & 0xF) * 5
+    pub fn set_i_to_glyph(&mut self, reg_id: Byte, offset: u16) {
+        // movzx eax, byte [rdi+reg]; and eax, 0xF; lea eax, [rax+rax*4]
+        self.emit(&[0x0F, 0xB6, 0x47, reg_id, 0x83, 0xE0, 0x0F, 0x8D, 0x04, 0x80]);
+        // add eax, offset; mov [rdi+I], ax
+        self.emit(&[0x05]);
+        self.emit(&(offset as u32).to_le_bytes());
+        self.emit(&[0x66, 0x89, 0x47, I]);
+    }
+
+    /// cmp byte [rdi+reg], value
+    pub fn compare(&mut self, reg_id: Byte, value: Byte) {
+        self.emit(&[0x80, 0x7F, reg_id, value]);
+    }
+
+    /// mov al, [rdi+x]; cmp al, [rdi+y]
+    pub fn compare_registers(&mut self, x_reg_id: Byte, y_reg_id: Byte) {
+        self.load_al(x_reg_id);
+        self.emit(&[0x3A, 0x47, y_reg_id]);
+    }
+
+    /// Leave the block when the last comparison was equal, or not equal
+    pub fn exit_if(&mut self, equal: bool, program_counter: Address, executed: u32) {
+        // jne or je over the exit
+        self.emit(&[if equal { 0x75 } else { 0x74 }, EXIT_LENGTH]);
+        self.exit(program_counter, executed);
+    }
+
+    /// Leave the block, continuing at program_counter after executing that many instructions
+    pub fn exit(&mut self, program_counter: Address, executed: u32) {
+        // mov word [rdi+PC], program_counter; mov eax, executed; ret
+        self.emit(&[0x66, 0xC7, 0x47, PROGRAM_COUNTER]);
+        self.emit(&program_counter.to_le_bytes());
+        self.emit(&[0xB8]);
+        self.emit(&executed.to_le_bytes());
+        self.emit(&[0xC3]);
+    }
+}
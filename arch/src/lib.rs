@@ -1,19 +1,53 @@
 extern crate rand;
+extern crate sha1_smol;
+extern crate gif;
+extern crate flate2;
+extern crate zip;
+#[macro_use]
+extern crate serde_json;
+extern crate crc32fast;
 
 pub type Address = u16;
 pub type Byte = u8;
 
+/// Where a display is drawn to, see Display::render
 pub trait Renderer {
+    /// The display switched to another resolution, everything is redrawn after
+    fn resize(&mut self, _width: usize, _height: usize) {}
+
     fn pixel(&mut self, x: usize, y: usize, is_set: bool);
+
+    /// The changed pixels of a frame were all sent
     fn flush(&mut self);
 }
 
-mod memory;
-mod registers;
-mod instructions;
 mod keyboard;
 
 mod executions;
 
+pub mod instructions;
+pub mod config;
+pub mod hashes;
+pub mod database;
+pub mod container;
+pub mod patch;
+pub mod analysis;
+pub mod registers;
+pub mod stack;
+pub mod timing;
+pub mod memory;
+pub mod decode_cache;
+#[cfg(feature = "jit")]
+pub mod jit;
+pub mod loader;
 pub mod display;
-pub mod cpu;
\ No newline at end of file
+pub mod cheats;
+pub mod png;
+pub mod effects;
+pub mod sanitizer;
+pub mod profiler;
+pub mod coverage;
+pub mod state;
+pub mod crash;
+pub mod cpu;
+pub mod vip;
\ No newline at end of file
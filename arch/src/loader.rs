@@ -0,0 +1,264 @@
+use std::fmt;
+
+use {Address, Byte};
+use memory::{Memory, FONT_OFFSET, FONT_SIZE, MEMORY_SIZE, PROGRAM_OFFSET};
+
+/// What a region of memory was loaded with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RegionKind {
+    Font,
+    Program,
+
+    /// an extra segment of data, numbered in the order it was added to the loader
+    Segment(usize),
+}
+
+impl fmt::Display for RegionKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RegionKind::Font => write!(f, "font"),
+            RegionKind::Program => write!(f, "program"),
+            RegionKind::Segment(index) => write!(f, "segment {}", index),
+        }
+    }
+}
+
+/// A contiguous range of loaded memory
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Region {
+    pub kind: RegionKind,
+    pub start: Address,
+    pub length: usize,
+}
+
+impl Region {
+    /// One past the last address of the region
+    pub fn end(&self) -> usize {
+        self.start as usize + self.length
+    }
+
+    pub fn contains(&self, address: Address) -> bool {
+        address >= self.start && (address as usize) < self.end()
+    }
+
+    fn overlaps(&self, other: &Region) -> bool {
+        (self.start as usize) < other.end() && (other.start as usize) < self.end()
+    }
+}
+
+/// MemoryMap
+///
+/// Describes what was loaded where, ordered by address
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MemoryMap {
+    pub regions: Vec<Region>,
+}
+
+impl MemoryMap {
+    /// The region an address was loaded as part of, if any
+    pub fn region_of(&self, address: Address) -> Option<&Region> {
+        self.regions.iter().find(|region| region.contains(address))
+    }
+}
+
+impl fmt::Display for MemoryMap {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for region in self.regions.iter() {
+            let last = region.end().saturating_sub(1).max(region.start as usize);
+            writeln!(f, "{:03X}-{:03X} {:>5} bytes  {}", region.start, last, region.length, region.kind)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum LoadError {
+    /// the region doesn't fit in memory
+    TooLarge { kind: RegionKind, start: Address, length: usize, available: usize },
+
+    /// two regions were asked to occupy the same memory
+    Overlap { first: Region, second: Region },
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::TooLarge { kind, start, length, available } => write!(
+                f, "The {} at {:03X} is {} bytes long, but only {} bytes are available", kind, start, length, available
+            ),
+            LoadError::Overlap { first, second } => write!(
+                f, "The {} at {:03X} overlaps the {} at {:03X}", second.kind, second.start, first.kind, first.start
+            ),
+        }
+    }
+}
+
+impl ::std::error::Error for LoadError {}
+
+/// Loader
+///
+/// Places a program, and optionally extra data segments, in memory.
+/// Everything is checked to fit in memory without overlapping anything else, including the font.
+pub struct Loader<'a> {
+    program: &'a [Byte],
+    base: Address,
+    entry: Address,
+    segments: Vec<(Address, &'a [Byte])>,
+}
+
+impl<'a> Loader<'a> {
+    /// Load a program at the usual program offset
+    pub fn new(program: &'a [Byte]) -> Loader<'a> {
+        Loader {
+            program,
+            base: PROGRAM_OFFSET as Address,
+            entry: PROGRAM_OFFSET as Address,
+            segments: Vec::new(),
+        }
+    }
+
+    /// Load the program at a different address, which is also where execution starts
+    pub fn at(mut self, base: Address) -> Loader<'a> {
+        self.base = base;
+        self.entry = base;
+        self
+    }
+
+    /// Start execution at an address other than where the program is loaded, past a preamble
+    pub fn entry_at(mut self, entry: Address) -> Loader<'a> {
+        self.entry = entry;
+        self
+    }
+
+    /// Place extra data at an address
+    pub fn with_segment(mut self, address: Address, data: &'a [Byte]) -> Loader<'a> {
+        self.segments.push((address, data));
+        self
+    }
+
+    /// The address the program is loaded at
+    pub fn base(&self) -> Address {
+        self.base
+    }
+
+    /// The address execution starts at
+    pub fn entry(&self) -> Address {
+        self.entry
+    }
+
+    pub fn load(&self) -> Result<(Memory, MemoryMap), LoadError> {
+        let mut memory = Memory::new();
+        let mut map = MemoryMap {
+            regions: vec![Region {
+                kind: RegionKind::Font,
+                start: FONT_OFFSET as Address,
+                length: FONT_SIZE,
+            }],
+        };
+
+        let placements = Some((RegionKind::Program, self.base, self.program)).into_iter()
+            .chain(self.segments.iter().enumerate().map(|(index, &(address, data))| {
+                (RegionKind::Segment(index), address, data)
+            }));
+
+        for (kind, start, data) in placements {
+            let available = MEMORY_SIZE.saturating_sub(start as usize);
+            if data.len() > available {
+                return Err(LoadError::TooLarge {
+                    kind,
+                    start,
+                    length: data.len(),
+                    available,
+                });
+            }
+
+            let region = Region {
+                kind,
+                start,
+                length: data.len(),
+            };
+            if let Some(first) = map.regions.iter().find(|other| other.overlaps(&region)) {
+                return Err(LoadError::Overlap {
+                    first: *first,
+                    second: region,
+                });
+            }
+
+            for (offset, &value) in data.iter().enumerate() {
+                memory.write(region.start as usize + offset, value);
+            }
+            map.regions.push(region);
+        }
+
+        map.regions.sort_by_key(|region| region.start);
+
+        Ok((memory, map))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::Cpu;
+
+    #[test]
+    fn oversized_rom() {
+        let available = MEMORY_SIZE - PROGRAM_OFFSET;
+        assert!(Cpu::new(&vec![0; available]).is_ok());
+        assert_eq!(Cpu::new(&vec![0; available + 1]).err(), Some(LoadError::TooLarge {
+            kind: RegionKind::Program,
+            start: PROGRAM_OFFSET as Address,
+            length: available + 1,
+            available,
+        }));
+    }
+
+    #[test]
+    fn overlapping_segment() {
+        let program = [0x12, 0x00, 0x00, 0x00];
+        assert_eq!(Loader::new(&program).with_segment(0x203, &[0xFF]).load().err(), Some(LoadError::Overlap {
+            first: Region { kind: RegionKind::Program, start: 0x200, length: 4 },
+            second: Region { kind: RegionKind::Segment(0), start: 0x203, length: 1 },
+        }));
+        assert_eq!(Loader::new(&program).with_segment(FONT_OFFSET as Address, &[0xFF]).load().err(), Some(LoadError::Overlap {
+            first: Region { kind: RegionKind::Font, start: FONT_OFFSET as Address, length: FONT_SIZE },
+            second: Region { kind: RegionKind::Segment(0), start: FONT_OFFSET as Address, length: 1 },
+        }));
+        assert!(Loader::new(&program).with_segment(0x204, &[0xFF]).load().is_ok());
+    }
+
+    #[test]
+    fn segment_past_memory() {
+        let program = [0x12, 0x00];
+        assert_eq!(Loader::new(&program).with_segment(0xFFE, &[1, 2, 3]).load().err(), Some(LoadError::TooLarge {
+            kind: RegionKind::Segment(0),
+            start: 0xFFE,
+            length: 3,
+            available: 2,
+        }));
+        assert_eq!(Loader::new(&program).with_segment(0x1000, &[1]).load().err(), Some(LoadError::TooLarge {
+            kind: RegionKind::Segment(0),
+            start: 0x1000,
+            length: 1,
+            available: 0,
+        }));
+    }
+
+    #[test]
+    fn custom_base() {
+        let program = [0x16, 0x00, 0xAB];
+        let loader = Loader::new(&program).at(0x600).with_segment(0x300, &[0xCD]);
+        assert_eq!((loader.base(), loader.entry()), (0x600, 0x600));
+
+        let (memory, map) = loader.load().unwrap();
+        assert_eq!(&memory.data()[0x600..0x603], &program);
+        assert_eq!(memory.data()[0x300], 0xCD);
+        assert_eq!(memory.data()[0x200], 0x00);
+        let kinds: Vec<RegionKind> = map.regions.iter().map(|region| region.kind).collect();
+        assert_eq!(kinds, vec![RegionKind::Font, RegionKind::Segment(0), RegionKind::Program]);
+        assert_eq!(map.region_of(0x602).map(|region| region.kind), Some(RegionKind::Program));
+        assert_eq!(map.region_of(0x603), None);
+
+        assert_eq!(Loader::new(&program).at(0x600).entry_at(0x602).entry(), 0x602);
+    }
+}
@@ -4,9 +4,14 @@ use {Byte};
 pub const FONT_OFFSET: usize = 0;
 pub const PROGRAM_OFFSET: usize = 0x200;
 
-const MEMORY_SIZE: usize = 4096;
+/// Hi-res programs start with a preamble patching the interpreter, execution starts after it
+pub const HIRES_ENTRY_POINT: usize = 0x2C0;
+pub const FONT_HEIGHT: usize = 5;
+pub const FONT_SIZE: usize = FONT_HEIGHT * 16;
 
-const FONT: [Byte; 5 * 16] = [
+pub const MEMORY_SIZE: usize = 4096;
+
+const FONT: [Byte; FONT_SIZE] = [
     // 0 
     0xF0, 0x90, 0x90, 0x90, 0xF0,
     // 1 
@@ -46,26 +51,56 @@ const FONT: [Byte; 5 * 16] = [
 /// x000 to x1FF is mostly reserved for the interperter
 /// x200 is where most programs start
 pub struct Memory {
-    pub data: [Byte; MEMORY_SIZE]
+    data: [Byte; MEMORY_SIZE],
+
+    /// lowest and highest address written since the last take_written
+    written: Option<(usize, usize)>,
 }
 
 impl Memory {
-    pub fn new(program_data: &Vec<Byte>) -> Memory {
+    /// Memory holding nothing but the font
+    pub fn new() -> Memory {
 
         let mut data = [0; MEMORY_SIZE];
 
         // Set font values in our data space
         for (index, value) in FONT.iter().enumerate() {
-            data[index] = *value;
-        }
-
-        // Set program data in program space
-        for (index, value) in program_data.iter().take(MEMORY_SIZE).enumerate() {
-            data[0x200 + index] = *value;
+            data[FONT_OFFSET + index] = *value;
         }
 
         Memory {
-            data
+            data,
+            written: None,
         }
     }
+
+    /// Everything in memory, read only so every change goes through write
+    pub fn data(&self) -> &[Byte; MEMORY_SIZE] {
+        &self.data
+    }
+
+    /// Addresses past the end of memory wrap around, I can point anywhere within its 16 bits
+    pub fn read(&self, address: usize) -> Byte {
+        self.data[address % MEMORY_SIZE]
+    }
+
+    pub fn write(&mut self, address: usize, value: Byte) {
+        let address = address % MEMORY_SIZE;
+        self.data[address] = value;
+        self.written = Some(match self.written {
+            Some((low, high)) => (low.min(address), high.max(address)),
+            None => (address, address),
+        });
+    }
+
+    /// The lowest and highest address written since the last call, if any
+    pub fn take_written(&mut self) -> Option<(usize, usize)> {
+        self.written.take()
+    }
+}
+
+impl Default for Memory {
+    fn default() -> Memory {
+        Memory::new()
+    }
 }
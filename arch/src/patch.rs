@@ -0,0 +1,449 @@
+use std::fmt;
+
+use crc32fast;
+
+use {Byte};
+use memory::MEMORY_SIZE;
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const IPS_MAX_RECORD: usize = 0xFFFF;
+
+const BPS_MAGIC: &[u8] = b"BPS1";
+const BPS_FOOTER_SIZE: usize = 12;
+
+/// The patch formats ROM hacks are shared in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PatchFormat {
+    Ips,
+    Bps,
+}
+
+impl PatchFormat {
+    pub fn detect(patch: &[Byte]) -> Option<PatchFormat> {
+        if patch.starts_with(IPS_MAGIC) {
+            Some(PatchFormat::Ips)
+        } else if patch.starts_with(BPS_MAGIC) {
+            Some(PatchFormat::Bps)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PatchError {
+    UnknownFormat,
+    Truncated,
+
+    /// a BPS patch was made for a different ROM
+    SourceMismatch { expected: u32, actual: u32 },
+
+    /// a BPS patch was made for a ROM of another size
+    SourceSizeMismatch { expected: usize, actual: usize },
+
+    /// applying a BPS patch didn't produce the ROM it was made from
+    TargetMismatch { expected: u32, actual: u32 },
+
+    /// the patch itself is damaged
+    PatchMismatch { expected: u32, actual: u32 },
+
+    /// a BPS action points outside the source or target
+    OutOfBounds,
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PatchError::UnknownFormat => write!(f, "Not an IPS or BPS patch"),
+            PatchError::Truncated => write!(f, "Patch is truncated"),
+            PatchError::SourceMismatch { expected, actual } => write!(f, "Patch expects a ROM with CRC32 {:08X}, got {:08X}", expected, actual),
+            PatchError::SourceSizeMismatch { expected, actual } => write!(f, "Patch expects a ROM of {} bytes, got {}", expected, actual),
+            PatchError::TargetMismatch { expected, actual } => write!(f, "Patched ROM should have CRC32 {:08X}, got {:08X}", expected, actual),
+            PatchError::PatchMismatch { expected, actual } => write!(f, "Patch should have CRC32 {:08X}, got {:08X}", expected, actual),
+            PatchError::OutOfBounds => write!(f, "Patch reads or writes out of bounds"),
+        }
+    }
+}
+
+impl ::std::error::Error for PatchError {}
+
+/// Apply a patch, detecting its format
+pub fn apply(rom: &[Byte], patch: &[Byte]) -> Result<Vec<Byte>, PatchError> {
+    match PatchFormat::detect(patch) {
+        Some(PatchFormat::Ips) => apply_ips(rom, patch),
+        Some(PatchFormat::Bps) => apply_bps(rom, patch),
+        None => Err(PatchError::UnknownFormat),
+    }
+}
+
+/// Apply patches one after the other, each to the result of the previous one
+pub fn apply_all<'a, I>(rom: &[Byte], patches: I) -> Result<Vec<Byte>, PatchError>
+    where I: IntoIterator<Item = &'a [Byte]> {
+        patches.into_iter().try_fold(rom.to_vec(), |rom, patch| apply(&rom, patch))
+    }
+
+/// Diff two ROMs into a patch of the given format
+pub fn create(format: PatchFormat, source: &[Byte], target: &[Byte]) -> Vec<Byte> {
+    match format {
+        PatchFormat::Ips => create_ips(source, target),
+        PatchFormat::Bps => create_bps(source, target),
+    }
+}
+
+/// Reads the fields of a patch, failing on truncation instead of panicking
+struct Reader<'a> {
+    data: &'a [Byte],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn new(data: &'a [Byte], offset: usize) -> Reader<'a> {
+        Reader {
+            data,
+            offset,
+        }
+    }
+
+    fn bytes(&mut self, count: usize) -> Result<&'a [Byte], PatchError> {
+        let bytes = self.data.get(self.offset..self.offset + count).ok_or(PatchError::Truncated)?;
+        self.offset += count;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<Byte, PatchError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn big_endian(&mut self, count: usize) -> Result<usize, PatchError> {
+        Ok(self.bytes(count)?.iter().fold(0, |value, byte| (value << 8) | *byte as usize))
+    }
+
+    /// BPS numbers, 7 bits per byte, the last byte marked by its high bit
+    fn number(&mut self) -> Result<usize, PatchError> {
+        let mut value = 0usize;
+        let mut shift = 1usize;
+        loop {
+            let byte = self.byte()?;
+            value = value.checked_add((byte & 0x7F) as usize * shift).ok_or(PatchError::OutOfBounds)?;
+            if byte & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift.checked_shl(7).ok_or(PatchError::OutOfBounds)?;
+            value = value.checked_add(shift).ok_or(PatchError::OutOfBounds)?;
+        }
+    }
+
+    /// BPS relative offsets, the lowest bit is the sign
+    fn signed_number(&mut self) -> Result<isize, PatchError> {
+        let value = self.number()?;
+        let magnitude = (value >> 1) as isize;
+        Ok(if value & 1 == 1 { -magnitude } else { magnitude })
+    }
+}
+
+fn apply_ips(rom: &[Byte], patch: &[Byte]) -> Result<Vec<Byte>, PatchError> {
+    let mut target = rom.to_vec();
+    let mut reader = Reader::new(patch, IPS_MAGIC.len());
+
+    loop {
+        let offset_bytes = reader.bytes(3)?;
+        if offset_bytes == IPS_EOF {
+            break;
+        }
+        let offset = offset_bytes.iter().fold(0, |value, byte| (value << 8) | *byte as usize);
+
+        let size = reader.big_endian(2)?;
+        let (size, run) = if size == 0 {
+            (reader.big_endian(2)?, Some(reader.byte()?))
+        } else {
+            (size, None)
+        };
+
+        if target.len() < offset + size {
+            target.resize(offset + size, 0);
+        }
+        match run {
+            Some(value) => target[offset..offset + size].iter_mut().for_each(|byte| *byte = value),
+            None => target[offset..offset + size].copy_from_slice(reader.bytes(size)?),
+        }
+    }
+
+    // Lunar IPS extension, the size to truncate the ROM to
+    if let Ok(size) = reader.big_endian(3) {
+        target.truncate(size);
+    }
+
+    Ok(target)
+}
+
+fn create_ips(source: &[Byte], target: &[Byte]) -> Vec<Byte> {
+    let mut patch = IPS_MAGIC.to_vec();
+
+    let mut offset = 0;
+    while offset < target.len() {
+        if source.get(offset) == Some(&target[offset]) {
+            offset += 1;
+            continue;
+        }
+
+        // an offset spelling EOF would end the patch early, start the record a byte before it
+        let start = if offset == 0x454F46 { offset - 1 } else { offset };
+
+        let mut end = offset;
+        while end < target.len() && end - start < IPS_MAX_RECORD && source.get(end) != Some(&target[end]) {
+            end += 1;
+        }
+
+        patch.extend_from_slice(&[(start >> 16) as u8, (start >> 8) as u8, start as u8]);
+        patch.extend_from_slice(&[((end - start) >> 8) as u8, (end - start) as u8]);
+        patch.extend_from_slice(&target[start..end]);
+        offset = end;
+    }
+
+    patch.extend_from_slice(IPS_EOF);
+
+    if target.len() < source.len() {
+        let size = target.len();
+        patch.extend_from_slice(&[(size >> 16) as u8, (size >> 8) as u8, size as u8]);
+    }
+
+    patch
+}
+
+fn crc32_at(data: &[Byte], offset: usize) -> u32 {
+    data[offset..offset + 4].iter().rev().fold(0, |value, byte| (value << 8) | *byte as u32)
+}
+
+fn apply_bps(rom: &[Byte], patch: &[Byte]) -> Result<Vec<Byte>, PatchError> {
+    if patch.len() < BPS_MAGIC.len() + BPS_FOOTER_SIZE {
+        return Err(PatchError::Truncated);
+    }
+    let footer = patch.len() - BPS_FOOTER_SIZE;
+
+    let expected = crc32_at(patch, footer + 8);
+    let actual = crc32fast::hash(&patch[..footer + 8]);
+    if expected != actual {
+        return Err(PatchError::PatchMismatch { expected, actual });
+    }
+
+    let expected = crc32_at(patch, footer);
+    let actual = crc32fast::hash(rom);
+    if expected != actual {
+        return Err(PatchError::SourceMismatch { expected, actual });
+    }
+
+    let mut reader = Reader::new(&patch[..footer], BPS_MAGIC.len());
+    let source_size = reader.number()?;
+    let target_size = reader.number()?;
+    let metadata_size = reader.number()?;
+    reader.bytes(metadata_size)?;
+
+    if source_size != rom.len() {
+        return Err(PatchError::SourceSizeMismatch { expected: source_size, actual: rom.len() });
+    }
+
+    // the size comes from the patch, no program needs more than memory holds up front
+    let mut target: Vec<Byte> = Vec::with_capacity(target_size.min(MEMORY_SIZE));
+    let mut source_offset = 0isize;
+    let mut target_offset = 0isize;
+
+    while reader.offset < footer {
+        let action = reader.number()?;
+        let length = (action >> 2) + 1;
+        if target.len() + length > target_size {
+            return Err(PatchError::OutOfBounds);
+        }
+
+        match action & 3 {
+            // SourceRead, copy from the same offset in the source
+            0 => {
+                let start = target.len();
+                target.extend_from_slice(rom.get(start..start + length).ok_or(PatchError::OutOfBounds)?);
+            },
+            // TargetRead, copy from the patch
+            1 => target.extend_from_slice(reader.bytes(length)?),
+            // SourceCopy, copy from anywhere in the source
+            2 => {
+                source_offset += reader.signed_number()?;
+                let start = source_offset as usize;
+                if source_offset < 0 || start + length > rom.len() {
+                    return Err(PatchError::OutOfBounds);
+                }
+                target.extend_from_slice(&rom[start..start + length]);
+                source_offset += length as isize;
+            },
+            // TargetCopy, copy from what was already written, byte by byte as the ranges may overlap
+            _ => {
+                target_offset += reader.signed_number()?;
+                if target_offset < 0 || target_offset as usize >= target.len() {
+                    return Err(PatchError::OutOfBounds);
+                }
+                for _ in 0..length {
+                    let byte = target[target_offset as usize];
+                    target.push(byte);
+                    target_offset += 1;
+                }
+            },
+        }
+    }
+
+    let expected = crc32_at(patch, footer + 4);
+    let actual = crc32fast::hash(&target);
+    if target.len() != target_size || expected != actual {
+        return Err(PatchError::TargetMismatch { expected, actual });
+    }
+
+    Ok(target)
+}
+
+fn write_number(patch: &mut Vec<Byte>, mut value: usize) {
+    loop {
+        let bits = (value & 0x7F) as Byte;
+        value >>= 7;
+        if value == 0 {
+            patch.push(0x80 | bits);
+            return;
+        }
+        patch.push(bits);
+        value -= 1;
+    }
+}
+
+/// Bytes matching the source are SourceRead, anything else is TargetRead
+fn create_bps(source: &[Byte], target: &[Byte]) -> Vec<Byte> {
+    let mut patch = BPS_MAGIC.to_vec();
+    write_number(&mut patch, source.len());
+    write_number(&mut patch, target.len());
+    write_number(&mut patch, 0);
+
+    let same = |offset: usize| source.get(offset) == Some(&target[offset]);
+
+    let mut offset = 0;
+    while offset < target.len() {
+        let matching = same(offset);
+        let mut end = offset + 1;
+        while end < target.len() && same(end) == matching {
+            end += 1;
+        }
+
+        let length = end - offset;
+        if matching {
+            write_number(&mut patch, (length - 1) << 2);
+        } else {
+            write_number(&mut patch, ((length - 1) << 2) | 1);
+            patch.extend_from_slice(&target[offset..end]);
+        }
+        offset = end;
+    }
+
+    patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+    patch.extend_from_slice(&crc32fast::hash(target).to_le_bytes());
+    let crc = crc32fast::hash(&patch);
+    patch.extend_from_slice(&crc.to_le_bytes());
+
+    patch
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A BPS patch of nothing but its header, with valid checksums
+    fn bps_header(source: &[Byte], source_size: usize, target_size: usize) -> Vec<Byte> {
+        let mut patch = BPS_MAGIC.to_vec();
+        write_number(&mut patch, source_size);
+        write_number(&mut patch, target_size);
+        write_number(&mut patch, 0);
+        patch.extend_from_slice(&crc32fast::hash(source).to_le_bytes());
+        patch.extend_from_slice(&crc32fast::hash(&[]).to_le_bytes());
+        let crc = crc32fast::hash(&patch);
+        patch.extend_from_slice(&crc.to_le_bytes());
+        patch
+    }
+
+    #[test]
+    fn bps_source_size() {
+        let rom = [0x00, 0xE0];
+        assert_eq!(
+            apply(&rom, &bps_header(&rom, 3, 0)),
+            Err(PatchError::SourceSizeMismatch { expected: 3, actual: 2 })
+        );
+    }
+
+    #[test]
+    fn bps_huge_target_size() {
+        let rom = [0x00, 0xE0];
+        match apply(&rom, &bps_header(&rom, 2, usize::MAX >> 8)) {
+            Err(PatchError::TargetMismatch { .. }) => {},
+            result => panic!("{:?}", result),
+        }
+    }
+
+    #[test]
+    fn bps_round_trip() {
+        let source = [0x00, 0xE0, 0x12, 0x00];
+        let target = [0x00, 0xE0, 0x60, 0x01, 0x12, 0x02];
+        assert_eq!(apply(&source, &create(PatchFormat::Bps, &source, &target)), Ok(target.to_vec()));
+    }
+
+    /// An IPS patch of the records given, each as offset and data
+    fn ips(records: &[&[Byte]]) -> Vec<Byte> {
+        let mut patch = IPS_MAGIC.to_vec();
+        records.iter().for_each(|record| patch.extend_from_slice(record));
+        patch.extend_from_slice(IPS_EOF);
+        patch
+    }
+
+    #[test]
+    fn ips_record() {
+        let patch = ips(&[&[0x00, 0x00, 0x02, 0x00, 0x02, 0xAA, 0xBB]]);
+        assert_eq!(apply(&[0, 1, 2, 3], &patch), Ok(vec![0, 1, 0xAA, 0xBB]));
+    }
+
+    #[test]
+    fn ips_run() {
+        let patch = ips(&[&[0x00, 0x00, 0x01, 0x00, 0x00, 0x00, 0x02, 0xFF]]);
+        assert_eq!(apply(&[0, 1, 2, 3], &patch), Ok(vec![0, 0xFF, 0xFF, 3]));
+    }
+
+    #[test]
+    fn ips_grows_rom() {
+        let patch = ips(&[&[0x00, 0x00, 0x05, 0x00, 0x02, 0xAA, 0xBB]]);
+        assert_eq!(apply(&[0, 1, 2, 3], &patch), Ok(vec![0, 1, 2, 3, 0, 0xAA, 0xBB]));
+    }
+
+    #[test]
+    fn ips_truncates_rom() {
+        let mut patch = ips(&[]);
+        patch.extend_from_slice(&[0x00, 0x00, 0x02]);
+        assert_eq!(apply(&[0, 1, 2, 3], &patch), Ok(vec![0, 1]));
+    }
+
+    #[test]
+    fn ips_truncated() {
+        assert_eq!(apply(&[0, 1], IPS_MAGIC), Err(PatchError::Truncated));
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend_from_slice(&[0x00, 0x00, 0x00, 0x00, 0x02, 0xAA]);
+        assert_eq!(apply(&[0, 1], &patch), Err(PatchError::Truncated));
+    }
+
+    /// A change at 454F46 can't start a record there, its offset would read as EOF
+    #[test]
+    fn ips_offset_spelling_eof() {
+        let source = vec![0; 0x454F48];
+        let mut target = source.clone();
+        target[0x454F46] = 1;
+        let patch = create(PatchFormat::Ips, &source, &target);
+        assert_eq!(&patch[IPS_MAGIC.len()..IPS_MAGIC.len() + 5], &[0x45, 0x4F, 0x45, 0x00, 0x02]);
+        assert_eq!(apply(&source, &patch), Ok(target));
+    }
+
+    #[test]
+    fn ips_round_trip() {
+        let source = [0x00, 0xE0, 0x12, 0x00, 0x00, 0x00];
+        let target = [0x00, 0xE0, 0x60, 0x01, 0x12];
+        assert_eq!(apply(&source, &create(PatchFormat::Ips, &source, &target)), Ok(target.to_vec()));
+        assert_eq!(apply(&target, &create(PatchFormat::Ips, &target, &source)), Ok(source.to_vec()));
+    }
+}
@@ -0,0 +1,90 @@
+use std::io::Write;
+
+use crc32fast::Hasher;
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+
+use {Byte};
+
+const SIGNATURE: [Byte; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1A, b'\n'];
+
+/// Encode an 8 bit RGB image, rows top to bottom, as a PNG file
+pub fn encode(width: usize, height: usize, rgb: &[Byte]) -> Vec<Byte> {
+    assert_eq!(rgb.len(), width * height * 3, "RGB data doesn't match the image size");
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&(width as u32).to_be_bytes());
+    header.extend_from_slice(&(height as u32).to_be_bytes());
+    // bit depth 8, color type RGB, deflate, no filter, no interlace
+    header.extend_from_slice(&[8, 2, 0, 0, 0]);
+
+    // every row starts with its filter type, always none
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    for row in rgb.chunks(width * 3).take(height) {
+        encoder.write_all(&[0]).and_then(|_| encoder.write_all(row)).expect("Writing to memory can't fail");
+    }
+    let pixels = encoder.finish().expect("Writing to memory can't fail");
+
+    let mut png = SIGNATURE.to_vec();
+    write_chunk(&mut png, b"IHDR", &header);
+    write_chunk(&mut png, b"IDAT", &pixels);
+    write_chunk(&mut png, b"IEND", &[]);
+    png
+}
+
+fn write_chunk(png: &mut Vec<Byte>, kind: &[Byte; 4], data: &[Byte]) {
+    let mut hasher = Hasher::new();
+    hasher.update(kind);
+    hasher.update(data);
+
+    png.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    png.extend_from_slice(kind);
+    png.extend_from_slice(data);
+    png.extend_from_slice(&hasher.finalize().to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    use flate2::read::ZlibDecoder;
+
+    /// The chunks of a PNG as kind and data, checking each CRC
+    fn chunks(png: &[Byte]) -> Vec<([Byte; 4], Vec<Byte>)> {
+        let mut chunks = Vec::new();
+        let mut rest = &png[SIGNATURE.len()..];
+        while !rest.is_empty() {
+            let length = u32::from_be_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + length]);
+            let crc = u32::from_be_bytes([rest[8 + length], rest[9 + length], rest[10 + length], rest[11 + length]]);
+            assert_eq!(crc, crc32fast::hash(&rest[4..8 + length]));
+            chunks.push(([kind[0], kind[1], kind[2], kind[3]], data.to_vec()));
+            rest = &rest[12 + length..];
+        }
+        chunks
+    }
+
+    #[test]
+    fn two_by_two() {
+        let rgb = [
+            0xFF, 0x00, 0x00, 0x00, 0xFF, 0x00,
+            0x00, 0x00, 0xFF, 0x12, 0x34, 0x56,
+        ];
+        let png = encode(2, 2, &rgb);
+        assert!(png.starts_with(&SIGNATURE));
+
+        let chunks = chunks(&png);
+        let kinds: Vec<&[Byte]> = chunks.iter().map(|(kind, _)| &kind[..]).collect();
+        assert_eq!(kinds, vec![&b"IHDR"[..], &b"IDAT"[..], &b"IEND"[..]]);
+        assert_eq!(chunks[0].1, vec![0, 0, 0, 2, 0, 0, 0, 2, 8, 2, 0, 0, 0]);
+
+        let mut pixels = Vec::new();
+        ZlibDecoder::new(&chunks[1].1[..]).read_to_end(&mut pixels).unwrap();
+        assert_eq!(pixels, vec![
+            0, 0xFF, 0x00, 0x00, 0x00, 0xFF, 0x00,
+            0, 0x00, 0x00, 0xFF, 0x12, 0x34, 0x56,
+        ]);
+        assert!(chunks[2].1.is_empty());
+    }
+}
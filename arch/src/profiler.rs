@@ -0,0 +1,388 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::fmt;
+
+use serde_json;
+
+use {Address};
+use instructions::Instruction;
+use memory::MEMORY_SIZE;
+
+/// How many of the hottest addresses the text report lists
+const HOT_SPOTS: usize = 20;
+
+/// Call events kept for the trace, a long session would otherwise grow without bounds
+const MAX_TRACE_EVENTS: usize = 1_000_000;
+
+/// What one subroutine cost, in instructions
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RoutineCost {
+    pub calls: u64,
+
+    /// executed by the routine and everything it called
+    pub inclusive: u64,
+
+    /// executed by the routine itself
+    pub exclusive: u64,
+}
+
+/// A call stack, interned: its index in Profiler::stacks
+type StackId = usize;
+
+/// A routine being executed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Frame {
+    entry: Address,
+    stack: StackId,
+
+    /// instructions executed when it was called
+    since: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TraceEvent {
+    Begin { entry: Address, at: u64 },
+    End { entry: Address, at: u64 },
+    Frame { start: u64, end: u64 },
+}
+
+/// Profiler
+///
+/// Counts instructions per address, opcode and subroutine, following JSR and RTS.
+/// Time is measured in instructions, converted with the clock speed for the trace.
+pub struct Profiler {
+    clock_speed: u32,
+    entry: Address,
+    executed: u64,
+
+    per_address: Vec<u64>,
+    instructions: BTreeMap<Address, Instruction>,
+    per_opcode: BTreeMap<&'static str, u64>,
+
+    /// routines being executed, the program start at the bottom
+    stack: Vec<Frame>,
+
+    /// how many times each routine is on the stack, a recursive routine only counts once towards its inclusive cost
+    active: Vec<u32>,
+
+    /// calls, and the inclusive cost of calls that returned
+    routines: BTreeMap<Address, RoutineCost>,
+
+    /// every call stack seen, as the stack it was called from and the routine on top
+    stacks: Vec<(Option<StackId>, Address)>,
+    stack_ids: HashMap<(Option<StackId>, Address), StackId>,
+
+    /// instructions executed with each stack, which makes the exclusive costs
+    folded: Vec<u64>,
+
+    trace: Vec<TraceEvent>,
+    frame_start: u64,
+
+    /// seconds each frame took to emulate
+    frame_times: Vec<f64>,
+}
+
+impl Profiler {
+    pub fn new(entry: Address, clock_speed: u32) -> Profiler {
+        let mut profiler = Profiler {
+            clock_speed,
+            entry,
+            executed: 0,
+            per_address: vec![0; MEMORY_SIZE],
+            instructions: BTreeMap::new(),
+            per_opcode: BTreeMap::new(),
+            stack: Vec::new(),
+            active: vec![0; MEMORY_SIZE],
+            routines: BTreeMap::new(),
+            stacks: Vec::new(),
+            stack_ids: HashMap::new(),
+            folded: Vec::new(),
+            trace: vec![TraceEvent::Begin { entry, at: 0 }],
+            frame_start: 0,
+            frame_times: Vec::new(),
+        };
+        profiler.enter(entry);
+        profiler
+    }
+
+    /// Count an instruction that was just executed
+    pub fn record(&mut self, address: Address, instruction: &Instruction) {
+        self.executed += 1;
+        self.per_address[address as usize % MEMORY_SIZE] += 1;
+        self.instructions.entry(address).or_insert(*instruction);
+        *self.per_opcode.entry(instruction.mnemonic()).or_insert(0) += 1;
+
+        if let Some(frame) = self.stack.last() {
+            self.folded[frame.stack] += 1;
+        }
+
+        match *instruction {
+            Instruction::JSR { address } => {
+                self.routines.entry(address).or_default().calls += 1;
+                self.enter(address);
+                self.trace_event(TraceEvent::Begin { entry: address, at: self.executed });
+            },
+            // a return with nothing to return from doesn't leave the program start
+            Instruction::RTS if self.stack.len() > 1 => {
+                let entry = self.leave();
+                self.trace_event(TraceEvent::End { entry, at: self.executed });
+            },
+            _ => {},
+        }
+    }
+
+    /// Push a routine, its instructions are counted from the next one on
+    fn enter(&mut self, entry: Address) {
+        let parent = self.stack.last().map(|frame| frame.stack);
+        let stack = match self.stack_ids.get(&(parent, entry)) {
+            Some(&stack) => stack,
+            None => {
+                let stack = self.stacks.len();
+                self.stacks.push((parent, entry));
+                self.folded.push(0);
+                self.stack_ids.insert((parent, entry), stack);
+                stack
+            },
+        };
+
+        self.active[entry as usize % MEMORY_SIZE] += 1;
+        self.stack.push(Frame { entry, stack, since: self.executed });
+    }
+
+    /// Pop the routine on top, the outermost call of a routine adds what it executed to its inclusive cost
+    fn leave(&mut self) -> Address {
+        let frame = self.stack.pop().expect("Profiler stack is never empty");
+        let active = &mut self.active[frame.entry as usize % MEMORY_SIZE];
+        *active -= 1;
+        if *active == 0 {
+            self.routines.entry(frame.entry).or_default().inclusive += self.executed - frame.since;
+        }
+        frame.entry
+    }
+
+    /// Mark the end of a frame, and how long it took to emulate
+    pub fn frame(&mut self, seconds: f64) {
+        self.frame_times.push(seconds);
+        let event = TraceEvent::Frame { start: self.frame_start, end: self.executed };
+        self.trace_event(event);
+        self.frame_start = self.executed;
+    }
+
+    fn trace_event(&mut self, event: TraceEvent) {
+        if self.trace.len() < MAX_TRACE_EVENTS {
+            self.trace.push(event);
+        }
+    }
+
+    pub fn executed(&self) -> u64 {
+        self.executed
+    }
+
+    pub fn count_at(&self, address: Address) -> u64 {
+        self.per_address[address as usize % MEMORY_SIZE]
+    }
+
+    /// Costs so far, routines still running included
+    pub fn routines(&self) -> BTreeMap<Address, RoutineCost> {
+        let mut routines = self.routines.clone();
+
+        let mut running = BTreeSet::new();
+        for frame in self.stack.iter() {
+            if running.insert(frame.entry) {
+                routines.entry(frame.entry).or_default().inclusive += self.executed - frame.since;
+            }
+        }
+        for (stack, &count) in self.folded.iter().enumerate() {
+            routines.entry(self.stacks[stack].1).or_default().exclusive += count;
+        }
+
+        routines
+    }
+
+    /// The entries of the routines on a stack, from the bottom
+    fn entries(&self, stack: StackId) -> Vec<Address> {
+        let mut entries = Vec::new();
+        let mut next = Some(stack);
+        while let Some(stack) = next {
+            let (parent, entry) = self.stacks[stack];
+            entries.push(entry);
+            next = parent;
+        }
+        entries.reverse();
+        entries
+    }
+
+    fn name(&self, entry: Address) -> String {
+        if entry == self.entry {
+            String::from("main")
+        } else {
+            format!("sub_{:03X}", entry)
+        }
+    }
+
+    /// Stacks in the folded format flame graph tools take, one line per distinct stack
+    pub fn folded_stacks(&self) -> String {
+        let stacks: BTreeMap<Vec<Address>, u64> = self.folded.iter()
+            .enumerate()
+            .filter(|&(_, &count)| count > 0)
+            .map(|(stack, &count)| (self.entries(stack), count))
+            .collect();
+
+        let mut folded = String::new();
+        for (stack, count) in stacks.iter() {
+            let names: Vec<String> = stack.iter().map(|entry| self.name(*entry)).collect();
+            folded.push_str(&format!("{} {}\n", names.join(";"), count));
+        }
+        folded
+    }
+
+    /// Calls and frames as Chrome trace events, loadable in chrome://tracing or Perfetto
+    pub fn chrome_trace(&self) -> String {
+        let microseconds = |instructions: u64| instructions as f64 * 1_000_000.0 / self.clock_speed.max(1) as f64;
+        let call = |phase: &str, entry: Address, at: u64| json!({
+            "name": self.name(entry),
+            "cat": "call",
+            "ph": phase,
+            "ts": microseconds(at),
+            "pid": 1,
+            "tid": 1,
+        });
+
+        let mut events: Vec<serde_json::Value> = self.trace.iter()
+            .map(|event| match *event {
+                TraceEvent::Begin { entry, at } => call("B", entry, at),
+                TraceEvent::End { entry, at } => call("E", entry, at),
+                TraceEvent::Frame { start, end } => json!({
+                    "name": "frame",
+                    "cat": "frame",
+                    "ph": "X",
+                    "ts": microseconds(start),
+                    "dur": microseconds(end - start),
+                    "pid": 1,
+                    "tid": 2,
+                }),
+            })
+            .collect();
+
+        // close whatever was still running when profiling stopped
+        for frame in self.stack.iter().rev() {
+            events.push(call("E", frame.entry, self.executed));
+        }
+
+        json!({
+            "traceEvents": events,
+            "displayTimeUnit": "ms",
+        }).to_string()
+    }
+}
+
+/// The value below which the given fraction of the sorted values fall
+fn percentile(sorted: &[f64], fraction: f64) -> f64 {
+    if sorted.is_empty() {
+        return 0.0;
+    }
+    let index = ((sorted.len() - 1) as f64 * fraction).round() as usize;
+    sorted[index]
+}
+
+impl fmt::Display for Profiler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "instructions: {}", self.executed)?;
+
+        writeln!(f, "\nhot spots:")?;
+        let mut hot: Vec<(Address, u64)> = self.instructions.keys()
+            .map(|&address| (address, self.count_at(address)))
+            .collect();
+        hot.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        for &(address, count) in hot.iter().take(HOT_SPOTS) {
+            let share = count as f64 * 100.0 / self.executed.max(1) as f64;
+            writeln!(f, "    {:03X} {:>10} {:>6.2}%  {}", address, count, share, self.instructions[&address])?;
+        }
+
+        writeln!(f, "\nopcodes:")?;
+        let mut opcodes: Vec<_> = self.per_opcode.iter().collect();
+        opcodes.sort_by(|a, b| b.1.cmp(a.1).then(a.0.cmp(b.0)));
+        for (mnemonic, count) in opcodes {
+            writeln!(f, "    {:<8} {:>10}", mnemonic, count)?;
+        }
+
+        writeln!(f, "\nsubroutines:        calls  inclusive  exclusive")?;
+        let routines = self.routines();
+        let mut routines: Vec<_> = routines.iter().collect();
+        routines.sort_by(|a, b| b.1.inclusive.cmp(&a.1.inclusive).then(a.0.cmp(b.0)));
+        for (entry, cost) in routines {
+            writeln!(f, "    {:<12} {:>8} {:>10} {:>10}", self.name(*entry), cost.calls, cost.inclusive, cost.exclusive)?;
+        }
+
+        if !self.frame_times.is_empty() {
+            let mut sorted = self.frame_times.clone();
+            sorted.sort_by(|a, b| a.partial_cmp(b).unwrap_or(::std::cmp::Ordering::Equal));
+            let average = sorted.iter().sum::<f64>() / sorted.len() as f64;
+            let milliseconds = |seconds: f64| seconds * 1000.0;
+
+            writeln!(f, "\nframe times ({} frames):", sorted.len())?;
+            writeln!(f, "    min     {:.3} ms", milliseconds(sorted[0]))?;
+            writeln!(f, "    average {:.3} ms", milliseconds(average))?;
+            writeln!(f, "    median  {:.3} ms", milliseconds(percentile(&sorted, 0.5)))?;
+            writeln!(f, "    95%     {:.3} ms", milliseconds(percentile(&sorted, 0.95)))?;
+            writeln!(f, "    99%     {:.3} ms", milliseconds(percentile(&sorted, 0.99)))?;
+            writeln!(f, "    max     {:.3} ms", milliseconds(sorted[sorted.len() - 1]))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::Cpu;
+
+    /// main calls sub_206 twice, which calls sub_20C, which calls itself until V0 is 3
+    fn profile(count: usize) -> Cpu {
+        let rom = [
+            0x22, 0x06, // 200 JSR 206
+            0x22, 0x06, // 202 JSR 206
+            0x12, 0x04, // 204 JMP 204
+            0x60, 0x00, // 206 MOV V0, 0
+            0x22, 0x0C, // 208 JSR 20C
+            0x00, 0xEE, // 20A RTS
+            0x70, 0x01, // 20C ADD V0, 1
+            0x30, 0x03, // 20E SE V0, 3
+            0x22, 0x0C, // 210 JSR 20C
+            0x00, 0xEE, // 212 RTS
+        ];
+        let mut cpu = Cpu::new(&rom).unwrap();
+        cpu.enable_profiler();
+        cpu.run(count).unwrap();
+        cpu
+    }
+
+    fn cost(calls: u64, inclusive: u64, exclusive: u64) -> RoutineCost {
+        RoutineCost { calls, inclusive, exclusive }
+    }
+
+    #[test]
+    fn recursive_calls() {
+        let cpu = profile(40);
+        let profiler = cpu.get_profiler().unwrap();
+        let routines = profiler.routines();
+        assert_eq!(routines[&0x200], cost(0, 40, 12));
+        assert_eq!(routines[&0x206], cost(2, 28, 6));
+        assert_eq!(routines[&0x20C], cost(6, 22, 22));
+        assert_eq!(profiler.folded_stacks(), "main 12\n\
+            main;sub_206 6\n\
+            main;sub_206;sub_20C 8\n\
+            main;sub_206;sub_20C;sub_20C 8\n\
+            main;sub_206;sub_20C;sub_20C;sub_20C 6\n");
+    }
+
+    /// Routines that haven't returned yet count up to the last instruction
+    #[test]
+    fn running_calls() {
+        let cpu = profile(14);
+        let routines = cpu.get_profiler().unwrap().routines();
+        assert_eq!(routines[&0x200], cost(0, 14, 1));
+        assert_eq!(routines[&0x206], cost(1, 13, 2));
+        assert_eq!(routines[&0x20C], cost(3, 11, 11));
+    }
+}
@@ -13,15 +13,14 @@ use { Byte };
 /// PC -
 ///     Program couter, points to the currently executed command
 /// 
-/// SP - 
-///     Stack Pointer, points to the top of the stack
+/// The stack pointer belongs to the call stack
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct Registers {
     pub vs: [Byte; 16],
     pub i: u16,              
     pub sound_timer: Byte,
     pub delay_timer: Byte,
     pub program_counter: u16,
-    pub stack_pointer: u8,
 }
 
 impl Registers {
@@ -31,8 +30,13 @@ impl Registers {
             i: 0,
             delay_timer: 0,
             sound_timer: 0,
-            stack_pointer: 0,
             program_counter: 0,
         }
     }
-}
\ No newline at end of file
+}
+
+impl Default for Registers {
+    fn default() -> Registers {
+        Registers::new()
+    }
+}
@@ -0,0 +1,381 @@
+use std::collections::{HashSet, VecDeque};
+use std::fmt;
+
+use rand;
+
+use {Address, Byte};
+use config::Config;
+use effects::Effects;
+use instructions::Instruction;
+use loader::MemoryMap;
+use memory::{Memory, FONT_OFFSET, FONT_SIZE, MEMORY_SIZE};
+use registers::Registers;
+
+/// How many executed addresses a report carries
+const HISTORY_SIZE: usize = 16;
+
+/// Something the program did that it shouldn't rely on
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Issue {
+    /// Vx read before anything was written to it
+    UninitializedRegister(Byte),
+
+    /// I used before anything was written to it
+    UninitializedI,
+
+    /// memory read that was neither loaded nor written
+    UninitializedMemory(Address),
+
+    /// an instruction fetched from an odd address
+    OddProgramCounter,
+
+    /// an instruction fetched from the font
+    ExecutingFont,
+
+    /// I plus the bytes accessed or added to it goes past 0xFFF
+    IWrapped,
+
+    /// a write to memory that was already executed as code
+    SelfModifyingCode(Address),
+}
+
+impl Issue {
+    /// Undefined behavior, as opposed to only depending on power-on state
+    pub fn is_undefined(&self) -> bool {
+        match *self {
+            Issue::UninitializedRegister(_) | Issue::UninitializedI | Issue::UninitializedMemory(_) => false,
+            Issue::OddProgramCounter | Issue::ExecutingFont | Issue::IWrapped | Issue::SelfModifyingCode(_) => true,
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Issue::UninitializedRegister(reg_id) => write!(f, "V{:X} read before being written", reg_id),
+            Issue::UninitializedI => write!(f, "I used before being written"),
+            Issue::UninitializedMemory(address) => write!(f, "{:03X} read before being loaded or written", address),
+            Issue::OddProgramCounter => write!(f, "executing from an odd address"),
+            Issue::ExecutingFont => write!(f, "executing from the font"),
+            Issue::IWrapped => write!(f, "I wraps past FFF"),
+            Issue::SelfModifyingCode(address) => write!(f, "writing to {:03X}, which was already executed", address),
+        }
+    }
+}
+
+/// Report
+///
+/// An issue, where it happened and how the program got there
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    /// the instruction that ran into the issue
+    pub address: Address,
+    pub issue: Issue,
+
+    /// the instruction that last set the state involved, e.g where I was set for a bad read
+    pub origin: Option<Address>,
+
+    /// the addresses executed before, oldest first
+    pub history: Vec<Address>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:03X} {}", self.address, self.issue)?;
+        if let Some(origin) = self.origin {
+            write!(f, ", set by {:03X}", origin)?;
+        }
+        write!(f, "\n    after")?;
+        for address in self.history.iter() {
+            write!(f, " {:03X}", address)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SanitizerOptions {
+    /// fill memory that wasn't loaded, the registers and I with random values on power-on
+    pub randomize: bool,
+
+    /// stop the cpu on undefined behavior, instead of only reporting it
+    pub trap: bool,
+}
+
+/// Where a byte or register got its value from
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Uninitialized,
+    Loaded,
+    Written(Address),
+}
+
+impl State {
+    fn origin(&self) -> Option<Address> {
+        match *self {
+            State::Written(address) => Some(address),
+            _ => None,
+        }
+    }
+}
+
+/// Sanitizer
+///
+/// Watches every instruction before it runs, tracking which state was written and by whom
+pub struct Sanitizer {
+    options: SanitizerOptions,
+    memory: Vec<State>,
+    registers: [State; 16],
+    i: State,
+    executed: Vec<bool>,
+    history: VecDeque<Address>,
+    reports: Vec<Report>,
+
+    /// issues already reported, by address
+    seen: HashSet<(Address, Issue)>,
+}
+
+impl Sanitizer {
+    /// Everything the memory map covers counts as initialized
+    pub fn new(options: SanitizerOptions, memory_map: &MemoryMap) -> Sanitizer {
+        let mut memory = vec![State::Uninitialized; MEMORY_SIZE];
+        for region in memory_map.regions.iter() {
+            let end = region.end().min(MEMORY_SIZE);
+            memory[region.start as usize..end].iter_mut().for_each(|state| *state = State::Loaded);
+        }
+
+        Sanitizer {
+            options,
+            memory,
+            registers: [State::Uninitialized; 16],
+            i: State::Uninitialized,
+            executed: vec![false; MEMORY_SIZE],
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+            reports: Vec::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    /// Everything counts as initialized, for resuming from a save state the program already set up
+    pub fn resumed(options: SanitizerOptions) -> Sanitizer {
+        Sanitizer {
+            options,
+            memory: vec![State::Loaded; MEMORY_SIZE],
+            registers: [State::Loaded; 16],
+            i: State::Loaded,
+            executed: vec![false; MEMORY_SIZE],
+            history: VecDeque::with_capacity(HISTORY_SIZE),
+            reports: Vec::new(),
+            seen: HashSet::new(),
+        }
+    }
+
+    pub fn options(&self) -> SanitizerOptions {
+        self.options
+    }
+
+    /// Fill what the sanitizer considers uninitialized with random values
+    pub fn randomize(&self, memory: &mut Memory, registers: &mut Registers) {
+        for (address, state) in self.memory.iter().enumerate() {
+            if *state == State::Uninitialized {
+                memory.write(address, rand::random());
+            }
+        }
+        for v in registers.vs.iter_mut() {
+            *v = rand::random();
+        }
+        registers.i = rand::random::<u16>() & 0xFFF;
+    }
+
+    /// Every report so far, in the order found
+    pub fn reports(&self) -> &[Report] {
+        &self.reports
+    }
+
+    fn report(&mut self, address: Address, issue: Issue, origin: Option<Address>) {
+        if self.seen.insert((address, issue)) {
+            self.reports.push(Report {
+                address,
+                issue,
+                origin,
+                history: self.history.iter().cloned().collect(),
+            });
+        }
+    }
+
+    /// Check the instruction about to run at address.
+    /// Returns the undefined behavior to trap on, in which case the instruction must not run.
+    pub fn check(&mut self, address: Address, instruction: Option<&Instruction>, registers: &Registers, config: &Config) -> Option<Issue> {
+        let mut issues: Vec<(Issue, Option<Address>)> = Vec::new();
+
+        if address % 2 == 1 {
+            issues.push((Issue::OddProgramCounter, None));
+        }
+        if (FONT_OFFSET..FONT_OFFSET + FONT_SIZE).contains(&(address as usize)) {
+            issues.push((Issue::ExecutingFont, None));
+        }
+
+        let effects = instruction.map(|instruction| Effects::of(instruction, registers, config));
+        if let Some(effects) = effects {
+            for reg_id in effects.registers_read() {
+                if self.registers[reg_id as usize] == State::Uninitialized {
+                    issues.push((Issue::UninitializedRegister(reg_id), None));
+                }
+            }
+            if effects.reads_i && self.i == State::Uninitialized {
+                issues.push((Issue::UninitializedI, None));
+            }
+
+            if let Some(Instruction::ADI { reg_id }) = instruction.cloned() {
+                if registers.i as usize + registers.vs[reg_id as usize] as usize >= MEMORY_SIZE {
+                    issues.push((Issue::IWrapped, self.i.origin()));
+                }
+            }
+
+            if let Some(access) = effects.memory {
+                if access.start + access.length > MEMORY_SIZE {
+                    issues.push((Issue::IWrapped, self.i.origin()));
+                }
+                for location in access.addresses() {
+                    let location = location % MEMORY_SIZE;
+                    if access.is_write() {
+                        if self.executed[location] {
+                            issues.push((Issue::SelfModifyingCode(location as Address), self.i.origin()));
+                            break;
+                        }
+                    } else if self.memory[location] == State::Uninitialized {
+                        issues.push((Issue::UninitializedMemory(location as Address), self.i.origin()));
+                        break;
+                    }
+                }
+            }
+        }
+
+        let trapped = issues.iter()
+            .map(|&(issue, _)| issue)
+            .find(|issue| self.options.trap && issue.is_undefined());
+        for (issue, origin) in issues {
+            self.report(address, issue, origin);
+        }
+        if trapped.is_some() {
+            return trapped;
+        }
+
+        self.executed[address as usize % MEMORY_SIZE] = true;
+        self.executed[(address as usize + 1) % MEMORY_SIZE] = true;
+
+        if let Some(effects) = effects {
+            for reg_id in 0..16 {
+                if effects.writes & (1 << reg_id) != 0 {
+                    self.registers[reg_id] = State::Written(address);
+                }
+            }
+            if effects.writes_i {
+                self.i = State::Written(address);
+            }
+            if let Some(access) = effects.memory.filter(|access| access.is_write()) {
+                for location in access.addresses() {
+                    self.memory[location % MEMORY_SIZE] = State::Written(address);
+                }
+            }
+        }
+
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(address);
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::{Cpu, Fault};
+
+    /// Run a ROM in strict mode, with what it ended in and the reports
+    fn run(rom: &[Byte], count: usize) -> (Result<(), Fault>, Vec<Report>) {
+        let mut cpu = Cpu::new(rom).unwrap();
+        cpu.enable_sanitizer(SanitizerOptions { randomize: false, trap: true });
+        let result = cpu.run(count);
+        (result, cpu.get_sanitizer().unwrap().reports().to_vec())
+    }
+
+    fn trapped(address: Address, issue: Issue) -> Result<(), Fault> {
+        Err(Fault::UndefinedBehavior { address, issue })
+    }
+
+    #[test]
+    fn odd_program_counter() {
+        let rom = [
+            0x12, 0x03, // 200 JMP 203
+            0x00, 0x60, // 202
+            0x01,       // 204
+        ];
+        assert_eq!(run(&rom, 2).0, trapped(0x203, Issue::OddProgramCounter));
+    }
+
+    #[test]
+    fn executing_font() {
+        let rom = [
+            0x10, 0x0A, // 200 JMP 00A
+        ];
+        assert_eq!(run(&rom, 2).0, trapped(0x00A, Issue::ExecutingFont));
+    }
+
+    #[test]
+    fn i_wrapped() {
+        let rom = [
+            0x60, 0x00, // 200 MOV V0, 00
+            0xAF, 0xFE, // 202 MOVI FFE
+            0xD0, 0x02, // 204 DRW V0, V0, 2
+            0xD0, 0x03, // 206 DRW V0, V0, 3
+        ];
+        let (result, reports) = run(&rom, 4);
+        assert_eq!(result, trapped(0x206, Issue::IWrapped));
+        assert_eq!(reports.last().map(|report| report.origin), Some(Some(0x202)));
+
+        let rom = [
+            0x60, 0x02, // 200 MOV V0, 02
+            0xAF, 0xFD, // 202 MOVI FFD
+            0xF0, 0x1E, // 204 ADI V0
+            0xF0, 0x1E, // 206 ADI V0
+        ];
+        assert_eq!(run(&rom, 4).0, trapped(0x206, Issue::IWrapped));
+    }
+
+    #[test]
+    fn self_modifying_code() {
+        let rom = [
+            0x60, 0x00, // 200 MOV V0, 00
+            0xA2, 0x0C, // 202 MOVI 20C
+            0xF0, 0x55, // 204 STR V0, ahead of what ran
+            0xA2, 0x00, // 206 MOVI 200
+            0xF0, 0x55, // 208 STR V0, over what ran
+        ];
+        let (result, reports) = run(&rom, 5);
+        assert_eq!(result, trapped(0x208, Issue::SelfModifyingCode(0x200)));
+        assert_eq!(reports.len(), 1);
+        assert_eq!(reports[0].origin, Some(0x206));
+    }
+
+    #[test]
+    fn uninitialized_read() {
+        let rom = [
+            0xA3, 0x00, // 200 MOVI 300
+            0x60, 0x01, // 202 MOV V0, 01
+            0xD0, 0x01, // 204 DRW V0, V0, 1
+            0x43, 0x00, // 206 SNE V3, 00
+            0x12, 0x08, // 208 JMP 208
+        ];
+        let (result, reports) = run(&rom, 5);
+        // only depends on power-on state, so it doesn't trap
+        assert_eq!(result, Ok(()));
+        assert_eq!(reports, vec![
+            Report { address: 0x204, issue: Issue::UninitializedMemory(0x300), origin: Some(0x200), history: vec![0x200, 0x202] },
+            Report { address: 0x206, issue: Issue::UninitializedRegister(3), origin: None, history: vec![0x200, 0x202, 0x204] },
+        ]);
+        assert_eq!(reports[0].to_string(), "204 300 read before being loaded or written, set by 200\n    after 200 202");
+    }
+}
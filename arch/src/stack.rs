@@ -0,0 +1,198 @@
+use std::fmt;
+
+use {Address};
+
+/// Return addresses the COSMAC VIP interpreter has room for
+pub const VIP_STACK_DEPTH: usize = 12;
+
+/// Return addresses SUPER-CHIP, XO-CHIP and most later interpreters have room for
+pub const STACK_DEPTH: usize = 16;
+
+/// What happens when a call doesn't fit on the stack, or a return has nothing to return to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+pub enum StackPolicy {
+    /// stop the program
+    #[default]
+    Fault,
+
+    /// the stack pointer wraps around, overwriting the oldest return address on overflow
+    /// and returning to a stale one on underflow
+    Wrap,
+
+    /// the call is made without saving a return address, the return does nothing
+    Ignore,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StackError {
+    Overflow,
+    Underflow,
+}
+
+impl fmt::Display for StackError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StackError::Overflow => write!(f, "Stack overflow"),
+            StackError::Underflow => write!(f, "Stack underflow"),
+        }
+    }
+}
+
+impl ::std::error::Error for StackError {}
+
+/// CallStack
+///
+/// The return addresses of the subroutines being executed, innermost on top
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CallStack {
+    slots: Vec<Address>,
+
+    /// the next free slot, also how many return addresses are on the stack
+    pointer: usize,
+    policy: StackPolicy,
+}
+
+impl CallStack {
+    pub fn new(depth: usize, policy: StackPolicy) -> CallStack {
+        CallStack {
+            slots: vec![0; depth.max(1)],
+            pointer: 0,
+            policy,
+        }
+    }
+
+    pub fn depth(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn policy(&self) -> StackPolicy {
+        self.policy
+    }
+
+    pub fn len(&self) -> usize {
+        self.pointer
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.pointer == 0
+    }
+
+    /// Save the address a call returns to
+    pub fn push(&mut self, address: Address) -> Result<(), StackError> {
+        if self.pointer == self.depth() {
+            match self.policy {
+                StackPolicy::Fault => return Err(StackError::Overflow),
+                StackPolicy::Wrap => self.pointer = 0,
+                StackPolicy::Ignore => return Ok(()),
+            }
+        }
+        self.slots[self.pointer] = address;
+        self.pointer += 1;
+        Ok(())
+    }
+
+    /// Take the address to return to, None when the return is to be ignored
+    pub fn pop(&mut self) -> Result<Option<Address>, StackError> {
+        if self.pointer == 0 {
+            match self.policy {
+                StackPolicy::Fault => return Err(StackError::Underflow),
+                StackPolicy::Wrap => self.pointer = self.depth(),
+                StackPolicy::Ignore => return Ok(None),
+            }
+        }
+        self.pointer -= 1;
+        Ok(Some(self.slots[self.pointer]))
+    }
+
+    /// The return addresses on the stack, outermost first. Reversed, it is a backtrace.
+    pub fn return_addresses(&self) -> &[Address] {
+        &self.slots[..self.pointer]
+    }
+
+    /// Every slot, including those not in use, as the hardware would hold them
+    pub fn slots(&self) -> &[Address] {
+        &self.slots
+    }
+
+    /// Put back slots and a stack pointer taken from another stack, e.g from a save state
+    pub fn restore(&mut self, slots: &[Address], pointer: usize) {
+        let depth = self.depth();
+        for (slot, address) in self.slots.iter_mut().zip(slots.iter()) {
+            *slot = *address;
+        }
+        self.pointer = pointer.min(depth);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use config::{Config, Platform};
+    use cpu::{Cpu, Fault};
+
+    /// A stack of depth holding 1, 2 and so on up to depth
+    fn full(depth: usize, policy: StackPolicy) -> CallStack {
+        let mut stack = CallStack::new(depth, policy);
+        for address in 1..=depth {
+            stack.push(address as Address).unwrap();
+        }
+        stack
+    }
+
+    #[test]
+    fn depth_by_platform() {
+        let rom = [
+            0x22, 0x00, // 200 JSR 200
+        ];
+        for &(platform, depth) in [(Platform::Chip8, VIP_STACK_DEPTH), (Platform::SuperChip, STACK_DEPTH)].iter() {
+            let mut cpu = Cpu::with_config(&rom, Config::for_platform(platform)).unwrap();
+            cpu.run(depth).unwrap();
+            assert_eq!(cpu.try_tick(), Err(Fault::StackOverflow { address: 0x200 }));
+            assert_eq!(cpu.save_state().stack_pointer, depth);
+        }
+    }
+
+    #[test]
+    fn fault_policy() {
+        let mut stack = full(2, StackPolicy::Fault);
+        assert_eq!(stack.push(3), Err(StackError::Overflow));
+        assert_eq!(stack.return_addresses(), &[1, 2]);
+        assert_eq!(stack.pop(), Ok(Some(2)));
+        assert_eq!(stack.pop(), Ok(Some(1)));
+        assert_eq!(stack.pop(), Err(StackError::Underflow));
+    }
+
+    #[test]
+    fn wrap_policy() {
+        let mut stack = full(2, StackPolicy::Wrap);
+        assert_eq!(stack.push(3), Ok(()));
+        assert_eq!(stack.slots(), &[3, 2]);
+        assert_eq!(stack.pop(), Ok(Some(3)));
+        // returns to what is left in the slot below
+        assert_eq!(stack.pop(), Ok(Some(2)));
+        assert_eq!(stack.pop(), Ok(Some(3)));
+    }
+
+    #[test]
+    fn ignore_policy() {
+        let mut stack = full(2, StackPolicy::Ignore);
+        assert_eq!(stack.push(3), Ok(()));
+        assert_eq!(stack.return_addresses(), &[1, 2]);
+        assert_eq!(stack.pop(), Ok(Some(2)));
+        assert_eq!(stack.pop(), Ok(Some(1)));
+        assert_eq!(stack.pop(), Ok(None));
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn restore_clamps_pointer() {
+        let mut stack = CallStack::new(2, StackPolicy::Fault);
+        stack.restore(&[5, 6, 7], 5);
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack.return_addresses(), &[5, 6]);
+
+        stack.restore(&[8], 1);
+        assert_eq!(stack.slots(), &[8, 6]);
+        assert_eq!(stack.return_addresses(), &[8]);
+    }
+}
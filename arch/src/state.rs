@@ -0,0 +1,299 @@
+use std::fmt;
+
+use {Address, Byte};
+use config::{Config, Palette, Platform, Quirk, Quirks, Timing};
+use stack::StackPolicy;
+use display::{Display, MAX_HEIGHT, MAX_WIDTH};
+use memory::MEMORY_SIZE;
+use registers::Registers;
+
+const MAGIC: &[Byte; 4] = b"C8ST";
+const VERSION: Byte = 5;
+
+/// SaveState
+///
+/// Everything needed to resume a machine exactly where it was: registers, call stack, memory,
+/// display, held keys and the config it ran with. Tools watching the cpu aren't part of it.
+///
+/// Stored as `C8ST`, a version byte and then the fields in order, numbers big endian
+#[derive(Clone, Debug, PartialEq)]
+pub struct SaveState {
+    pub config: Config,
+    pub registers: Registers,
+
+    /// every slot of the call stack, whether in use or not
+    pub stack: Vec<Address>,
+
+    /// how many slots of the call stack are in use
+    pub stack_pointer: usize,
+    pub memory: Vec<Byte>,
+
+    pub display: Display,
+
+    /// bit k set for each key k held down
+    pub keys: u16,
+
+    /// a DRW holds the program until the next vertical blank
+    pub waiting_for_vblank: bool,
+}
+
+impl SaveState {
+    pub fn to_bytes(&self) -> Vec<Byte> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(VERSION);
+
+        bytes.push(match self.config.platform {
+            Platform::Chip8 => 0,
+            Platform::SuperChip => 1,
+            Platform::XoChip => 2,
+            Platform::HiresChip8 => 3,
+        });
+        let quirks = Quirk::ALL.iter()
+            .enumerate()
+            .filter(|&(_, &quirk)| self.config.quirks.get(quirk))
+            .fold(0, |bits, (bit, _)| bits | 1 << bit);
+        bytes.push(quirks);
+        bytes.extend_from_slice(&self.config.clock_speed.to_be_bytes());
+        bytes.push(match self.config.timing {
+            Timing::Fixed => 0,
+            Timing::Vip => 1,
+        });
+        bytes.extend_from_slice(&self.config.palette.background);
+        bytes.extend_from_slice(&self.config.palette.foreground);
+        bytes.push(self.config.stack_depth as Byte);
+        bytes.push(match self.config.stack_policy {
+            StackPolicy::Fault => 0,
+            StackPolicy::Wrap => 1,
+            StackPolicy::Ignore => 2,
+        });
+
+        let registers = &self.registers;
+        bytes.extend_from_slice(&registers.vs);
+        bytes.extend_from_slice(&registers.i.to_be_bytes());
+        bytes.push(registers.sound_timer);
+        bytes.push(registers.delay_timer);
+        bytes.extend_from_slice(&registers.program_counter.to_be_bytes());
+
+        bytes.push(self.stack_pointer as Byte);
+        bytes.push(self.stack.len() as Byte);
+        for address in self.stack.iter() {
+            bytes.extend_from_slice(&address.to_be_bytes());
+        }
+
+        bytes.extend_from_slice(&self.memory);
+
+        bytes.extend_from_slice(&(self.display.width() as u16).to_be_bytes());
+        bytes.extend_from_slice(&(self.display.height() as u16).to_be_bytes());
+        let pixels: Vec<bool> = self.display.pixels().map(|(_, _, is_set)| is_set).collect();
+        for pixels in pixels.chunks(8) {
+            bytes.push(pixels.iter().enumerate().fold(0, |byte, (bit, &is_set)| byte | (is_set as Byte) << (7 - bit)));
+        }
+
+        bytes.extend_from_slice(&self.keys.to_be_bytes());
+        bytes.push(self.waiting_for_vblank as Byte);
+        bytes
+    }
+
+    pub fn from_bytes(bytes: &[Byte]) -> Result<SaveState, StateError> {
+        let mut reader = Reader { bytes, position: 0 };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(StateError::InvalidMagic);
+        }
+        let version = reader.byte()?;
+        if version != VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+
+        let platform = match reader.byte()? {
+            0 => Platform::Chip8,
+            1 => Platform::SuperChip,
+            2 => Platform::XoChip,
+            3 => Platform::HiresChip8,
+            _ => return Err(StateError::Invalid("platform")),
+        };
+        let quirk_bits = reader.byte()?;
+        let mut quirks = Quirks::for_platform(platform);
+        for (bit, &quirk) in Quirk::ALL.iter().enumerate() {
+            quirks.set(quirk, quirk_bits & 1 << bit != 0);
+        }
+        let clock_speed = reader.u32()?;
+        let timing = match reader.byte()? {
+            0 => Timing::Fixed,
+            1 => Timing::Vip,
+            _ => return Err(StateError::Invalid("timing")),
+        };
+        let mut palette = Palette::default();
+        palette.background.copy_from_slice(reader.take(3)?);
+        palette.foreground.copy_from_slice(reader.take(3)?);
+        let stack_depth = reader.byte()? as usize;
+        let stack_policy = match reader.byte()? {
+            0 => StackPolicy::Fault,
+            1 => StackPolicy::Wrap,
+            2 => StackPolicy::Ignore,
+            _ => return Err(StateError::Invalid("stack policy")),
+        };
+
+        let mut registers = Registers::new();
+        registers.vs.copy_from_slice(reader.take(16)?);
+        registers.i = reader.u16()?;
+        registers.sound_timer = reader.byte()?;
+        registers.delay_timer = reader.byte()?;
+        registers.program_counter = reader.u16()?;
+
+        let stack_pointer = reader.byte()? as usize;
+        let stack_size = reader.byte()? as usize;
+        let mut stack = Vec::with_capacity(stack_size);
+        for _ in 0..stack_size {
+            stack.push(reader.u16()?);
+        }
+
+        let memory = reader.take(MEMORY_SIZE)?.to_vec();
+
+        let width = reader.u16()? as usize;
+        let height = reader.u16()? as usize;
+        if !(8..=MAX_WIDTH).contains(&width) || !(1..=MAX_HEIGHT).contains(&height) {
+            return Err(StateError::Invalid("display resolution"));
+        }
+        let mut display = Display::with_resolution(width, height);
+        let pixels = reader.take((width * height).div_ceil(8))?.iter()
+            .flat_map(|byte| (0..8).map(move |bit| byte & 0x80 >> bit != 0))
+            .take(width * height);
+        for (index, is_set) in pixels.enumerate() {
+            display.set_pixel(index % width, index / width, is_set);
+        }
+
+        let keys = reader.u16()?;
+        let waiting_for_vblank = match reader.byte()? {
+            0 => false,
+            1 => true,
+            _ => return Err(StateError::Invalid("vblank wait")),
+        };
+
+        Ok(SaveState {
+            config: Config {
+                platform,
+                quirks,
+                clock_speed,
+                timing,
+                palette,
+                stack_depth,
+                stack_policy,
+            },
+            registers,
+            stack,
+            stack_pointer,
+            memory,
+            display,
+            keys,
+            waiting_for_vblank,
+        })
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [Byte],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, length: usize) -> Result<&'a [Byte], StateError> {
+        let bytes = self.bytes.get(self.position..self.position + length).ok_or(StateError::Truncated)?;
+        self.position += length;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<Byte, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok((bytes[0] as u16) << 8 | bytes[1] as u16)
+    }
+
+    fn u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(bytes.iter().fold(0, |value, &byte| value << 8 | byte as u32))
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    InvalidMagic,
+    UnsupportedVersion(Byte),
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::InvalidMagic => write!(f, "Not a save state"),
+            StateError::UnsupportedVersion(version) => write!(f, "Unsupported save state version {}", version),
+            StateError::Truncated => write!(f, "Save state is truncated"),
+            StateError::Invalid(field) => write!(f, "Save state has an invalid {}", field),
+        }
+    }
+}
+
+impl ::std::error::Error for StateError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::Cpu;
+
+    /// A state with the config and every part of the machine away from its defaults
+    fn state() -> SaveState {
+        let rom = [
+            0x60, 0x05, // 200 MOV V0, 05
+            0xA2, 0x0A, // 202 MOVI 20A
+            0x22, 0x08, // 204 JSR 208
+            0x12, 0x06, // 206 JMP 206
+            0xD0, 0x05, // 208 DRW V0, V0, 5
+            0xF0, 0x15, // 20A SDELAY V0
+        ];
+        let mut config = Config::for_platform(Platform::SuperChip);
+        config.timing = Timing::Vip;
+        config.stack_policy = StackPolicy::Wrap;
+        config.palette.foreground = [0x12, 0x34, 0x56];
+        let mut cpu = Cpu::with_config(&rom, config).unwrap();
+        cpu.run(5).unwrap();
+        cpu.pressed_key(0xA);
+        cpu.save_state()
+    }
+
+    #[test]
+    fn round_trip() {
+        let state = state();
+        assert_eq!(state.stack_pointer, 1);
+        assert!(state.display.pixels().any(|(_, _, is_set)| is_set));
+        assert_eq!(SaveState::from_bytes(&state.to_bytes()), Ok(state));
+    }
+
+    #[test]
+    fn truncated() {
+        let bytes = state().to_bytes();
+        assert_eq!(SaveState::from_bytes(&[]), Err(StateError::Truncated));
+        assert_eq!(SaveState::from_bytes(&bytes[..bytes.len() - 1]), Err(StateError::Truncated));
+        assert_eq!(SaveState::from_bytes(&bytes[..MAGIC.len() + 1 + 100]), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn invalid_fields() {
+        let bytes = state().to_bytes();
+        let with = |index: usize, value: Byte| {
+            let mut bytes = bytes.clone();
+            bytes[index] = value;
+            SaveState::from_bytes(&bytes)
+        };
+        let last = bytes.len() - 1;
+        assert_eq!(with(0, b'X'), Err(StateError::InvalidMagic));
+        assert_eq!(with(4, VERSION - 1), Err(StateError::UnsupportedVersion(VERSION - 1)));
+        assert_eq!(with(5, 4), Err(StateError::Invalid("platform")));
+        // platform, quirks and the clock speed come before the timing
+        assert_eq!(with(11, 2), Err(StateError::Invalid("timing")));
+        assert_eq!(with(last, 2), Err(StateError::Invalid("vblank wait")));
+    }
+}
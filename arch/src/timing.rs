@@ -0,0 +1,140 @@
+use {Address, Byte};
+use instructions::Instruction;
+use instructions::Instruction::*;
+use registers::Registers;
+
+/// Machine cycles the VIP's CDP1802 runs per second, one every 8 ticks of its 1.76064 MHz clock
+pub const VIP_CYCLES_PER_SECOND: u32 = 220_080;
+
+/// Machine cycles between two of the 60 Hz display interrupts
+pub const VIP_CYCLES_PER_FRAME: u32 = VIP_CYCLES_PER_SECOND / 60;
+
+/// Machine cycles the CDP1861 takes from the cpu every frame, one per byte of the 128 lines it
+/// shows, each of the 32 display rows being repeated 4 times
+pub const DISPLAY_DMA_CYCLES: u32 = 1024;
+
+/// Machine cycles of the interrupt routine, which sets up the DMA and counts the timers down
+pub const INTERRUPT_ROUTINE_CYCLES: u32 = 46;
+
+/// What is left of each frame for the program
+pub const PROGRAM_CYCLES_PER_FRAME: u32 = VIP_CYCLES_PER_FRAME - DISPLAY_DMA_CYCLES - INTERRUPT_ROUTINE_CYCLES;
+
+/// Fetching and decoding, which every instruction goes through before its routine
+const FETCH_CYCLES: u32 = 40;
+
+/// Taking a skip costs the extra branch in the interpreter
+const SKIP_CYCLES: u32 = 4;
+
+/// DRW: looking up the sprite and coordinates, per sprite row copied and per bit a row is shifted
+/// to line up with the display's bytes
+const DRAW_SETUP_CYCLES: u32 = 26;
+const DRAW_ROW_CYCLES: u32 = 24;
+const DRAW_SHIFT_CYCLES: u32 = 4;
+
+/// DRW rows not on a byte boundary also take a second display byte
+const DRAW_SPLIT_ROW_CYCLES: u32 = 10;
+
+/// The machine cycles an instruction took on the VIP, approximated from the structure of the
+/// interpreter's routines.
+///
+/// Takes the registers before the instruction ran and the program counter after, which tells
+/// whether a skip was taken.
+pub fn cycles(instruction: &Instruction, before: &Registers, program_counter: Address) -> u32 {
+    let skipped = program_counter == before.program_counter.wrapping_add(4);
+    let skip = |cycles: u32| if skipped { cycles + SKIP_CYCLES } else { cycles };
+    let v = |reg_id: Byte| before.vs[reg_id as usize & 0x0F] as u32;
+
+    FETCH_CYCLES + match *instruction {
+        // a machine code routine of the program's own, nothing to go by
+        SYS { .. } => 0,
+        // clears the 256 bytes of display memory in a loop
+        CLS => 3_078,
+        // the preamble's routine clears the hi-res display's 512 bytes the same way
+        HCLS => 6_156,
+        RTS => 10,
+        JMP { .. } => 12,
+        JSR { .. } => 26,
+        SE { .. } | SNE { .. } => skip(10),
+        SEXY { .. } | SNEXY { .. } | SKP { .. } | SKNP { .. } => skip(14),
+        MOV { .. } => 6,
+        ADD { .. } | GDELAY { .. } | SDELAY { .. } | SSOUND { .. } => 10,
+        // the 8XYN routines are assembled in memory and run from there
+        MOVXY { .. } | ORXY { .. } | ANDXY { .. } | XORXY { .. } | ADDXY { .. } | SUBXY { .. } |
+        SHR { .. } | RSUBXY { .. } | SHL { .. } => 20,
+        MOVI { .. } => 12,
+        JMI { .. } => 22,
+        RAND { .. } => 36,
+        DRW { x_reg_id, value, .. } => {
+            let shift = v(x_reg_id) % 8;
+            let row = DRAW_ROW_CYCLES + shift * DRAW_SHIFT_CYCLES + if shift != 0 { DRAW_SPLIT_ROW_CYCLES } else { 0 };
+            DRAW_SETUP_CYCLES + value as u32 * row
+        },
+        // polled once per pass, the interpreter stays on it until a key is pressed
+        KEY { .. } => 14,
+        ADI { .. } | FONT { .. } => 16,
+        // the digits are found by repeated subtraction, one loop per unit of each
+        BCD { reg_id } => {
+            let value = v(reg_id);
+            84 + 16 * (value / 100 + value / 10 % 10 + value % 10)
+        },
+        STR { reg_id } | LDR { reg_id } => 14 + 14 * (reg_id as u32 + 1),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cpu::Cpu;
+
+    /// Registers with V0 set, at the program counter 200
+    fn registers(v0: Byte) -> Registers {
+        let mut registers = Registers::new();
+        registers.vs[0] = v0;
+        registers.program_counter = 0x200;
+        registers
+    }
+
+    #[test]
+    fn draw_by_height_and_alignment() {
+        let draw = |x: Byte, value: Byte| cycles(&DRW { x_reg_id: 0, y_reg_id: 1, value }, &registers(x), 0x202);
+        assert_eq!(draw(8, 0), FETCH_CYCLES + DRAW_SETUP_CYCLES);
+        assert_eq!(draw(8, 1), FETCH_CYCLES + DRAW_SETUP_CYCLES + DRAW_ROW_CYCLES);
+        assert_eq!(draw(8, 5), FETCH_CYCLES + DRAW_SETUP_CYCLES + 5 * DRAW_ROW_CYCLES);
+        // shifted by 3 bits into a second byte
+        assert_eq!(draw(11, 5), FETCH_CYCLES + DRAW_SETUP_CYCLES + 5 * (DRAW_ROW_CYCLES + 3 * DRAW_SHIFT_CYCLES + DRAW_SPLIT_ROW_CYCLES));
+    }
+
+    #[test]
+    fn taken_skip() {
+        let se = SE { reg_id: 0, value: 0 };
+        assert_eq!(cycles(&se, &registers(1), 0x202), FETCH_CYCLES + 10);
+        assert_eq!(cycles(&se, &registers(0), 0x204), FETCH_CYCLES + 10 + SKIP_CYCLES);
+        let skp = SKP { reg_id: 0 };
+        assert_eq!(cycles(&skp, &registers(0), 0x204), FETCH_CYCLES + 14 + SKIP_CYCLES);
+    }
+
+    #[test]
+    fn bcd_by_value() {
+        let bcd = |value: Byte| cycles(&BCD { reg_id: 0 }, &registers(value), 0x202);
+        assert_eq!(bcd(0), FETCH_CYCLES + 84);
+        assert_eq!(bcd(9), FETCH_CYCLES + 84 + 16 * 9);
+        assert_eq!(bcd(100), FETCH_CYCLES + 84 + 16);
+        assert_eq!(bcd(255), FETCH_CYCLES + 84 + 16 * (2 + 5 + 5));
+    }
+
+    /// A frame runs instructions until its cycles are spent, the last one running over
+    #[test]
+    fn frame_stops_at_program_cycles() {
+        let rom = [
+            0x12, 0x00, // 200 JMP 200
+        ];
+        let jump = FETCH_CYCLES + 12;
+        let mut cpu = Cpu::new(&rom).unwrap();
+        for frame in 1..=3 {
+            cpu.run_frame().unwrap();
+            let end = frame * PROGRAM_CYCLES_PER_FRAME as u64;
+            assert!(cpu.cycles() >= end && cpu.cycles() < end + jump as u64, "frame {} ended at {}", frame, cpu.cycles());
+            assert_eq!(cpu.cycles() % jump as u64, 0);
+        }
+    }
+}
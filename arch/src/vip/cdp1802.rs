@@ -0,0 +1,519 @@
+use {Byte};
+
+/// What the CDP1802 is wired to
+pub trait Bus {
+    fn read(&mut self, address: u16) -> Byte;
+    fn write(&mut self, address: u16, value: Byte);
+
+    /// OUT 1 to 7, value is the byte at R(X)
+    fn output(&mut self, port: Byte, value: Byte);
+
+    /// INP 1 to 7, the byte on the bus
+    fn input(&mut self, port: Byte) -> Byte;
+
+    /// EF1 to EF4
+    fn flag(&self, flag: Byte) -> bool;
+}
+
+/// Cdp1802
+///
+/// The RCA CDP1802 COSMAC microprocessor: sixteen 16 bit registers, any of which can be the
+/// program counter (R(P)) or the data pointer (R(X)), an 8 bit accumulator D and a carry flag DF.
+/// Every instruction takes 2 machine cycles, the long branches and skips 3.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cdp1802 {
+    pub r: [u16; 16],
+    pub p: Byte,
+    pub x: Byte,
+    pub d: Byte,
+    pub df: bool,
+
+    /// X and P saved by an interrupt or MARK
+    pub t: Byte,
+    pub ie: bool,
+    pub q: bool,
+
+    /// stopped by IDL until a DMA or interrupt
+    pub idle: bool,
+}
+
+impl Cdp1802 {
+    /// As after a reset: everything that matters cleared, interrupts enabled
+    pub fn new() -> Cdp1802 {
+        Cdp1802 {
+            r: [0; 16],
+            p: 0,
+            x: 0,
+            d: 0,
+            df: false,
+            t: 0,
+            ie: true,
+            q: false,
+            idle: false,
+        }
+    }
+
+    /// Take an interrupt if they are enabled: X and P go to T, P becomes 1 and X 2.
+    /// Returns the machine cycles it took.
+    pub fn interrupt(&mut self) -> u32 {
+        if !self.ie {
+            return 0;
+        }
+        self.t = self.x << 4 | self.p;
+        self.p = 1;
+        self.x = 2;
+        self.ie = false;
+        self.idle = false;
+        1
+    }
+
+    /// A DMA out cycle, the byte at R0 goes to the device and R0 moves on
+    pub fn dma_out<B: Bus>(&mut self, bus: &mut B) -> Byte {
+        let value = bus.read(self.r[0]);
+        self.r[0] = self.r[0].wrapping_add(1);
+        self.idle = false;
+        value
+    }
+
+    fn fetch<B: Bus>(&mut self, bus: &mut B) -> Byte {
+        let p = self.p as usize;
+        let value = bus.read(self.r[p]);
+        self.r[p] = self.r[p].wrapping_add(1);
+        value
+    }
+
+    fn rx(&mut self) -> &mut u16 {
+        &mut self.r[self.x as usize]
+    }
+
+    /// D = a + b + carry, DF = carry out
+    fn add(&mut self, a: Byte, b: Byte, carry: bool) {
+        let sum = a as u16 + b as u16 + carry as u16;
+        self.d = sum as Byte;
+        self.df = sum > 0xFF;
+    }
+
+    /// D = a - b - borrow, DF = no borrow out
+    fn subtract(&mut self, a: Byte, b: Byte, borrow: bool) {
+        let difference = a as i16 - b as i16 - borrow as i16;
+        self.d = difference as Byte;
+        self.df = difference >= 0;
+    }
+
+    /// Execute one instruction, returning the machine cycles it took. Idle, it only lets a cycle pass.
+    pub fn step<B: Bus>(&mut self, bus: &mut B) -> u32 {
+        if self.idle {
+            return 1;
+        }
+
+        let opcode = self.fetch(bus);
+        let n = opcode & 0x0F;
+        let rn = n as usize;
+        let p = self.p as usize;
+
+        match opcode >> 4 {
+            0x0 if n == 0 => self.idle = true,
+            0x0 => self.d = bus.read(self.r[rn]),
+            0x1 => self.r[rn] = self.r[rn].wrapping_add(1),
+            0x2 => self.r[rn] = self.r[rn].wrapping_sub(1),
+            0x3 => {
+                let target = bus.read(self.r[p]);
+                let taken = match n & 0x7 {
+                    0 => true,
+                    1 => self.q,
+                    2 => self.d == 0,
+                    3 => self.df,
+                    flag => bus.flag(flag - 3),
+                };
+                // 38 is NBR, the skip which never branches
+                if n != 0x8 && taken != (n & 0x8 != 0) {
+                    self.r[p] = self.r[p] & 0xFF00 | target as u16;
+                } else {
+                    self.r[p] = self.r[p].wrapping_add(1);
+                }
+            },
+            0x4 => {
+                self.d = bus.read(self.r[rn]);
+                self.r[rn] = self.r[rn].wrapping_add(1);
+            },
+            0x5 => bus.write(self.r[rn], self.d),
+            0x6 => match n {
+                0x0 => *self.rx() = self.rx().wrapping_add(1),
+                0x1..=0x7 => {
+                    let address = *self.rx();
+                    let value = bus.read(address);
+                    bus.output(n, value);
+                    *self.rx() = address.wrapping_add(1);
+                },
+                // 68 is only an instruction on the CDP1804 and later
+                0x8 => {},
+                _ => {
+                    let value = bus.input(n - 8);
+                    let address = *self.rx();
+                    bus.write(address, value);
+                    self.d = value;
+                },
+            },
+            0x7 => self.step_7(n, bus),
+            0x8 => self.d = self.r[rn] as Byte,
+            0x9 => self.d = (self.r[rn] >> 8) as Byte,
+            0xA => self.r[rn] = self.r[rn] & 0xFF00 | self.d as u16,
+            0xB => self.r[rn] = self.r[rn] & 0x00FF | (self.d as u16) << 8,
+            0xC => {
+                self.step_long(n, bus);
+                return 3;
+            },
+            0xD => self.p = n,
+            0xE => self.x = n,
+            _ => self.step_f(n, bus),
+        }
+        2
+    }
+
+    fn step_7<B: Bus>(&mut self, n: Byte, bus: &mut B) {
+        let address = *self.rx();
+        match n {
+            // RET and DIS
+            0x0 | 0x1 => {
+                let value = bus.read(address);
+                *self.rx() = address.wrapping_add(1);
+                self.x = value >> 4;
+                self.p = value & 0x0F;
+                self.ie = n == 0x0;
+            },
+            // LDXA
+            0x2 => {
+                self.d = bus.read(address);
+                *self.rx() = address.wrapping_add(1);
+            },
+            // STXD
+            0x3 => {
+                bus.write(address, self.d);
+                *self.rx() = address.wrapping_sub(1);
+            },
+            // ADC, SDB, SMB
+            0x4 => {
+                let value = bus.read(address);
+                let (d, df) = (self.d, self.df);
+                self.add(value, d, df);
+            },
+            0x5 => {
+                let value = bus.read(address);
+                let (d, df) = (self.d, self.df);
+                self.subtract(value, d, !df);
+            },
+            0x7 => {
+                let value = bus.read(address);
+                let (d, df) = (self.d, self.df);
+                self.subtract(d, value, !df);
+            },
+            // SHRC and SHLC, shifting through DF
+            0x6 => {
+                let carry = self.d & 0x01 != 0;
+                self.d = self.d >> 1 | (self.df as Byte) << 7;
+                self.df = carry;
+            },
+            0xE => {
+                let carry = self.d & 0x80 != 0;
+                self.d = self.d << 1 | self.df as Byte;
+                self.df = carry;
+            },
+            // SAV
+            0x8 => bus.write(address, self.t),
+            // MARK
+            0x9 => {
+                self.t = self.x << 4 | self.p;
+                bus.write(self.r[2], self.t);
+                self.x = self.p;
+                self.r[2] = self.r[2].wrapping_sub(1);
+            },
+            // REQ and SEQ
+            0xA => self.q = false,
+            0xB => self.q = true,
+            // ADCI, SDBI, SMBI
+            0xC => {
+                let value = self.fetch(bus);
+                let (d, df) = (self.d, self.df);
+                self.add(value, d, df);
+            },
+            0xD => {
+                let value = self.fetch(bus);
+                let (d, df) = (self.d, self.df);
+                self.subtract(value, d, !df);
+            },
+            _ => {
+                let value = self.fetch(bus);
+                let (d, df) = (self.d, self.df);
+                self.subtract(d, value, !df);
+            },
+        }
+    }
+
+    /// Long branches and skips
+    fn step_long<B: Bus>(&mut self, n: Byte, bus: &mut B) {
+        let condition = match n & 0x3 {
+            0 => true,
+            1 => self.q,
+            2 => self.d == 0,
+            _ => self.df,
+        };
+        let p = self.p as usize;
+        match n {
+            // NOP
+            0x4 => {},
+            // LSIE
+            0xC => if self.ie {
+                self.r[p] = self.r[p].wrapping_add(2);
+            },
+            // the branches, C8 is LSKP, the long skip which never branches
+            0x0..=0x3 | 0x8..=0xB => {
+                let taken = if n == 0x8 { false } else { condition != (n & 0x8 != 0) };
+                if taken {
+                    let high = bus.read(self.r[p]) as u16;
+                    let low = bus.read(self.r[p].wrapping_add(1)) as u16;
+                    self.r[p] = high << 8 | low;
+                } else {
+                    self.r[p] = self.r[p].wrapping_add(2);
+                }
+            },
+            // the skips: C5 LSNQ, C6 LSNZ, C7 LSNF, CD LSQ, CE LSZ, CF LSDF
+            _ => {
+                let skip = condition == (n & 0x8 != 0);
+                if skip {
+                    self.r[p] = self.r[p].wrapping_add(2);
+                }
+            },
+        }
+    }
+
+    fn step_f<B: Bus>(&mut self, n: Byte, bus: &mut B) {
+        let d = self.d;
+        // SHR and SHL take no operand
+        match n {
+            0x6 => {
+                self.df = d & 0x01 != 0;
+                self.d = d >> 1;
+                return;
+            },
+            0xE => {
+                self.df = d & 0x80 != 0;
+                self.d = d << 1;
+                return;
+            },
+            _ => {},
+        }
+
+        // F0 to F7 work on the byte at R(X), F8 to FF on the next byte of the program
+        let value = if n < 0x8 {
+            let address = *self.rx();
+            bus.read(address)
+        } else {
+            self.fetch(bus)
+        };
+        match n & 0x7 {
+            0x0 => self.d = value,
+            0x1 => self.d |= value,
+            0x2 => self.d &= value,
+            0x3 => self.d ^= value,
+            0x4 => self.add(value, d, false),
+            0x5 => self.subtract(value, d, false),
+            _ => self.subtract(d, value, false),
+        }
+    }
+}
+
+impl Default for Cdp1802 {
+    fn default() -> Cdp1802 {
+        Cdp1802::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 64K of RAM and the EF lines
+    struct TestBus {
+        memory: Vec<Byte>,
+        flags: [bool; 4],
+    }
+
+    impl Bus for TestBus {
+        fn read(&mut self, address: u16) -> Byte {
+            self.memory[address as usize]
+        }
+
+        fn write(&mut self, address: u16, value: Byte) {
+            self.memory[address as usize] = value;
+        }
+
+        fn output(&mut self, _port: Byte, _value: Byte) {}
+
+        fn input(&mut self, _port: Byte) -> Byte {
+            0
+        }
+
+        fn flag(&self, flag: Byte) -> bool {
+            self.flags[flag as usize - 1]
+        }
+    }
+
+    /// A 1802 about to run program from 0000, with R0 as the program counter
+    fn machine(program: &[Byte]) -> (Cdp1802, TestBus) {
+        let mut bus = TestBus {
+            memory: vec![0; 0x10000],
+            flags: [false; 4],
+        };
+        bus.memory[..program.len()].copy_from_slice(program);
+        (Cdp1802::new(), bus)
+    }
+
+    /// Where the program counter goes after a branch or skip, with D and DF set
+    fn branch(program: &[Byte], d: Byte, df: bool) -> u16 {
+        let (mut cpu, mut bus) = machine(program);
+        cpu.d = d;
+        cpu.df = df;
+        cpu.step(&mut bus);
+        cpu.r[0]
+    }
+
+    #[test]
+    fn short_branch_polarity() {
+        assert_eq!(branch(&[0x30, 0x40], 1, false), 0x40);
+        assert_eq!(branch(&[0x32, 0x40], 0, false), 0x40);
+        assert_eq!(branch(&[0x32, 0x40], 1, false), 0x02);
+        assert_eq!(branch(&[0x3A, 0x40], 0, false), 0x02);
+        assert_eq!(branch(&[0x3A, 0x40], 1, false), 0x40);
+        assert_eq!(branch(&[0x33, 0x40], 0, true), 0x40);
+        assert_eq!(branch(&[0x3B, 0x40], 0, true), 0x02);
+        // SKP never branches
+        assert_eq!(branch(&[0x38, 0x40], 0, true), 0x02);
+
+        let (mut cpu, mut bus) = machine(&[0x36, 0x40, 0x3E, 0x40]);
+        bus.flags[2] = true;
+        cpu.step(&mut bus);
+        assert_eq!(cpu.r[0], 0x40);
+        cpu.r[0] = 0x02;
+        cpu.step(&mut bus);
+        assert_eq!(cpu.r[0], 0x04);
+    }
+
+    #[test]
+    fn long_branch_and_skip_polarity() {
+        assert_eq!(branch(&[0xC2, 0x12, 0x34], 0, false), 0x1234);
+        assert_eq!(branch(&[0xC2, 0x12, 0x34], 1, false), 0x03);
+        assert_eq!(branch(&[0xCA, 0x12, 0x34], 1, false), 0x1234);
+        assert_eq!(branch(&[0xCB, 0x12, 0x34], 0, true), 0x03);
+        // LSKP always skips, never branches
+        assert_eq!(branch(&[0xC8, 0x12, 0x34], 0, true), 0x03);
+
+        assert_eq!(branch(&[0xCE], 0, false), 0x03);
+        assert_eq!(branch(&[0xCE], 1, false), 0x01);
+        assert_eq!(branch(&[0xC6], 1, false), 0x03);
+        assert_eq!(branch(&[0xCF], 0, true), 0x03);
+        assert_eq!(branch(&[0xC7], 0, true), 0x01);
+        // Q is off after a reset
+        assert_eq!(branch(&[0xC5], 0, false), 0x03);
+        assert_eq!(branch(&[0xCD], 0, false), 0x01);
+    }
+
+    /// D and DF after a subtraction of the byte at R(X) or of an immediate byte, X being R5 pointing at 0040
+    fn subtract(opcode: Byte, memory: Byte, d: Byte, df: bool) -> (Byte, bool) {
+        let (mut cpu, mut bus) = machine(&[opcode, memory]);
+        bus.memory[0x40] = memory;
+        cpu.x = 5;
+        cpu.r[5] = 0x40;
+        cpu.d = d;
+        cpu.df = df;
+        cpu.step(&mut bus);
+        (cpu.d, cpu.df)
+    }
+
+    #[test]
+    fn subtract_borrow() {
+        // SD: M(R(X)) - D, DF set when nothing was borrowed
+        assert_eq!(subtract(0xF5, 5, 3, false), (2, true));
+        assert_eq!(subtract(0xF5, 3, 5, true), (0xFE, false));
+        // SM: D - M(R(X))
+        assert_eq!(subtract(0xF7, 5, 3, true), (0xFE, false));
+        assert_eq!(subtract(0xF7, 3, 5, false), (2, true));
+        // SDB and SMB subtract the borrow of a clear DF
+        assert_eq!(subtract(0x75, 5, 3, false), (1, true));
+        assert_eq!(subtract(0x75, 5, 3, true), (2, true));
+        assert_eq!(subtract(0x77, 3, 3, false), (0xFF, false));
+        assert_eq!(subtract(0x77, 3, 3, true), (0, true));
+        // SDBI and SMBI take the next byte of the program
+        assert_eq!(subtract(0x7D, 5, 3, false), (1, true));
+        assert_eq!(subtract(0x7F, 3, 3, false), (0xFF, false));
+    }
+
+    /// MARK saves X and P, the subroutine returns to them with RET
+    #[test]
+    fn mark_and_return() {
+        let (mut cpu, mut bus) = machine(&[
+            0x79, // MARK
+            0xD3, // SEP R3
+        ]);
+        bus.memory[0x40..0x43].copy_from_slice(&[
+            0xE2, // SEX R2
+            0x12, // INC R2
+            0x70, // RET
+        ]);
+        cpu.x = 5;
+        cpu.r[2] = 0x80;
+        cpu.r[3] = 0x40;
+        cpu.ie = false;
+
+        cpu.step(&mut bus);
+        assert_eq!((cpu.t, cpu.x, cpu.p), (0x50, 0, 0));
+        assert_eq!(bus.memory[0x80], 0x50);
+        assert_eq!(cpu.r[2], 0x7F);
+
+        for _ in 0..4 {
+            cpu.step(&mut bus);
+        }
+        assert_eq!((cpu.x, cpu.p), (5, 0));
+        assert_eq!(cpu.r[0], 0x02);
+        assert_eq!(cpu.r[2], 0x81);
+        assert!(cpu.ie);
+    }
+
+    /// SAV stores the X and P an interrupt saved in T
+    #[test]
+    fn interrupt_and_save() {
+        let (mut cpu, mut bus) = machine(&[]);
+        bus.memory[0x40] = 0x78;
+        cpu.x = 5;
+        cpu.r[1] = 0x40;
+        cpu.r[2] = 0x80;
+
+        assert_eq!(cpu.interrupt(), 1);
+        assert_eq!((cpu.t, cpu.x, cpu.p), (0x50, 2, 1));
+        assert!(!cpu.ie);
+        // interrupts are off until the routine returns
+        assert_eq!(cpu.interrupt(), 0);
+
+        cpu.step(&mut bus);
+        assert_eq!(bus.memory[0x80], 0x50);
+    }
+
+    #[test]
+    fn machine_cycles() {
+        let cycles = |program: &[Byte]| {
+            let (mut cpu, mut bus) = machine(program);
+            cpu.step(&mut bus)
+        };
+        assert_eq!(cycles(&[0xF8, 0x12]), 2);
+        assert_eq!(cycles(&[0x30, 0x40]), 2);
+        assert_eq!(cycles(&[0x79]), 2);
+        assert_eq!(cycles(&[0xC0, 0x12, 0x34]), 3);
+        assert_eq!(cycles(&[0xC4]), 3);
+        assert_eq!(cycles(&[0xC8]), 3);
+        assert_eq!(cycles(&[0xCD]), 3);
+
+        let (mut cpu, mut bus) = machine(&[0x00]);
+        assert_eq!(cpu.step(&mut bus), 2);
+        assert!(cpu.idle);
+        assert_eq!(cpu.step(&mut bus), 1);
+    }
+}
@@ -0,0 +1,390 @@
+use std::fmt;
+
+use {Byte};
+use display::{Display, RESOLUTION_HEIGHT, RESOLUTION_WIDTH};
+use keyboard::Keyboard;
+use memory::PROGRAM_OFFSET;
+use timing::VIP_CYCLES_PER_FRAME;
+
+pub mod cdp1802;
+
+use self::cdp1802::{Bus, Cdp1802};
+
+/// RAM of the VIP CHIP-8 was written for
+pub const VIP_RAM_SIZE: usize = 4096;
+
+/// Where the monitor ROM sits, it is mirrored over the upper half of the address space
+pub const MONITOR_OFFSET: usize = 0x8000;
+pub const MONITOR_SIZE: usize = 512;
+
+/// Room for the interpreter, below the program
+pub const INTERPRETER_SIZE: usize = PROGRAM_OFFSET;
+
+/// The CDP1861 draws 262 lines a frame, 14 machine cycles each
+const CYCLES_PER_LINE: u32 = 14;
+const LINES_PER_FRAME: u32 = VIP_CYCLES_PER_FRAME / CYCLES_PER_LINE;
+
+/// Of which 128 show the display, 8 DMA bytes of 8 pixels each
+const FIRST_DISPLAY_LINE: u32 = 80;
+const DISPLAY_LINES: u32 = 128;
+const BYTES_PER_LINE: usize = 8;
+
+/// The interrupt comes 2 lines before the display starts, for the routine to set up R0
+const INTERRUPT_LINE: u32 = FIRST_DISPLAY_LINE - 2;
+
+/// EF1 tells the routine the display is about to start or end, 4 lines ahead
+const EF1_LINES: u32 = 4;
+
+/// Vip
+///
+/// The RCA COSMAC VIP: a CDP1802 running the original CHIP-8 interpreter, with a CDP1861 showing
+/// the display page over DMA and interrupting at 60 Hz, and the hex keypad on EF3.
+///
+/// Neither the monitor ROM nor the interpreter are bundled. The machine starts from a reset with
+/// the monitor mapped at 0000, as the hardware does, and the monitor goes on to run the interpreter.
+/// The interpreter points R1 at its own interrupt routine, which sets R0 to the display page every frame.
+///
+/// Timing is exact to the instruction: DMA and interrupts are taken between instructions,
+/// as the 1802 does, at the cycle they are due or right after.
+pub struct Vip {
+    cpu: Cdp1802,
+    io: Io,
+
+    /// machine cycles into the current frame
+    frame_cycle: u32,
+
+    /// the next line the CDP1861 handles
+    line: u32,
+}
+
+/// Everything on the bus of the 1802
+struct Io {
+    ram: Vec<Byte>,
+    monitor: Vec<Byte>,
+
+    /// after a reset the monitor shows at 0000 as well, until an address in its own range is read
+    monitor_at_zero: bool,
+
+    /// the CDP1861 is turned on by INP 1 and off by OUT 1
+    display_enabled: bool,
+    display: Display,
+
+    /// EF1 of the CDP1861
+    display_flag: bool,
+
+    /// the key OUT 2 selects for EF3
+    key_latch: Byte,
+    keyboard: Keyboard,
+}
+
+impl Bus for Io {
+    fn read(&mut self, address: u16) -> Byte {
+        let address = address as usize;
+        if address >= MONITOR_OFFSET {
+            self.monitor_at_zero = false;
+            return self.monitor[address % MONITOR_SIZE];
+        }
+        if self.monitor_at_zero {
+            return self.monitor[address % MONITOR_SIZE];
+        }
+        self.ram[address % self.ram.len()]
+    }
+
+    fn write(&mut self, address: u16, value: Byte) {
+        let address = address as usize;
+        if address < MONITOR_OFFSET {
+            let size = self.ram.len();
+            self.ram[address % size] = value;
+        }
+    }
+
+    fn output(&mut self, port: Byte, value: Byte) {
+        match port {
+            1 => self.display_enabled = false,
+            2 => self.key_latch = value & 0x0F,
+            _ => {},
+        }
+    }
+
+    fn input(&mut self, port: Byte) -> Byte {
+        if port == 1 {
+            self.display_enabled = true;
+        }
+        0
+    }
+
+    fn flag(&self, flag: Byte) -> bool {
+        match flag {
+            1 => self.display_flag,
+            3 => self.keyboard.is_pressed(self.key_latch),
+            _ => false,
+        }
+    }
+}
+
+impl Vip {
+    /// A VIP with 4K of RAM, the interpreter at 0000 and the program at 0200
+    pub fn new(monitor: &[Byte], interpreter: &[Byte], program: &[Byte]) -> Result<Vip, VipError> {
+        if monitor.len() != MONITOR_SIZE {
+            return Err(VipError::MonitorSize(monitor.len()));
+        }
+        if interpreter.len() > INTERPRETER_SIZE {
+            return Err(VipError::InterpreterTooLarge(interpreter.len()));
+        }
+        if program.len() > VIP_RAM_SIZE - PROGRAM_OFFSET {
+            return Err(VipError::ProgramTooLarge(program.len()));
+        }
+
+        let mut ram = vec![0; VIP_RAM_SIZE];
+        ram[..interpreter.len()].copy_from_slice(interpreter);
+        ram[PROGRAM_OFFSET..PROGRAM_OFFSET + program.len()].copy_from_slice(program);
+
+        Ok(Vip {
+            cpu: Cdp1802::new(),
+            io: Io {
+                ram,
+                monitor: monitor.to_vec(),
+                monitor_at_zero: true,
+                display_enabled: false,
+                display: Display::new(),
+                display_flag: false,
+                key_latch: 0,
+                keyboard: Keyboard::new(),
+            },
+            frame_cycle: 0,
+            line: 0,
+        })
+    }
+
+    /// Run until the end of the current 60 Hz frame
+    pub fn run_frame(&mut self) {
+        while self.frame_cycle < VIP_CYCLES_PER_FRAME {
+            self.run_line_events();
+            self.frame_cycle += self.cpu.step(&mut self.io);
+        }
+        self.frame_cycle -= VIP_CYCLES_PER_FRAME;
+        self.line = 0;
+    }
+
+    /// Whatever the CDP1861 does at the lines started by now
+    fn run_line_events(&mut self) {
+        while self.line < LINES_PER_FRAME && self.line * CYCLES_PER_LINE <= self.frame_cycle {
+            let line = self.line;
+            self.line += 1;
+
+            let display_end = FIRST_DISPLAY_LINE + DISPLAY_LINES;
+            self.io.display_flag = self.io.display_enabled && (
+                (FIRST_DISPLAY_LINE - EF1_LINES..FIRST_DISPLAY_LINE).contains(&line) ||
+                (display_end - EF1_LINES..display_end).contains(&line)
+            );
+            if !self.io.display_enabled {
+                continue;
+            }
+
+            if line == INTERRUPT_LINE {
+                self.frame_cycle += self.cpu.interrupt();
+            }
+            if (FIRST_DISPLAY_LINE..display_end).contains(&line) {
+                self.dma_line(line - FIRST_DISPLAY_LINE);
+            }
+        }
+    }
+
+    /// 8 bytes from R0 onto a display line, each row of the CHIP-8 display is shown on 4 of them
+    fn dma_line(&mut self, line: u32) {
+        let y = line as usize * RESOLUTION_HEIGHT / DISPLAY_LINES as usize;
+        for byte_index in 0..BYTES_PER_LINE {
+            let byte = self.cpu.dma_out(&mut self.io);
+            for bit in 0..8 {
+                let x = byte_index * 8 + bit;
+                if x < RESOLUTION_WIDTH {
+                    self.io.display.set_pixel(x, y, byte & 0x80 >> bit != 0);
+                }
+            }
+        }
+        self.frame_cycle += BYTES_PER_LINE as u32;
+    }
+
+    pub fn pressed_key(&mut self, key: Byte) {
+        self.io.keyboard.set_pressed(key);
+    }
+
+    pub fn released_key(&mut self, key: Byte) {
+        self.io.keyboard.set_released(key);
+    }
+
+    pub fn get_display(&self) -> &Display {
+        &self.io.display
+    }
+
+    /// The display as the CDP1861 last showed it, renderers get what changed since the last frame
+    pub fn get_display_mut(&mut self) -> &mut Display {
+        &mut self.io.display
+    }
+
+    /// Q drives the VIP's tone generator
+    pub fn is_sound_on(&self) -> bool {
+        self.cpu.q
+    }
+
+    pub fn get_cpu(&self) -> &Cdp1802 {
+        &self.cpu
+    }
+
+    pub fn get_ram(&self) -> &[Byte] {
+        &self.io.ram
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum VipError {
+    MonitorSize(usize),
+    InterpreterTooLarge(usize),
+    ProgramTooLarge(usize),
+}
+
+impl fmt::Display for VipError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            VipError::MonitorSize(size) => write!(f, "The monitor ROM must be {} bytes, not {}", MONITOR_SIZE, size),
+            VipError::InterpreterTooLarge(size) => write!(f, "Interpreter of {} bytes doesn't fit below the program", size),
+            VipError::ProgramTooLarge(size) => write!(f, "Program of {} bytes doesn't fit in the VIP's RAM", size),
+        }
+    }
+}
+
+impl ::std::error::Error for VipError {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Marks R9 with AA from its reset mapping at 0000, then jumps into its own range at 8006,
+    /// which unmaps it, and from there to the interpreter at 0000
+    fn monitor() -> Vec<Byte> {
+        let mut monitor = vec![0; MONITOR_SIZE];
+        monitor[..9].copy_from_slice(&[
+            0xF8, 0xAA,       // 0000 LDI AA
+            0xA9,             // 0002 PLO R9
+            0xC0, 0x80, 0x06, // 0003 LBR 8006
+            0xC0, 0x00, 0x00, // 0006 LBR 0000
+        ]);
+        monitor
+    }
+
+    /// Turns the display on, then off once key 5 is pressed. The interrupt routine points R0
+    /// at the display page at 0200 and counts the interrupts in R5.
+    fn interpreter() -> Vec<Byte> {
+        let mut interpreter = vec![0; 0x4B];
+        let mut assemble = |address: usize, code: &[Byte]| interpreter[address..address + code.len()].copy_from_slice(code);
+        assemble(0x00, &[
+            0xF8, 0x10, // 0000 LDI 10
+            0xA3,       // 0002 PLO R3
+            0xD3,       // 0003 SEP R3
+        ]);
+        assemble(0x10, &[
+            0xF8, 0x40, // 0010 LDI 40
+            0xA1,       // 0012 PLO R1, the interrupt routine
+            0xF8, 0xF0, // 0013 LDI F0
+            0xA2,       // 0015 PLO R2, the stack
+            0xE2,       // 0016 SEX R2
+            0x69,       // 0017 INP 1, display on
+            0xF8, 0x05, // 0018 LDI 05
+            0x52,       // 001A STR R2
+            0x62,       // 001B OUT 2, latch key 5
+            0x22,       // 001C DEC R2
+            0x3E, 0x1D, // 001D BN3 1D
+            0x61,       // 001F OUT 1, display off
+            0x22,       // 0020 DEC R2
+            0x30, 0x21, // 0021 BR 21
+        ]);
+        assemble(0x3F, &[
+            0x70,       // 003F RET
+            0xF8, 0x02, // 0040 LDI 02
+            0xB0,       // 0042 PHI R0
+            0xF8, 0x00, // 0043 LDI 00
+            0xA0,       // 0045 PLO R0
+            0x15,       // 0046 INC R5
+            0x22,       // 0047 DEC R2
+            0x78,       // 0048 SAV
+            0x30, 0x3F, // 0049 BR 3F
+        ]);
+        interpreter
+    }
+
+    /// The display page: the top left pixel set in row 0 and the top right one in row 1,
+    /// on each of the 4 lines showing them
+    fn page() -> Vec<Byte> {
+        let mut page = vec![0; 32 * 4 * BYTES_PER_LINE];
+        for line in 0..4 {
+            page[line * BYTES_PER_LINE] = 0x80;
+            page[(4 + line) * BYTES_PER_LINE + 7] = 0x01;
+        }
+        page
+    }
+
+    fn vip() -> Vip {
+        Vip::new(&monitor(), &interpreter(), &page()).unwrap()
+    }
+
+    #[test]
+    fn monitor_mapped_at_reset() {
+        let mut vip = vip();
+        assert!(vip.io.monitor_at_zero);
+        vip.run_frame();
+        assert!(!vip.io.monitor_at_zero);
+        assert_eq!(vip.get_cpu().r[9], 0xAA);
+        // the interpreter set up its registers from RAM
+        assert_eq!((vip.get_cpu().p, vip.get_cpu().r[1], vip.get_cpu().r[2]), (3, 0x40, 0xF0));
+    }
+
+    #[test]
+    fn display_on_and_off() {
+        let mut vip = vip();
+        for frame in 1..=3 {
+            vip.run_frame();
+            assert!(vip.io.display_enabled);
+            assert_eq!(vip.get_cpu().r[5], frame);
+        }
+
+        // another key than the one latched doesn't matter
+        vip.pressed_key(4);
+        vip.run_frame();
+        assert!(vip.io.display_enabled);
+
+        vip.pressed_key(5);
+        vip.run_frame();
+        assert!(!vip.io.display_enabled);
+        assert_eq!(vip.io.key_latch, 5);
+        let interrupts = vip.get_cpu().r[5];
+        vip.run_frame();
+        assert_eq!(vip.get_cpu().r[5], interrupts);
+    }
+
+    #[test]
+    fn display_from_dma() {
+        let mut vip = vip();
+        vip.run_frame();
+        let display = vip.get_display();
+        assert!(display.pixel(0, 0) && display.pixel(63, 1));
+        assert_eq!(display.pixels().filter(|&(_, _, is_set)| is_set).count(), 2);
+        // R0 went over 8 bytes for each line shown
+        assert_eq!(vip.get_cpu().r[0], 0x200 + (DISPLAY_LINES as u16) * BYTES_PER_LINE as u16);
+    }
+
+    /// EF1 is set for the 4 lines before the display starts and before it ends,
+    /// the interrupt comes on the third of those at the start
+    #[test]
+    fn ef1_and_interrupt_lines() {
+        let mut vip = vip();
+        vip.io.display_enabled = true;
+        for line in 0..LINES_PER_FRAME {
+            vip.frame_cycle = line * CYCLES_PER_LINE;
+            vip.run_line_events();
+            let expected = (76..80).contains(&line) || (204..208).contains(&line);
+            assert_eq!(vip.io.display_flag, expected, "EF1 on line {}", line);
+            assert_eq!(vip.get_cpu().p == 1, line >= INTERRUPT_LINE, "interrupted on line {}", line);
+        }
+        assert_eq!(INTERRUPT_LINE, 78);
+    }
+}
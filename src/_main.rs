@@ -7,6 +7,7 @@ use std::fs::File;
 use std::{thread, time};
 
 use arch::cpu::Cpu;
+use arch::config::DEFAULT_CLOCK_SPEED;
 
 use console_renderer::ConsoleRenderer;
 
@@ -16,11 +17,13 @@ fn main() {
     let mut program_data = Vec::new();
     let mut file = File::open("d:/tmp/prog.ch8").expect("Unable to find program file");
     file.read_to_end(&mut program_data).expect("Failed to read program");
-    let mut cpu = Cpu::new(&program_data);
+    let mut cpu = Cpu::new(&program_data).expect("Unable to load program");
     
+    // a frame's worth of instructions, then the 60 Hz interrupt lets a DRW waiting for it go on
     loop {
-        cpu.tick();
-        cpu.render_pass(&mut renderer);
-        thread::sleep(time::Duration::from_secs(1/30));
+        cpu.run(DEFAULT_CLOCK_SPEED as usize / 60).expect("Program crashed");
+        cpu.interrupt();
+        cpu.render(&mut renderer);
+        thread::sleep(time::Duration::from_millis(1000 / 60));
     }
 }
\ No newline at end of file
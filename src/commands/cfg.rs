@@ -0,0 +1,31 @@
+use arch::analysis::Image;
+use arch::analysis::cfg::Cfg;
+use arch::container;
+
+use commands::{fail, read_file};
+
+const USAGE: &str = "usage: chip8 cfg <rom> [--dot]";
+
+/// chip8 cfg <rom> [--dot]
+///
+/// Prints the functions and unreachable regions of a ROM,
+/// or its whole control-flow graph as Graphviz DOT
+pub fn run(args: &[String]) {
+    let (path, dot) = match args {
+        [path] => (path, false),
+        [path, flag] if flag == "--dot" => (path, true),
+        _ => fail(USAGE),
+    };
+
+    let rom = container::decode(&read_file(path))
+        .unwrap_or_else(|e| fail(&format!("Unable to decode {}: {}", path, e)));
+
+    let image = Image::new(&rom.data);
+    let cfg = Cfg::build(&image);
+
+    if dot {
+        print!("{}", cfg.to_dot(&image));
+    } else {
+        print!("{}", cfg);
+    }
+}
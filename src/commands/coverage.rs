@@ -0,0 +1,48 @@
+use std::str;
+
+use arch::analysis::Image;
+use arch::container;
+use arch::coverage::Coverage;
+
+use commands::{fail, read_file, write_file};
+
+const USAGE: &str = "usage: chip8 coverage <rom> <coverage>... [--png <heatmap.png>]";
+
+/// Read a coverage file written by a run with --coverage
+pub fn load(path: &str) -> Coverage {
+    let data = read_file(path);
+    let text = str::from_utf8(&data).unwrap_or_else(|_| fail(&format!("{} is not a coverage file", path)));
+    Coverage::parse(text).unwrap_or_else(|e| fail(&format!("Unable to parse {}: {}", path, e)))
+}
+
+/// chip8 coverage <rom> <coverage>... [--png <heatmap.png>]
+///
+/// Merges the coverage of several runs and prints the ROM disassembled with what each line was used for,
+/// optionally writing a heatmap of all of memory
+pub fn run(args: &[String]) {
+    let (path, rest) = match args.split_first() {
+        Some((path, rest)) if !rest.is_empty() => (path, rest),
+        _ => fail(USAGE),
+    };
+
+    let (coverage_paths, heatmap_path) = match rest {
+        [paths @ .., flag, heatmap] if flag == "--png" => (paths, Some(heatmap)),
+        paths => (paths, None),
+    };
+    if coverage_paths.is_empty() {
+        fail(USAGE);
+    }
+
+    let rom = container::decode(&read_file(path))
+        .unwrap_or_else(|e| fail(&format!("Unable to decode {}: {}", path, e)));
+
+    let mut coverage = Coverage::new();
+    for coverage_path in coverage_paths {
+        coverage.merge(&load(coverage_path));
+    }
+
+    print!("{}", coverage.annotated_disassembly(&Image::new(&rom.data)));
+    if let Some(heatmap_path) = heatmap_path {
+        write_file(heatmap_path, &coverage.heatmap());
+    }
+}
@@ -0,0 +1,24 @@
+use arch::analysis::Image;
+use arch::analysis::cfg::Cfg;
+use arch::analysis::decompile;
+use arch::container;
+
+use commands::{fail, read_file};
+
+const USAGE: &str = "usage: chip8 decompile <rom>";
+
+/// chip8 decompile <rom>
+///
+/// Prints every function of a ROM as structured pseudo-code
+pub fn run(args: &[String]) {
+    let path = match args {
+        [path] => path,
+        _ => fail(USAGE),
+    };
+
+    let rom = container::decode(&read_file(path))
+        .unwrap_or_else(|e| fail(&format!("Unable to decode {}: {}", path, e)));
+
+    let cfg = Cfg::build(&Image::new(&rom.data));
+    print!("{}", decompile::decompile(&cfg));
+}
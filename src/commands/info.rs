@@ -0,0 +1,26 @@
+use arch::analysis::detect;
+use arch::analysis::stats::RomStats;
+use arch::container;
+
+use commands::{fail, read_file};
+
+const USAGE: &str = "usage: chip8 info <rom>";
+
+/// chip8 info <rom>
+///
+/// Prints what can be told about a ROM without running it
+pub fn run(args: &[String]) {
+    let path = match args {
+        [path] => path,
+        _ => fail(USAGE),
+    };
+
+    let file = read_file(path);
+    let rom = container::decode(&file)
+        .unwrap_or_else(|e| fail(&format!("Unable to decode {}: {}", path, e)));
+
+    println!("file:     {} ({} bytes, {:?})", path, file.len(), rom.format);
+    print!("{}", RomStats::of(&rom.data));
+    println!();
+    print!("{}", detect::detect(&rom.data));
+}
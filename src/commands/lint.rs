@@ -0,0 +1,32 @@
+use arch::analysis::Image;
+use arch::analysis::cfg::Cfg;
+use arch::analysis::lint;
+use arch::container;
+
+use commands::{fail, read_file};
+
+const USAGE: &str = "usage: chip8 lint <rom>";
+
+/// chip8 lint <rom>
+///
+/// Prints what looks wrong in the reachable code of a ROM, and fails if any of it is a warning
+pub fn run(args: &[String]) {
+    let path = match args {
+        [path] => path,
+        _ => fail(USAGE),
+    };
+
+    let rom = container::decode(&read_file(path))
+        .unwrap_or_else(|e| fail(&format!("Unable to decode {}: {}", path, e)));
+
+    let image = Image::new(&rom.data);
+    let findings = lint::lint(&image, &Cfg::build(&image));
+    for finding in findings.iter() {
+        println!("{}", finding);
+    }
+
+    let warnings = findings.iter().filter(|finding| finding.check.is_warning()).count();
+    if warnings > 0 {
+        fail(&format!("{} warning{}", warnings, if warnings == 1 { "" } else { "s" }));
+    }
+}
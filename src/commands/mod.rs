@@ -0,0 +1,131 @@
+use std::env;
+use std::fs::File;
+use std::io::{Read, Write};
+use std::process;
+
+use arch::analysis::detect::{self, Detection};
+use arch::config::{Config, Platform};
+use arch::container::RomOptions;
+use arch::database::{Database, RomInfo};
+use arch::hashes;
+
+pub mod patch;
+pub mod info;
+pub mod cfg;
+pub mod decompile;
+pub mod lint;
+pub mod coverage;
+pub mod recompile;
+pub mod vip;
+
+/// Report a failed command and exit
+pub fn fail(message: &str) -> ! {
+    eprintln!("{}", message);
+    process::exit(1);
+}
+
+pub fn read_file(path: &str) -> Vec<u8> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .unwrap_or_else(|e| fail(&format!("Unable to read {}: {}", path, e)));
+    data
+}
+
+pub fn write_file(path: &str, data: &[u8]) {
+    File::create(path)
+        .and_then(|mut file| file.write_all(data))
+        .unwrap_or_else(|e| fail(&format!("Unable to write {}: {}", path, e)));
+}
+
+/// Users can add and override ROM database entries in ~/.chip8/database.txt
+fn local_database_path() -> Option<String> {
+    env::var("HOME").or_else(|_| env::var("USERPROFILE"))
+        .ok()
+        .map(|home| format!("{}/.chip8/database.txt", home))
+}
+
+/// The embedded database, with the local one merged over it
+pub fn load_database() -> Database {
+    let mut database = Database::embedded();
+
+    let path = match local_database_path() {
+        Some(path) => path,
+        None => return database,
+    };
+    if let Ok(mut file) = File::open(&path) {
+        let mut text = String::new();
+        file.read_to_string(&mut text)
+            .unwrap_or_else(|e| fail(&format!("Unable to read {}: {}", path, e)));
+        let local = Database::parse(&text)
+            .unwrap_or_else(|e| fail(&format!("{}: {}", path, e)));
+        database.extend(local);
+    }
+
+    database
+}
+
+/// The config a program runs with, and what it is based on
+pub struct ProgramConfig {
+    pub config: Config,
+
+    /// the database entry of the program
+    pub info: Option<RomInfo>,
+
+    /// the guess for a program nothing is known about, only applied if confident
+    pub detection: Option<Detection>,
+}
+
+impl ProgramConfig {
+    /// Settle on the config of a program, each of these winning over the ones before:
+    /// the database, a confident detection if nothing else knows the platform or quirks,
+    /// the options stored along with the program and the platform given.
+    pub fn resolve(program_data: &[u8], rom_options: &RomOptions, platform: Option<Platform>) -> ProgramConfig {
+        let mut config = Config::default();
+        let mut is_known = rom_options.platform.is_some() || !rom_options.quirks.is_empty();
+
+        let info = load_database().lookup(&hashes::sha1(program_data)).cloned();
+        if let Some(ref info) = info {
+            info.apply(&mut config);
+            is_known |= info.platform.is_some() || !info.quirks.is_empty();
+        }
+
+        // a guess would silently change how the program runs, so only a confident one is taken
+        let detection = if platform.is_none() && !is_known {
+            let detection = detect::detect(program_data);
+            if detection.is_confident() {
+                detection.apply(&mut config);
+            }
+            Some(detection)
+        } else {
+            None
+        };
+
+        // Options stored along with the program know better than the database
+        rom_options.apply(&mut config);
+        if let Some(platform) = platform {
+            config.set_platform(platform);
+        }
+
+        ProgramConfig {
+            config,
+            info,
+            detection,
+        }
+    }
+
+    /// What was made of the detection, if there was one
+    pub fn detection_note(&self) -> Option<String> {
+        self.detection.as_ref().map(|detection| if detection.is_confident() {
+            format!(
+                "Detected a {} program ({:.0}% confident), use --platform to pick another one.",
+                detection.platform, detection.confidence * 100.0
+            )
+        } else {
+            format!(
+                "Unsure which platform this program is for, it might be {} ({:.0}% confident). Running with the default quirks, use --platform to pick one.",
+                detection.platform, detection.confidence * 100.0
+            )
+        })
+    }
+}
@@ -0,0 +1,39 @@
+use arch::container;
+use arch::patch::{self, PatchFormat};
+
+use commands::{fail, read_file, write_file};
+
+const USAGE: &str = "usage: chip8 patch create <original> <modified> <patch.ips|patch.bps>";
+
+/// chip8 patch create <original> <modified> <patch>
+///
+/// Diffs two ROMs into a patch, the format is chosen by the patch's extension
+pub fn run(args: &[String]) {
+    match args {
+        [command, original, modified, output] if command == "create" => {
+            create(original, modified, output)
+        },
+        _ => fail(USAGE),
+    }
+}
+
+fn create(original_path: &str, modified_path: &str, patch_path: &str) {
+    let format = if patch_path.to_lowercase().ends_with(".bps") {
+        PatchFormat::Bps
+    } else if patch_path.to_lowercase().ends_with(".ips") {
+        PatchFormat::Ips
+    } else {
+        fail(USAGE)
+    };
+
+    let original = decode(original_path);
+    let modified = decode(modified_path);
+
+    write_file(patch_path, &patch::create(format, &original, &modified));
+}
+
+fn decode(path: &str) -> Vec<u8> {
+    container::decode(&read_file(path))
+        .unwrap_or_else(|e| fail(&format!("Unable to decode {}: {}", path, e)))
+        .data
+}
@@ -0,0 +1,33 @@
+use arch::analysis::Image;
+use arch::analysis::cfg::Cfg;
+use arch::analysis::recompile;
+use arch::container;
+
+use commands::{fail, read_file, ProgramConfig};
+
+const USAGE: &str = "usage: chip8 recompile <rom> [--platform <chip8|hires|schip|xochip>]";
+
+/// chip8 recompile <rom> [--platform <chip8|hires|schip|xochip>]
+///
+/// Prints a Rust module running the ROM natively on top of arch, for the platform given,
+/// or else the one the ROM runs with
+pub fn run(args: &[String]) {
+    let (path, platform) = match args {
+        [path] => (path, None),
+        [path, flag, name] if flag == "--platform" => (path, Some(name.parse().unwrap_or_else(|e: String| fail(&e)))),
+        _ => fail(USAGE),
+    };
+
+    let rom = container::decode(&read_file(path))
+        .unwrap_or_else(|e| fail(&format!("Unable to decode {}: {}", path, e)));
+
+    // the module goes to stdout
+    let resolved = ProgramConfig::resolve(&rom.data, &rom.options, platform);
+    if let Some(note) = resolved.detection_note() {
+        eprintln!("{}", note);
+    }
+
+    let image = Image::new(&rom.data);
+    let cfg = Cfg::build(&image);
+    print!("{}", recompile::recompile(&image, &cfg, &resolved.config));
+}
@@ -0,0 +1,58 @@
+use arch::config::{Config, Timing};
+use arch::cpu::Cpu;
+use arch::display::Display;
+use arch::vip::Vip;
+
+use commands::{fail, read_file};
+
+const USAGE: &str = "usage: chip8 vip <monitor> <interpreter> <rom> [--frames <n>]";
+
+/// chip8 vip <monitor> <interpreter> <rom> [--frames <n>]
+///
+/// Runs the ROM on an emulated COSMAC VIP, with the monitor ROM and CHIP-8 interpreter given,
+/// next to the interpreter here with VIP timing, and reports the first frame their displays differ
+pub fn run(args: &[String]) {
+    let (monitor, interpreter, path, frames) = match args {
+        [monitor, interpreter, path] => (monitor, interpreter, path, 60),
+        [monitor, interpreter, path, flag, frames] if flag == "--frames" =>
+            (monitor, interpreter, path, frames.parse().unwrap_or_else(|_| fail(USAGE))),
+        _ => fail(USAGE),
+    };
+
+    let program = read_file(path);
+    let mut vip = Vip::new(&read_file(monitor), &read_file(interpreter), &program)
+        .unwrap_or_else(|e| fail(&e.to_string()));
+
+    let config = Config { timing: Timing::Vip, ..Config::default() };
+    let mut cpu = Cpu::with_config(&program, config)
+        .unwrap_or_else(|e| fail(&format!("Unable to load {}: {}", path, e)));
+
+    for frame in 0..frames {
+        vip.run_frame();
+        if let Err(fault) = cpu.run_frame() {
+            fail(&format!("Frame {}: {}", frame, fault));
+        }
+        if !same_pixels(vip.get_display(), cpu.get_display()) {
+            println!("Displays differ at frame {}\n\nVIP:\n{}\nCpu:\n{}",
+                     frame, ascii(vip.get_display()), ascii(cpu.get_display()));
+            return;
+        }
+    }
+    println!("Displays match for {} frames\n\n{}", frames, ascii(vip.get_display()));
+}
+
+fn same_pixels(a: &Display, b: &Display) -> bool {
+    a.width() == b.width() && a.height() == b.height() && a.pixels().eq(b.pixels())
+}
+
+/// The display as text, '#' for set pixels
+fn ascii(display: &Display) -> String {
+    let mut ascii = String::with_capacity((display.width() + 1) * display.height());
+    for (x, _, is_set) in display.pixels() {
+        ascii.push(if is_set { '#' } else { '.' });
+        if x + 1 == display.width() {
+            ascii.push('\n');
+        }
+    }
+    ascii
+}
@@ -3,28 +3,38 @@ use arch::display::{RESOLUTION_HEIGHT, RESOLUTION_WIDTH};
 use std::string::String;
 
 pub struct ConsoleRenderer {
-    pixels: [ bool; RESOLUTION_HEIGHT * RESOLUTION_WIDTH ]
+    width: usize,
+    height: usize,
+    pixels: Vec<bool>
 }
 
 impl ConsoleRenderer {
     pub fn new() -> ConsoleRenderer {
         ConsoleRenderer {
-            pixels: [ false; RESOLUTION_HEIGHT * RESOLUTION_WIDTH ]
+            width: RESOLUTION_WIDTH,
+            height: RESOLUTION_HEIGHT,
+            pixels: vec![ false; RESOLUTION_HEIGHT * RESOLUTION_WIDTH ]
         }
     }
 }
 
 impl Renderer for ConsoleRenderer {
+    fn resize(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        self.pixels = vec![ false; width * height ];
+    }
+
     fn pixel(&mut self, x: usize, y: usize, is_set: bool) {
-        self.pixels[x + (y * RESOLUTION_WIDTH)] = is_set;
+        self.pixels[x + (y * self.width)] = is_set;
     }
 
     fn flush(&mut self) {
-        let mut string = String::with_capacity(RESOLUTION_HEIGHT * RESOLUTION_WIDTH + RESOLUTION_HEIGHT);
+        let mut string = String::with_capacity(self.height * self.width + self.height);
 
-        for rowdex in 0..RESOLUTION_HEIGHT {
-            let offset = rowdex * RESOLUTION_WIDTH;
-            for coldex in 0..RESOLUTION_WIDTH {
+        for rowdex in 0..self.height {
+            let offset = rowdex * self.width;
+            for coldex in 0..self.width {
                 if self.pixels[offset + coldex] {
                     string.push_str("#");
                 } else {
@@ -37,4 +47,4 @@ impl Renderer for ConsoleRenderer {
 
         println!("{}", string);
     }
-}
\ No newline at end of file
+}
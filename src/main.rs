@@ -6,9 +6,98 @@ extern crate opengl_graphics;
 extern crate arch;
 
 mod program;
+mod commands;
 
-use program::Program;
+use std::env;
+
+use arch::sanitizer::SanitizerOptions;
+
+use program::{Options, Program};
+
+const USAGE: &str = "usage:
+    chip8 <rom> [--platform <chip8|hires|schip|xochip>] [--timing <fixed|vip>] [--strict] [--randomize] [--profile <prefix>] [--coverage <coverage>] [--state <state>] [--patch <patch>]...
+    chip8 info <rom>
+    chip8 cfg <rom> [--dot]
+    chip8 decompile <rom>
+    chip8 lint <rom>
+    chip8 coverage <rom> <coverage>... [--png <heatmap.png>]
+    chip8 recompile <rom> [--platform <chip8|hires|schip|xochip>]
+    chip8 vip <monitor> <interpreter> <rom> [--frames <n>]
+    chip8 patch create <original> <modified> <patch.ips|patch.bps>";
 
 fn main() {
-    Program::new("c:/tmp/prog.chp8").run();
-}
\ No newline at end of file
+    let args: Vec<String> = env::args().skip(1).collect();
+
+    match args.first().map(String::as_str) {
+        Some("info") => commands::info::run(&args[1..]),
+        Some("cfg") => commands::cfg::run(&args[1..]),
+        Some("decompile") => commands::decompile::run(&args[1..]),
+        Some("lint") => commands::lint::run(&args[1..]),
+        Some("coverage") => commands::coverage::run(&args[1..]),
+        Some("recompile") => commands::recompile::run(&args[1..]),
+        Some("vip") => commands::vip::run(&args[1..]),
+        Some("patch") => commands::patch::run(&args[1..]),
+        Some(_) => run(&args),
+        None => commands::fail(USAGE),
+    }
+}
+
+/// chip8 <rom> [--platform <chip8|hires|schip|xochip>] [--timing <fixed|vip>] [--strict] [--randomize] [--profile <prefix>] [--coverage <coverage>] [--state <state>] [--patch <patch>]...
+///
+/// Patches are applied to the ROM in the order given.
+/// Without a platform, it is looked up or detected.
+/// --timing vip runs as many instructions per frame as fit in the VIP's machine cycles, instead of a fixed rate.
+/// --strict reports uninitialized reads and stops on undefined behavior,
+/// --randomize starts with random memory and registers, and reports issues, only stopping on them with --strict.
+/// --profile writes profiler reports to <prefix>.txt, <prefix>.folded and <prefix>.trace.json on exit.
+/// --coverage adds what memory was used for to the coverage file on exit.
+/// --state resumes from a save state, such as the one in a crash report, it can't be randomized.
+fn run(args: &[String]) {
+    let program_path = &args[0];
+
+    let mut options = Options::default();
+    let mut rest = args[1..].iter();
+    while let Some(arg) = rest.next() {
+        match (arg.as_str(), rest.as_slice().first()) {
+            ("--patch", Some(path)) => {
+                options.patch_paths.push(path.clone());
+                rest.next();
+            },
+            ("--platform", Some(name)) => {
+                options.platform = Some(name.parse().unwrap_or_else(|e: String| commands::fail(&e)));
+                rest.next();
+            },
+            ("--timing", Some(name)) => {
+                options.timing = Some(name.parse().unwrap_or_else(|e: String| commands::fail(&e)));
+                rest.next();
+            },
+            ("--profile", Some(prefix)) => {
+                options.profile_prefix = Some(prefix.clone());
+                rest.next();
+            },
+            ("--coverage", Some(path)) => {
+                options.coverage_path = Some(path.clone());
+                rest.next();
+            },
+            ("--state", Some(path)) => {
+                options.state_path = Some(path.clone());
+                rest.next();
+            },
+            ("--strict", _) => options.sanitizer = Some(SanitizerOptions {
+                trap: true,
+                ..options.sanitizer.unwrap_or_default()
+            }),
+            ("--randomize", _) => options.sanitizer = Some(SanitizerOptions {
+                randomize: true,
+                ..options.sanitizer.unwrap_or_default()
+            }),
+            _ => commands::fail(USAGE),
+        }
+    }
+
+    if options.state_path.is_some() && options.sanitizer.map_or(false, |sanitizer| sanitizer.randomize) {
+        commands::fail("--randomize can't be used with --state, which sets all of memory and the registers");
+    }
+
+    Program::new(program_path, &options).run();
+}
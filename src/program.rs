@@ -1,16 +1,31 @@
-use std::fs::File;
+use std::fs::{self, File};
+use std::path::Path;
 use std::io::Read;
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
 
 use opengl_graphics::{ OpenGL, GlGraphics };
 use glutin_window::GlutinWindow;
 
-use piston::window::WindowSettings;
+use piston::window::{Window, WindowSettings};
 use piston::input::{RenderEvent, RenderArgs, UpdateEvent, IdleEvent, PressEvent, ReleaseEvent, Button, Key};
 use piston::event_loop::*;
 
 use graphics::*;
 
-use arch::cpu::Cpu;
+use arch::Renderer;
+use arch::cpu::{Cpu, Fault};
+use arch::crash::CrashReport;
+use arch::cheats::{CheatList, RamSearch, SearchFilter};
+use arch::coverage::Coverage;
+use arch::config::{Platform, Timing};
+use arch::container;
+use arch::patch;
+use arch::database::RomInfo;
+use arch::hashes;
+use arch::sanitizer::SanitizerOptions;
+use arch::state::SaveState;
+
+use commands::{coverage, fail, read_file, write_file, ProgramConfig};
 
 struct Pixel {
     x: usize,
@@ -40,22 +55,213 @@ fn match_key_to_cpu(key: Key) -> Option<u8> {
     }
 }
 
+fn match_key_to_cheat(key: Key) -> Option<usize> {
+    match key {
+        Key::F1 => Some(0),
+        Key::F2 => Some(1),
+        Key::F3 => Some(2),
+        Key::F4 => Some(3),
+        Key::F5 => Some(4),
+        Key::F6 => Some(5),
+        Key::F7 => Some(6),
+        Key::F8 => Some(7),
+        Key::F9 => Some(8),
+        _ => None,
+    }
+}
+
+/// F10 starts a RAM search over, these narrow it down, backspace to the value of the hex key held
+fn match_key_to_search_filter(key: Key, held_key: Option<u8>) -> Option<SearchFilter> {
+    match key {
+        Key::Backspace => held_key.map(SearchFilter::Equal),
+        Key::F11 => Some(SearchFilter::Changed),
+        Key::Minus => Some(SearchFilter::Decreased),
+        Key::Equals => Some(SearchFilter::Increased),
+        _ => None,
+    }
+}
+
+/// How many RAM search candidates are printed after each filter
+const SHOWN_CANDIDATES: usize = 16;
+
+/// Cheats of a ROM are stored next to it, e.g prog.ch8 -> prog.ch8.cht
+fn cheats_path_of(program_path: &str) -> String {
+    format!("{}.cht", program_path)
+}
+
+fn load_cheats(cheats_path: &str) -> CheatList {
+    let mut text = String::new();
+    match File::open(cheats_path) {
+        Ok(mut file) => {
+            file.read_to_string(&mut text)
+                .unwrap_or_else(|e| fail(&format!("Unable to read {}: {}", cheats_path, e)));
+            CheatList::parse(&text)
+                .unwrap_or_else(|e| fail(&format!("{}: {}", cheats_path, e)))
+        },
+        Err(_) => CheatList::new(),
+    }
+}
+
+fn print_rom_info(info: &RomInfo) {
+    if let Some(ref title) = info.title {
+        match info.author {
+            Some(ref author) => println!("{} by {}", title, author),
+            None => println!("{}", title),
+        }
+    }
+    for &(key, ref description) in info.keys.iter() {
+        println!("  {:X}: {}", key, description);
+    }
+}
+
+/// Screen
+///
+/// The display as the window shows it, the cpu only sends the pixels that changed
+struct Screen {
+    width: usize,
+    height: usize,
+    pixels: Vec<bool>,
+
+    /// pixels changed since the window was last drawn
+    changed: bool,
+}
+
+impl Screen {
+    fn new() -> Screen {
+        Screen {
+            width: 0,
+            height: 0,
+            pixels: Vec::new(),
+            changed: true,
+        }
+    }
+}
+
+impl Renderer for Screen {
+    fn resize(&mut self, width: usize, height: usize) {
+        self.width = width;
+        self.height = height;
+        self.pixels = vec![false; width * height];
+    }
+
+    fn pixel(&mut self, x: usize, y: usize, is_set: bool) {
+        self.pixels[x + y * self.width] = is_set;
+    }
+
+    fn flush(&mut self) {
+        self.changed = true;
+    }
+}
+
+fn color_of(rgb: [u8; 3]) -> [f32; 4] {
+    [rgb[0] as f32 / 255.0, rgb[1] as f32 / 255.0, rgb[2] as f32 / 255.0, 1.0]
+}
+
+/// How to run a program, as given on the command line
+#[derive(Default)]
+pub struct Options {
+    /// applied to the ROM in order
+    pub patch_paths: Vec<String>,
+
+    /// wins over anything known or detected about the ROM
+    pub platform: Option<Platform>,
+
+    /// strict mode
+    pub sanitizer: Option<SanitizerOptions>,
+
+    /// where the profiler reports go on exit, as <prefix>.txt, <prefix>.folded and <prefix>.trace.json
+    pub profile_prefix: Option<String>,
+
+    /// coverage file the run is merged into on exit
+    pub coverage_path: Option<String>,
+
+    /// save state to resume from, e.g the one in a crash report
+    pub state_path: Option<String>,
+
+    pub timing: Option<Timing>,
+}
+
 pub struct Program {
     cpu: Cpu,
+
+    /// where crash reports go, next to the ROM
+    program_path: String,
+    rom_sha1: String,
+
+    /// sanitizer reports already printed
+    reported: usize,
+
+    /// instructions owed to the cpu according to its clock speed
+    pending_cycles: f64,
+
+    /// 60 Hz frames owed to the cpu, run with VIP timing and only an interrupt with a fixed clock
+    pending_frames: f64,
+    profile_prefix: Option<String>,
+    coverage_path: Option<String>,
+    cheats: CheatList,
+    cheats_path: String,
+    ram_search: Option<RamSearch>,
+
+    /// the hex key last pressed and still held, the value backspace searches for
+    held_key: Option<u8>,
     window: GlutinWindow,
     opengl: GlGraphics,
+    screen: Screen,
+
+    /// the size the window was last drawn at, it is drawn again when resized
+    drawn_size: Option<[u32; 2]>,
 }
 
 impl Program {
-    pub fn new(program_path: &str) -> Program {
-        let mut program_data = Vec::new();
-        let mut file = File::open(program_path).expect("Unable to find program file");
-        file.read_to_end(&mut program_data).expect("Failed to read program");
+    pub fn new(program_path: &str, options: &Options) -> Program {
+        let rom = container::decode(&read_file(program_path))
+            .unwrap_or_else(|e| fail(&format!("Unable to decode {}: {}", program_path, e)));
+
+        let patches: Vec<Vec<u8>> = options.patch_paths.iter().map(|path| read_file(path)).collect();
+        let program_data = patch::apply_all(&rom.data, patches.iter().map(Vec::as_slice))
+            .unwrap_or_else(|e| fail(&format!("Unable to patch {}: {}", program_path, e)));
+
+        let resolved = ProgramConfig::resolve(&program_data, &rom.options, options.platform);
+        let mut title = String::from("chip8");
+        if let Some(ref info) = resolved.info {
+            print_rom_info(info);
+            if let Some(ref rom_title) = info.title {
+                title = format!("chip8 - {}", rom_title);
+            }
+        }
+        if let Some(note) = resolved.detection_note() {
+            println!("{}", note);
+        }
+
+        let mut config = resolved.config;
+        if let Some(timing) = options.timing {
+            config.timing = timing;
+        }
+
+        let mut cpu = Cpu::with_config(&program_data, config)
+            .unwrap_or_else(|e| fail(&format!("Unable to load {}: {}", program_path, e)));
+        if let Some(sanitizer_options) = options.sanitizer {
+            cpu.enable_sanitizer(sanitizer_options);
+        }
+        // after the sanitizer, which then takes all of the state as initialized
+        if let Some(ref state_path) = options.state_path {
+            let state = SaveState::from_bytes(&read_file(state_path))
+                .unwrap_or_else(|e| fail(&format!("Unable to load {}: {}", state_path, e)));
+            cpu.load_state(&state);
+        }
+        if options.profile_prefix.is_some() {
+            cpu.enable_profiler();
+        }
+        if options.coverage_path.is_some() {
+            cpu.enable_coverage();
+        }
+        #[cfg(feature = "jit")]
+        cpu.enable_jit();
         
         let opengl_spec = OpenGL::V3_2;
         
         let window = WindowSettings::new(
-                    "chip8",
+                    title,
                     [800, 600]
                 )
                 .opengl(opengl_spec)
@@ -65,16 +271,33 @@ impl Program {
 
         let opengl = GlGraphics::new(opengl_spec);
 
+        let cheats_path = cheats_path_of(program_path);
+        let cheats = load_cheats(&cheats_path);
+
         Program {
-            cpu: Cpu::new(&program_data),
+            cpu,
+            program_path: program_path.to_string(),
+            rom_sha1: hashes::sha1(&program_data),
+            reported: 0,
+            pending_cycles: 0.0,
+            pending_frames: 0.0,
+            profile_prefix: options.profile_prefix.clone(),
+            coverage_path: options.coverage_path.clone(),
+            cheats,
+            cheats_path,
+            ram_search: None,
+            held_key: None,
             window,
             opengl,
+            screen: Screen::new(),
+            drawn_size: None,
 
         }
     }
 
     pub fn run(&mut self) {
-        let mut events = Events::new(EventSettings::new());
+        // buffers are swapped only when something was drawn
+        let mut events = Events::new(EventSettings::new().swap_buffers(false));
 
         while let Some(e) = events.next(&mut self.window) {
 
@@ -92,43 +315,173 @@ impl Program {
             if let Some(Button::Keyboard(key)) = e.release_args() {
                 if let Some(cpu_key) = match_key_to_cpu(key) {
                     self.cpu.released_key(cpu_key);
+                    if self.held_key == Some(cpu_key) {
+                        self.held_key = None;
+                    }
                 }
             }
 
             if let Some(Button::Keyboard(key)) = e.press_args() {
                 if let Some(cpu_key) = match_key_to_cpu(key) {
                     self.cpu.pressed_key(cpu_key);
+                    self.held_key = Some(cpu_key);
+                }
+
+                if let Some(cheat_index) = match_key_to_cheat(key) {
+                    self.toggle_cheat(cheat_index);
+                }
+
+                if key == Key::F10 {
+                    self.start_ram_search();
+                }
+                if let Some(filter) = match_key_to_search_filter(key, self.held_key) {
+                    self.filter_ram_search(filter);
                 }
             }
         }
+
+        self.write_profile();
+        self.write_coverage();
+    }
+
+    /// Write the profiler reports, if profiling
+    fn write_profile(&self) {
+        if let (Some(prefix), Some(profiler)) = (self.profile_prefix.as_ref(), self.cpu.get_profiler()) {
+            write_file(&format!("{}.txt", prefix), profiler.to_string().as_bytes());
+            write_file(&format!("{}.folded", prefix), profiler.folded_stacks().as_bytes());
+            write_file(&format!("{}.trace.json", prefix), profiler.chrome_trace().as_bytes());
+        }
     }
 
     pub fn update(&mut self, dt: f64) {
-        self.cpu.tick();
+        let started = Instant::now();
+        match self.cpu.get_config().timing {
+            Timing::Fixed => {
+                self.pending_cycles += dt * self.cpu.get_config().clock_speed as f64;
+                let cycles = self.pending_cycles as usize;
+                self.pending_cycles -= cycles as f64;
+                if let Err(fault) = self.cpu.run(cycles) {
+                    self.crash(fault);
+                }
+                self.pending_frames += dt * 60.0;
+                while self.pending_frames >= 1.0 {
+                    self.cpu.interrupt();
+                    self.pending_frames -= 1.0;
+                }
+            },
+            Timing::Vip => {
+                self.pending_frames += dt * 60.0;
+                while self.pending_frames >= 1.0 {
+                    if let Err(fault) = self.cpu.run_frame() {
+                        self.crash(fault);
+                    }
+                    self.pending_frames -= 1.0;
+                }
+            },
+        }
+        self.cpu.apply_cheats(&self.cheats);
+
+        if let Some(profiler) = self.cpu.get_profiler_mut() {
+            let elapsed = started.elapsed();
+            profiler.frame(elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1e9);
+        }
+
+        if let Some(sanitizer) = self.cpu.get_sanitizer() {
+            for report in sanitizer.reports()[self.reported..].iter() {
+                eprintln!("{}", report);
+            }
+            self.reported = sanitizer.reports().len();
+        }
+    }
+
+    /// Merge the coverage of this run into the coverage file, if recording it
+    fn write_coverage(&self) {
+        if let (Some(path), Some(run_coverage)) = (self.coverage_path.as_ref(), self.cpu.get_coverage()) {
+            let mut merged = if Path::new(path).exists() { coverage::load(path) } else { Coverage::new() };
+            merged.merge(run_coverage);
+            write_file(path, merged.to_string().as_bytes());
+        }
+    }
+
+    /// Write a crash report bundle next to the ROM, along with whatever else was being recorded, and exit
+    fn crash(&self, fault: Fault) -> ! {
+        let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|elapsed| elapsed.as_secs()).unwrap_or(0);
+        let directory = format!("{}.crash-{}", self.program_path, seconds);
+        fs::create_dir_all(&directory)
+            .unwrap_or_else(|e| fail(&format!("{}, unable to create crash report {}: {}", fault, directory, e)));
+
+        let report = CrashReport::new(&self.cpu, fault, &self.rom_sha1);
+        for (name, data) in report.files() {
+            write_file(&format!("{}/{}", directory, name), &data);
+        }
+
+        self.write_profile();
+        self.write_coverage();
+        fail(&format!("{}, crash report written to {}", fault, directory));
+    }
+
+    /// Toggle a cheat and persist the new state to the cheats file
+    pub fn toggle_cheat(&mut self, index: usize) {
+        if self.cheats.toggle(index).is_some() {
+            write_file(&self.cheats_path, self.cheats.to_string().as_bytes());
+        }
+    }
+
+    /// Start a RAM search over, every address being a candidate
+    pub fn start_ram_search(&mut self) {
+        let search = RamSearch::new(self.cpu.get_memory());
+        self.print_ram_search(&search);
+        self.ram_search = Some(search);
     }
 
+    /// Narrow the RAM search down, starting one if there is none
+    pub fn filter_ram_search(&mut self, filter: SearchFilter) {
+        let memory = self.cpu.get_memory();
+        let mut search = self.ram_search.take().unwrap_or_else(|| RamSearch::new(memory));
+        search.filter(memory, filter);
+        self.print_ram_search(&search);
+        self.ram_search = Some(search);
+    }
+
+    fn print_ram_search(&self, search: &RamSearch) {
+        let memory = self.cpu.get_memory();
+        println!("RAM search: {} candidates", search.candidates().len());
+        for &address in search.candidates().iter().take(SHOWN_CANDIDATES) {
+            println!("  {:03X} = {:02X}", address, memory.read(address as usize));
+        }
+    }
+
+    /// Draw the display, unless neither it nor the window changed since it was last drawn
     pub fn render(&mut self, args: &RenderArgs) {
         use graphics::*;
 
-        let pixels = self.cpu.get_display().temp();
+        self.cpu.render(&mut self.screen);
+        let size = args.viewport().draw_size;
+        if !self.screen.changed && self.drawn_size == Some(size) {
+            return;
+        }
+
+        let screen = &self.screen;
 
-        const GREEN: [f32; 4] = [0.0, 1.0, 0.0, 1.0];
-        const RED:   [f32; 4] = [1.0, 0.0, 0.0, 1.0];
+        let palette = self.cpu.get_config().palette;
+        let background = color_of(palette.background);
+        let foreground = color_of(palette.foreground);
         let square = rectangle::square(0.0, 0.0, 1.0);
 
         self.opengl.draw(args.viewport(), |c, gl| {
-            clear(GREEN, gl);
-            for pixel in pixels {
-                if pixel.2 {
-                    let x = (pixel.0 as f64);
-                    let y = (pixel.1 as f64);
-                    let transform = c.transform.trans(x, y);
-                    // Draw a box rotating around the middle of the screen.
-                    rectangle(RED, square, transform, gl);
+            clear(background, gl);
+            for y in 0..screen.height {
+                for x in 0..screen.width {
+                    if screen.pixels[x + y * screen.width] {
+                        let transform = c.transform.trans(x as f64, y as f64);
+                        rectangle(foreground, square, transform, gl);
+                    }
                 }
-                
             }
         });
-        
+
+        self.screen.changed = false;
+        self.drawn_size = Some(size);
+        self.window.swap_buffers();
     }
 }
\ No newline at end of file